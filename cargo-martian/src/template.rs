@@ -195,71 +195,40 @@ pub fn new_stage(
 const ADAPTER_MAIN_TEMPLATE: &str = r##"
 //! Martian-rust adapter {adapter}
 
-use serde::Deserialize;
 use martian::prelude::*;
-use docopt::Docopt;
-
-const USAGE: &'static str = "
-Martian adapter for {adapter} executable
-
-Usage:
-  {adapter} martian <adapter>...
-  {adapter} mro [--file=<filename>] [--rewrite]
-  {adapter} --help
-
-Options:
-  --help              Show this screen.
-  --file=<filename>   Output filename for the mro.
-  --rewrite           Whether to rewrite the file if it exists.
-";
-
-#[derive(Debug, Deserialize)]
-struct Args {open}
-    // Martian interface
-    cmd_martian: bool,
-    arg_adapter: Vec<String>,
-    // Mro generation
-    cmd_mro: bool,
-    flag_file: Option<String>,
-    flag_rewrite: bool,
-{close}
-
-fn main() -> Result<(), Error> {open}
-
-    let args: Args = Docopt::new(USAGE)
-        .and_then(|d| d.deserialize())
-        .unwrap_or_else(|e| e.exit());
 
+fn main() {open}
     let (stage_registry, mro_registry) = martian_stages![
         // TODO: Add the stage structs here
     ];
 
-    if args.cmd_martian {open}
-        // Setup the martian adapter
-        let runner = MartianAdapter::new(stage_registry);
+    // Handles `{adapter} martian <adapter>...` and
+    // `{adapter} mro [--file=<filename>] [--rewrite]`
+    MartianCli::new(stage_registry, mro_registry)
+        .mro_header("# Header comment")
+        // Record the rustc version, features and git commit captured by
+        // build.rs in the _jobinfo
+        .build_info(martian::build_info!())
         // If you want explicit control over the log level, use:
-        // let runner = runner.log_level();
-        // run the stage
-        let retcode = runner.run(args.arg_adapter);
-        // return from the process
-        std::process::exit(retcode);
-    {close} else if args.cmd_mro {open}
-        // Create the mro for all the stages in this adapter
-        martian_make_mro("# Header comment", args.flag_file, args.flag_rewrite, mro_registry)?;
-    {close} else {open}
-        // If you need custom commands, implement them here
-        unimplemented!()
-    {close}
-    
-    Ok(())
+        // .log_level(LevelFilter::Info)
+        // If you need custom commands, register them with:
+        // .command("name", "description", |args| {open} ... {close})
+        .run()
 {close}
 "##;
 
 const CARGO_TOML_ADDITION: &str = r#"
-docopt = "1.0"
 serde = { version = "1.0", features = ["derive"] }
 martian = {git = "https://github.com/martian-lang/martian-rust.git"}
 martian-derive = {git = "https://github.com/martian-lang/martian-rust.git"}
+
+[build-dependencies]
+martian = {git = "https://github.com/martian-lang/martian-rust.git"}
+"#;
+
+const BUILD_RS_TEMPLATE: &str = r#"fn main() {
+    martian::build::emit_build_info();
+}
 "#;
 
 const DEFAULT_MAIN: &str = r#"fn main() {
@@ -319,5 +288,15 @@ pub fn new_adapter(adapter_name: impl AsRef<str>) {
                 .expect("Couldn't open Cargo.toml for writing");
             write!(f, "{CARGO_TOML_ADDITION}").expect("Failed writing to Cargo.toml file");
         }
+
+        {
+            // Build script
+            let mut path = PathBuf::from(&adapter_name);
+            path.push("build.rs");
+
+            eprintln!("Writing build script to {path:?}");
+            let mut f = File::create(path).expect("Failed to create build.rs");
+            write!(f, "{BUILD_RS_TEMPLATE}").expect("Failed writing to build.rs file");
+        }
     }
 }
@@ -11,9 +11,11 @@ use syn::{
     Data, DeriveInput, Error, Expr, Fields, Ident, ImplItem, ItemImpl, ItemStruct, Lit, Meta, Type,
 };
 
-const ATTR_NOT_ON_TRAIT_IMPL_ERROR: &str = r#"The attribute #[make_mro] should only be applied to `martian::MartianMain` or `martian::MartianStage` trait implementation of a stage struct"#;
+const ATTR_NOT_ON_TRAIT_IMPL_ERROR: &str = r#"The attribute #[make_mro] should only be applied to `martian::MartianMain`, `martian::MartianStage`, `martian::MartianMainAsync` or `martian::MartianStageAsync` trait implementation of a stage struct"#;
 const MARTIAN_MAIN_TRAIT: &str = "MartianMain";
 const MARTIAN_STAGE_TRAIT: &str = "MartianStage";
+const MARTIAN_MAIN_ASYNC_TRAIT: &str = "MartianMainAsync";
+const MARTIAN_STAGE_ASYNC_TRAIT: &str = "MartianStageAsync";
 const STAGE_INPUT_IDENT: &str = "StageInputs";
 const STAGE_OUTPUT_IDENT: &str = "StageOutputs";
 const CHUNK_INPUT_IDENT: &str = "ChunkInputs";
@@ -34,6 +36,10 @@ const INVALID_MRO_TYPE_ERROR: &str = r#""The usage of mro_type should be of form
 /// You can also set the stage name here. By default, the stage name in the mro is the SHOUTY_SNAKE_CASE version
 /// of the stage struct name. You can override that using: `#[make_mro(mem_gb = 2, stage_name = MY_CUSTOM_NAME)]`
 ///
+/// When applied to a `MartianMainAsync` or `MartianStageAsync` trait implementation, it also
+/// implements `MartianMain` or `MartianStage` for the stage struct by blocking on the async
+/// functions using the runtime in `martian::runtime`. This requires the `tokio` feature of `martian`.
+///
 /// For examples on how to use it and customize, take a look at `tests/test_full_mro.rs`
 #[proc_macro_attribute]
 pub fn make_mro(
@@ -136,12 +142,12 @@ pub fn make_mro(
     // STEP 2
     // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
     // Identify whether #[make_mro] was applied to a `MartianMain` or `MartianStage`
-    // If we find that it was applied to a different trait impl, produce a
-    // sensible compile error. This only checks for the trait by name, so it is possible
-    // to trick the compiler to continue, but it should fail later if the trait
-    // signature is different from the one defined in `martian` crate
+    // (or their async versions). If we find that it was applied to a different trait
+    // impl, produce a sensible compile error. This only checks for the trait by name,
+    // so it is possible to trick the compiler to continue, but it should fail later if
+    // the trait signature is different from the one defined in `martian` crate
     let trait_path = item_impl.trait_.unwrap().1;
-    let which_trait = match parse_which_trait(trait_path) {
+    let (which_trait, is_async) = match parse_which_trait(trait_path) {
         Ok(t) => t,
         Err(e) => return e.to_compile_error().into(),
     };
@@ -196,8 +202,29 @@ pub fn make_mro(
     // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
     // STEP 5
     // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
-    // Stitch the quotes together
+    // For the async traits, implement the corresponding synchronous trait
+    // which blocks on the async functions.
     let (impl_generics, _, where_clause) = item_impl.generics.split_for_impl();
+    let sync_impl = if is_async {
+        let sync_fns = sync_stage_fns(which_trait);
+        let sync_trait = match which_trait {
+            StageKind::MainOnly => quote![::martian::MartianMain],
+            StageKind::WithSplit => quote![::martian::MartianStage],
+        };
+        quote![
+            #[automatically_derived]
+            impl #impl_generics #sync_trait for #stage_struct #where_clause {
+                #sync_fns
+            }
+        ]
+    } else {
+        quote![]
+    };
+
+    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
+    // STEP 6
+    // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
+    // Stitch the quotes together
     let item_clone2 = proc_macro2::TokenStream::from(item);
     quote![
         #item_clone2
@@ -207,10 +234,103 @@ pub fn make_mro(
             #stage_name_fn
             #using_attributes_fn
         }
+        #sync_impl
     ]
     .into()
 }
 
+// The body of the synchronous `MartianMain` or `MartianStage` impl for a stage
+// implementing `MartianMainAsync` or `MartianStageAsync`.
+fn sync_stage_fns(which: StageKind) -> proc_macro2::TokenStream {
+    match which {
+        StageKind::MainOnly => quote![
+            type StageInputs = <Self as ::martian::MartianMainAsync>::StageInputs;
+            type StageOutputs = <Self as ::martian::MartianMainAsync>::StageOutputs;
+
+            fn main(
+                &self,
+                args: <Self as ::martian::MartianMainAsync>::StageInputs,
+                rover: ::martian::MartianRover,
+            ) -> ::std::result::Result<
+                <Self as ::martian::MartianMainAsync>::StageOutputs,
+                ::martian::Error,
+            > {
+                ::martian::runtime::block_on(
+                    rover.get_threads(),
+                    <Self as ::martian::MartianMainAsync>::main(self, args, rover),
+                )
+            }
+
+            fn validate(
+                &self,
+                args: &<Self as ::martian::MartianMainAsync>::StageInputs,
+            ) -> ::std::result::Result<(), ::martian::ValidationErrors> {
+                <Self as ::martian::MartianMainAsync>::validate(self, args)
+            }
+        ],
+        StageKind::WithSplit => quote![
+            type StageInputs = <Self as ::martian::MartianStageAsync>::StageInputs;
+            type StageOutputs = <Self as ::martian::MartianStageAsync>::StageOutputs;
+            type ChunkInputs = <Self as ::martian::MartianStageAsync>::ChunkInputs;
+            type ChunkOutputs = <Self as ::martian::MartianStageAsync>::ChunkOutputs;
+
+            fn split(
+                &self,
+                args: <Self as ::martian::MartianStageAsync>::StageInputs,
+                rover: ::martian::MartianRover,
+            ) -> ::std::result::Result<
+                ::martian::StageDef<<Self as ::martian::MartianStageAsync>::ChunkInputs>,
+                ::martian::Error,
+            > {
+                ::martian::runtime::block_on(
+                    rover.get_threads(),
+                    <Self as ::martian::MartianStageAsync>::split(self, args, rover),
+                )
+            }
+
+            fn main(
+                &self,
+                args: <Self as ::martian::MartianStageAsync>::StageInputs,
+                chunk_args: <Self as ::martian::MartianStageAsync>::ChunkInputs,
+                rover: ::martian::MartianRover,
+            ) -> ::std::result::Result<
+                <Self as ::martian::MartianStageAsync>::ChunkOutputs,
+                ::martian::Error,
+            > {
+                ::martian::runtime::block_on(
+                    rover.get_threads(),
+                    <Self as ::martian::MartianStageAsync>::main(self, args, chunk_args, rover),
+                )
+            }
+
+            fn join(
+                &self,
+                args: <Self as ::martian::MartianStageAsync>::StageInputs,
+                chunk_defs: Vec<<Self as ::martian::MartianStageAsync>::ChunkInputs>,
+                chunk_outs: Vec<<Self as ::martian::MartianStageAsync>::ChunkOutputs>,
+                rover: ::martian::MartianRover,
+            ) -> ::std::result::Result<
+                <Self as ::martian::MartianStageAsync>::StageOutputs,
+                ::martian::Error,
+            > {
+                ::martian::runtime::block_on(
+                    rover.get_threads(),
+                    <Self as ::martian::MartianStageAsync>::join(
+                        self, args, chunk_defs, chunk_outs, rover,
+                    ),
+                )
+            }
+
+            fn validate(
+                &self,
+                args: &<Self as ::martian::MartianStageAsync>::StageInputs,
+            ) -> ::std::result::Result<(), ::martian::ValidationErrors> {
+                <Self as ::martian::MartianStageAsync>::validate(self, args)
+            }
+        ],
+    }
+}
+
 #[derive(Default)]
 struct AssociatedTypeBuilder {
     stage_inputs: Option<Type>,
@@ -306,18 +426,24 @@ impl AssociatedTypeBuilder {
     }
 }
 
-// Identify which trait impl the attribute is applied to among `MartianMain`
-// and `MartianStage`. If we find that this is applied to a different trait,
-// return an error.
-fn parse_which_trait(trait_path: syn::Path) -> Result<StageKind, Error> {
+// Identify which trait impl the attribute is applied to among `MartianMain`,
+// `MartianStage` and their async versions, and whether it is async. If we find
+// that this is applied to a different trait, return an error.
+fn parse_which_trait(trait_path: syn::Path) -> Result<(StageKind, bool), Error> {
     let mut last_ident = String::from("");
     let span = trait_path.segments[0].ident.span();
     for segment in trait_path.segments {
         if segment.ident == MARTIAN_MAIN_TRAIT {
-            return Ok(StageKind::MainOnly);
+            return Ok((StageKind::MainOnly, false));
         }
         if segment.ident == MARTIAN_STAGE_TRAIT {
-            return Ok(StageKind::WithSplit);
+            return Ok((StageKind::WithSplit, false));
+        }
+        if segment.ident == MARTIAN_MAIN_ASYNC_TRAIT {
+            return Ok((StageKind::MainOnly, true));
+        }
+        if segment.ident == MARTIAN_STAGE_ASYNC_TRAIT {
+            return Ok((StageKind::WithSplit, true));
         }
         last_ident = segment.ident.to_string();
     }
@@ -407,7 +533,14 @@ attr_parse!(
 ///
 /// You can optionally add a field to the "retain" section of the mro using `#[mro_retain]`.
 ///
-#[proc_macro_derive(MartianStruct, attributes(mro_retain, mro_type, mro_filename))]
+/// You can optionally check the value of a field before the stage runs using
+/// `#[mro_validate(range = 1..=64)]` or `#[mro_validate(exists)]` for paths and filetypes.
+/// `Option` fields are only checked if they are set and each element of a `Vec` is checked.
+///
+#[proc_macro_derive(
+    MartianStruct,
+    attributes(mro_retain, mro_type, mro_filename, mro_validate)
+)]
 pub fn martian_struct(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
     // ::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::::
     // STEP 1
@@ -448,6 +581,7 @@ pub fn martian_struct(item: proc_macro::TokenStream) -> proc_macro::TokenStream
     // Parse the #[mro_retain] attributes attached to the field, and make sure
     // that no serde field attributes are used
     let mut vec_inner = Vec::with_capacity(fields.len());
+    let mut validations = Vec::new();
     let blacklist: HashSet<&str> = MARTIAN_TOKENS.iter().copied().collect();
     for field in fields {
         let name = field.ident.clone().unwrap().to_string();
@@ -539,6 +673,21 @@ pub fn martian_struct(item: proc_macro::TokenStream) -> proc_macro::TokenStream
                         }
                     }
                 }
+            } else if attr.path().is_ident("mro_validate") {
+                match parse_mro_validate(attr) {
+                    Ok(checks) => {
+                        let ident = field.ident.as_ref().unwrap();
+                        for check in checks {
+                            let check = validate_value(&field.ty, &check);
+                            validations.push(quote![
+                                let value = &self.#ident;
+                                let field: &str = #name;
+                                #check
+                            ]);
+                        }
+                    }
+                    Err(e) => return e.to_compile_error().into(),
+                }
             }
         }
         if name.starts_with("__") {
@@ -583,13 +732,19 @@ pub fn martian_struct(item: proc_macro::TokenStream) -> proc_macro::TokenStream
             None => quote![None],
         };
 
+        let optional_code = if generic_argument(&ty, "Option").is_some() {
+            quote![.optional(true)]
+        } else {
+            quote![]
+        };
+
         vec_inner.push(if retain {
             quote![
-                <::martian::MroField>::retained(#name, #actual_type, #doc_comment_code, #mro_filename_code)
+                <::martian::MroField>::retained(#name, #actual_type, #doc_comment_code, #mro_filename_code)#optional_code
             ]
         } else {
             quote![
-                <::martian::MroField>::new(#name, #actual_type, #doc_comment_code, #mro_filename_code)
+                <::martian::MroField>::new(#name, #actual_type, #doc_comment_code, #mro_filename_code)#optional_code
             ]
         });
     }
@@ -602,6 +757,17 @@ pub fn martian_struct(item: proc_macro::TokenStream) -> proc_macro::TokenStream
     let (impl_generics, ty_generics, where_clause) = item_struct.generics.split_for_impl();
     let item_ident = item_struct.ident.clone();
     let item_ident_str = item_ident.to_string();
+    let validate_fn = if validations.is_empty() {
+        quote![]
+    } else {
+        quote![
+            fn validate(&self) -> ::std::result::Result<(), ::martian::ValidationErrors> {
+                let mut errors = ::martian::ValidationErrors::new();
+                #({ #validations })*
+                errors.into_result()
+            }
+        ]
+    };
     let final_token = quote![
         #[automatically_derived]
         impl #impl_generics ::martian::MartianStruct for #item_ident #ty_generics #where_clause {
@@ -610,6 +776,7 @@ pub fn martian_struct(item: proc_macro::TokenStream) -> proc_macro::TokenStream
                     #(#vec_inner),*
                 ]
             }
+            #validate_fn
         }
 
         #[automatically_derived]
@@ -626,6 +793,69 @@ pub fn martian_struct(item: proc_macro::TokenStream) -> proc_macro::TokenStream
     proc_macro::TokenStream::from(final_token)
 }
 
+// Parse `#[mro_validate(range = <range expr>, exists)]` into the checks to run
+// with `value: &T` and `field: &str` in scope.
+fn parse_mro_validate(attr: &syn::Attribute) -> Result<Vec<proc_macro2::TokenStream>, Error> {
+    let mut checks = Vec::new();
+    attr.parse_nested_meta(|meta| {
+        if meta.path.is_ident("range") {
+            let range: Expr = meta.value()?.parse()?;
+            checks.push(quote![
+                ::martian::validate::check_range(&mut errors, &*field, value, #range);
+            ]);
+            Ok(())
+        } else if meta.path.is_ident("exists") {
+            checks.push(quote![
+                ::martian::validate::check_exists(&mut errors, &*field, value);
+            ]);
+            Ok(())
+        } else {
+            Err(meta.error("Expecting `range = <range>` or `exists` in #[mro_validate(..)]"))
+        }
+    })?;
+    Ok(checks)
+}
+
+// Apply the check to the value inside an `Option`, or to each element of a `Vec`.
+fn validate_value(ty: &Type, check: &proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    if let Some(inner) = generic_argument(ty, "Option") {
+        let check = validate_value(inner, check);
+        quote![
+            if let Some(value) = value {
+                #check
+            }
+        ]
+    } else if let Some(inner) = generic_argument(ty, "Vec") {
+        let check = validate_value(inner, check);
+        quote![
+            for (i, value) in value.iter().enumerate() {
+                let field = format!("{}[{}]", field, i);
+                #check
+            }
+        ]
+    } else {
+        check.clone()
+    }
+}
+
+// `T` if `ty` is `Wrapper<T>`
+fn generic_argument<'a>(ty: &'a Type, wrapper: &str) -> Option<&'a Type> {
+    let Type::Path(ty_path) = ty else {
+        return None;
+    };
+    let segment = ty_path.path.segments.last()?;
+    if segment.ident != wrapper {
+        return None;
+    }
+    let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    match args.args.first()? {
+        syn::GenericArgument::Type(inner) => Some(inner),
+        _ => None,
+    }
+}
+
 /// Custom types which are fields of a `MartianStruct` need to implement `AsMartianBlanketType`.
 /// You can derive that trait on an enum or struct using `#[derive(MartianType)]`
 #[proc_macro_derive(MartianType)]
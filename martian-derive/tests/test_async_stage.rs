@@ -0,0 +1,123 @@
+use martian::prelude::*;
+use martian::MroMaker;
+use martian_derive::{make_mro, MartianStruct};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize, MartianStruct)]
+pub struct SumSquaresStageInputs {
+    values: Vec<f64>,
+}
+#[derive(Debug, Serialize, Deserialize, MartianStruct)]
+pub struct SumSquaresStageOutputs {
+    sum: f64,
+}
+#[derive(Clone, Serialize, Deserialize, MartianStruct)]
+pub struct SumSquaresChunkInputs {
+    value: f64,
+}
+#[derive(Serialize, Deserialize, MartianStruct)]
+pub struct SumSquaresChunkOutputs {
+    square: f64,
+}
+
+pub struct SumSquares;
+
+#[make_mro(mem_gb = 2)]
+impl MartianStageAsync for SumSquares {
+    type StageInputs = SumSquaresStageInputs;
+    type StageOutputs = SumSquaresStageOutputs;
+    type ChunkInputs = SumSquaresChunkInputs;
+    type ChunkOutputs = SumSquaresChunkOutputs;
+
+    async fn split(
+        &self,
+        args: Self::StageInputs,
+        _: MartianRover,
+    ) -> Result<StageDef<Self::ChunkInputs>, Error> {
+        Ok(args
+            .values
+            .into_iter()
+            .map(|value| SumSquaresChunkInputs { value })
+            .collect())
+    }
+
+    async fn main(
+        &self,
+        _: Self::StageInputs,
+        chunk_args: Self::ChunkInputs,
+        _: MartianRover,
+    ) -> Result<Self::ChunkOutputs, Error> {
+        let square = tokio::task::spawn(async move { chunk_args.value * chunk_args.value }).await?;
+        Ok(SumSquaresChunkOutputs { square })
+    }
+
+    async fn join(
+        &self,
+        _: Self::StageInputs,
+        _: Vec<Self::ChunkInputs>,
+        chunk_outs: Vec<Self::ChunkOutputs>,
+        _: MartianRover,
+    ) -> Result<Self::StageOutputs, Error> {
+        Ok(SumSquaresStageOutputs {
+            sum: chunk_outs.iter().map(|c| c.square).sum(),
+        })
+    }
+}
+
+pub struct Sum;
+
+#[make_mro]
+impl MartianMainAsync for Sum {
+    type StageInputs = SumSquaresStageInputs;
+    type StageOutputs = SumSquaresStageOutputs;
+
+    async fn main(
+        &self,
+        args: Self::StageInputs,
+        _: MartianRover,
+    ) -> Result<Self::StageOutputs, Error> {
+        tokio::task::yield_now().await;
+        Ok(SumSquaresStageOutputs {
+            sum: args.values.iter().sum(),
+        })
+    }
+
+    fn validate(&self, args: &Self::StageInputs) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        if args.values.is_empty() {
+            errors.add("values", "must not be empty");
+        }
+        errors.into_result()
+    }
+}
+
+#[test]
+fn test_async_stage_test_run() {
+    let args = SumSquaresStageInputs {
+        values: vec![1.0, 2.0, 3.0],
+    };
+    assert_eq!(SumSquares.test_run_tmpdir(args.clone()).unwrap().sum, 14.0);
+    assert_eq!(Sum.test_run_tmpdir(args).unwrap().sum, 6.0);
+}
+
+#[test]
+fn test_async_stage_validate() {
+    let err = Sum
+        .test_run_tmpdir(SumSquaresStageInputs { values: vec![] })
+        .unwrap_err();
+    assert_eq!(
+        err.downcast::<ValidationErrors>().unwrap().to_string(),
+        "invalid stage inputs:\n  - values: must not be empty"
+    );
+}
+
+#[test]
+fn test_async_stage_mro() {
+    let (stage_registry, mro_registry) = martian_stages![SumSquares, Sum];
+    assert_eq!(stage_registry.len(), 2);
+    assert_eq!(mro_registry.len(), 2);
+    assert_eq!(<SumSquares as MroMaker>::stage_name(), "SUM_SQUARES");
+    assert!(<SumSquares as MroMaker>::chunk_in_and_out().is_some());
+    assert_eq!(<SumSquares as MroMaker>::using_attributes().mem_gb, Some(2));
+    assert!(<Sum as MroMaker>::chunk_in_and_out().is_none());
+}
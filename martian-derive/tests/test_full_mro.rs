@@ -1,6 +1,8 @@
-use martian::make_mro_string;
 use martian::mro::{AsMartianBlanketType, MroMaker};
 use martian::prelude::*;
+use martian::{
+    make_mro_string, make_mro_string_with_pipelines, Binding, PipelineCall, PipelineMro,
+};
 use martian_derive::{make_mro, martian_filetype, MartianStruct, MartianType};
 use pretty_assertions::assert_eq;
 use serde::de::DeserializeOwned;
@@ -550,3 +552,93 @@ fn test_typed_map() {
         expected
     );
 }
+
+#[test]
+fn test_pipeline() {
+    #[derive(Serialize, Deserialize, MartianStruct)]
+    pub struct SumSquaresStageInputs {
+        values: Vec<f64>,
+    }
+    #[derive(Serialize, Deserialize, MartianStruct)]
+    pub struct SumSquaresStageOutputs {
+        sum_sq: f64,
+    }
+    pub struct SumSquares;
+
+    #[make_mro(mem_gb = 4, threads = 2)]
+    impl MartianMain for SumSquares {
+        type StageInputs = SumSquaresStageInputs;
+        type StageOutputs = SumSquaresStageOutputs;
+
+        fn main(&self, _: Self::StageInputs, _: MartianRover) -> Result<Self::StageOutputs, Error> {
+            unimplemented!()
+        }
+    }
+
+    #[derive(Serialize, Deserialize, MartianStruct)]
+    pub struct ReportStageInputs {
+        sum_sq: f64,
+        title: Option<String>,
+    }
+    #[derive(Serialize, Deserialize, MartianStruct)]
+    pub struct ReportStageOutputs {
+        report: TxtFile,
+    }
+    pub struct Report;
+
+    #[make_mro]
+    impl MartianMain for Report {
+        type StageInputs = ReportStageInputs;
+        type StageOutputs = ReportStageOutputs;
+
+        fn main(&self, _: Self::StageInputs, _: MartianRover) -> Result<Self::StageOutputs, Error> {
+            unimplemented!()
+        }
+    }
+
+    let pipeline = PipelineMro::builder("SUM_SQUARES_REPORT")
+        .inputs::<SumSquaresStageInputs>()
+        .outputs::<ReportStageOutputs>()
+        .output::<f64>("sum_sq")
+        .call(PipelineCall::stage::<SumSquares>().bind("values", Binding::input("values")))
+        .call(
+            PipelineCall::stage::<Report>()
+                .bind("sum_sq", Binding::output("SUM_SQUARES", "sum_sq"))
+                .bind("title", Binding::value(&"Sum of squares").unwrap()),
+        )
+        .returns("report", Binding::output("REPORT", "report"))
+        .returns("sum_sq", Binding::output("SUM_SQUARES", "sum_sq"))
+        .build()
+        .unwrap();
+
+    let expected = include_str!("mro/test_pipeline.mro");
+
+    assert_eq!(
+        make_mro_string_with_pipelines(
+            HEADER,
+            &[
+                SumSquares::stage_mro("adapter", "sum_squares"),
+                Report::stage_mro("adapter", "report"),
+            ],
+            &[pipeline],
+        ),
+        expected
+    );
+
+    // A renamed stage output is caught when the pipeline is built.
+    let err = PipelineMro::builder("SUM_SQUARES_REPORT")
+        .input::<Vec<f64>>("values")
+        .call(PipelineCall::stage::<SumSquares>().bind("values", Binding::input("values")))
+        .call(
+            PipelineCall::stage::<Report>()
+                .bind("sum_sq", Binding::output("SUM_SQUARES", "sum"))
+                .bind("title", Binding::Null),
+        )
+        .build()
+        .unwrap_err();
+    assert_eq!(
+        format!("{err:#}"),
+        "in call REPORT of pipeline SUM_SQUARES_REPORT: `sum_sq` is bound to SUM_SQUARES.sum, \
+         which is not an output of SUM_SQUARES"
+    );
+}
@@ -99,6 +99,74 @@ fn test_retain() {
     assert_eq!(expected, SimpleVec::mro_fields())
 }
 
+#[test]
+fn test_optional() {
+    #[derive(MartianStruct)]
+    #[allow(dead_code)]
+    struct Simple {
+        value: Option<i32>,
+        #[mro_retain]
+        values: Option<Vec<f64>>,
+        count: i32,
+    }
+    let expected = vec![
+        MroField::new("value", Primary(Int), None, None).optional(true),
+        MroField::retained("values", Array(Float.into()), None, None).optional(true),
+        MroField::new("count", Primary(Int), None, None),
+    ];
+    assert_eq!(expected, Simple::mro_fields());
+    assert!(Simple::mro_fields()[0].is_optional());
+}
+
+#[test]
+fn test_json_schema() {
+    #[derive(MartianStruct)]
+    #[allow(dead_code)]
+    struct Reads {
+        /// Read 1
+        r1: TxtFile,
+        lanes: Option<Vec<u8>>,
+    }
+    #[derive(MartianStruct)]
+    #[allow(dead_code)]
+    struct SI {
+        /// The reads of each sample
+        reads: HashMap<String, Reads>,
+        threshold: Option<f64>,
+    }
+    assert_eq!(
+        SI::json_schema(),
+        serde_json::json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "object",
+            "properties": {
+                "reads": {
+                    "type": "object",
+                    "additionalProperties": {"$ref": "#/$defs/Reads"},
+                    "description": "The reads of each sample",
+                },
+                "threshold": {"anyOf": [{"type": "number"}, {"type": "null"}]},
+            },
+            "required": ["reads"],
+            "$defs": {
+                "Reads": {
+                    "type": "object",
+                    "properties": {
+                        "r1": {"type": "string", "description": "Read 1"},
+                        "lanes": {
+                            "anyOf": [
+                                {"type": "array", "items": {"type": "integer"}},
+                                {"type": "null"},
+                            ],
+                        },
+                    },
+                    "required": ["r1"],
+                },
+            },
+        })
+    );
+}
+
 #[allow(dead_code)]
 #[test]
 fn test_mro_type_attr() {
@@ -148,3 +216,48 @@ fn test_mro_type_retain_attr() {
     let expected = vec![MroField::retained("values", Array(Map.into()), None, None)];
     assert_eq!(expected, SimpleVec::mro_fields());
 }
+
+#[test]
+fn test_mro_validate_attr() {
+    use martian::MartianFileType;
+
+    #[derive(MartianStruct)]
+    struct Inputs {
+        #[mro_validate(range = 1..=64)]
+        threads: u32,
+        #[mro_validate(range = 0.0..1.0)]
+        fraction: Option<f64>,
+        #[mro_validate(exists)]
+        reads: Vec<TxtFile>,
+        #[mro_validate(exists)]
+        reference: Option<PathBuf>,
+        name: String,
+    }
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let reads = TxtFile::new(tmp_dir.path(), "reads");
+    std::fs::write(&reads, "").unwrap();
+    let valid = Inputs {
+        threads: 4,
+        fraction: None,
+        reads: vec![reads.clone()],
+        reference: Some(tmp_dir.path().to_path_buf()),
+        name: String::new(),
+    };
+    assert_eq!(valid.validate(), Ok(()));
+    assert!(valid.name.is_empty());
+
+    let missing = TxtFile::new(tmp_dir.path(), "missing");
+    let invalid = Inputs {
+        threads: 0,
+        fraction: Some(1.5),
+        reads: vec![reads, missing.clone()],
+        ..valid
+    };
+    let errors = invalid.validate().unwrap_err();
+    let fields: Vec<_> = errors.iter().map(|e| e.field.as_str()).collect();
+    assert_eq!(fields, ["threads", "fraction", "reads[1]"]);
+    assert_eq!(
+        errors.iter().last().unwrap().message,
+        format!("{} does not exist", missing.as_ref().display())
+    );
+}
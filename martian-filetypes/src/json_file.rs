@@ -193,6 +193,9 @@ where
         }
 
         let mut stream = StreamDeserializer::<_, T>::new(io_read);
+        // Only used to skip trailing whitespace after an error, and the
+        // reader is typically a `BufReader` anyway.
+        #[allow(clippy::unbuffered_bytes)]
         match stream.next() {
             Some(Ok(t)) => Some(Ok(t)),
             Some(Err(e)) => self
@@ -503,6 +506,28 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_json_lazy_read_with_progress() -> Result<(), Error> {
+        let json_file = JsonFile::<Vec<i32>>::tempfile()?;
+        let input: Vec<i32> = (0..1000).collect();
+        json_file.write(&input)?;
+        let tmp = tempfile::tempdir()?;
+        let rover = martian::MartianRover::new(
+            tmp.path(),
+            martian::Resource::with_mem_gb(1).threads(1).vmem_gb(2),
+        );
+        let reader = json_file.lazy_reader_with_progress(&rover)?;
+        let actual: Vec<i32> = reader.collect::<Result<_, _>>()?;
+        assert_eq!(actual, input);
+
+        let lz4_file = crate::lz4_file::Lz4::<JsonFile<Vec<i32>>>::tempfile()?;
+        lz4_file.write(&input)?;
+        let reader = lz4_file.lazy_reader_with_progress(&rover)?;
+        let actual: Vec<i32> = reader.collect::<Result<_, _>>()?;
+        assert_eq!(actual, input);
+        Ok(())
+    }
+
     #[test]
     fn test_json_lazy_read_fail() {
         let paired_bc_reader: LazyJsonReader<String> = JsonFile::from("tests/newline_end.json")
@@ -95,7 +95,7 @@
 //! # 2. Common file formats
 //! ## Terminology
 //! - **Lazy Reading**: Read items one by one from a file that stores a list of items,
-//! without reading the entire file into memory
+//!   without reading the entire file into memory
 //! - **Lazy Writing**: Write items one by one into a file that stores a list of items.
 //!
 //! ## Performance comparison
@@ -119,7 +119,8 @@
 //! ## Examples
 //! Look at the individual filetype modules for examples.
 
-use martian::{Error, MartianFileType};
+use martian::progress::ProgressReader;
+use martian::{Error, MartianFileType, MartianRover};
 use std::fs::File;
 use std::iter::FromIterator;
 use std::path::PathBuf;
@@ -275,8 +276,33 @@ pub trait LazyFileTypeIO<T>: MartianFileType + Sized {
     }
     /// Get a lazy writer for this `MartianFileType`
     fn lazy_writer(&self) -> Result<Self::LazyWriter, Error>;
+
+    /// Get a lazy reader for this `MartianFileType` which reports the
+    /// fraction of the file read so far as the progress of the stage, see
+    /// [`MartianRover::progress()`].
+    fn lazy_reader_with_progress(
+        &self,
+        rover: &MartianRover,
+    ) -> Result<ProgressLazyReader<Self, T>, Error>
+    where
+        Self: LazyAgents<T, io::BufWriter<File>, ProgressReader<io::BufReader<File>>>,
+    {
+        let path: &std::path::Path = self.as_ref();
+        let total = std::fs::metadata(path)?.len();
+        let message = format!("reading {}", path.display());
+        LazyRead::with_reader(ProgressReader::new(
+            self.buf_reader()?,
+            total,
+            rover,
+            message,
+        ))
+    }
 }
 
+/// The lazy reader returned by [`LazyFileTypeIO::lazy_reader_with_progress()`].
+pub type ProgressLazyReader<F, T> =
+    <F as LazyAgents<T, io::BufWriter<File>, ProgressReader<io::BufReader<File>>>>::LazyReader;
+
 /// The trait lazy readers need to implement, which lets you read items one by one from a file
 /// that stores a list of items
 pub trait LazyRead<T, R: io::Read>: Sized + Iterator<Item = Result<T, Error>> {
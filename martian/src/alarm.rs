@@ -0,0 +1,348 @@
+//! Structured alarms, aggregated by key.
+//!
+//! An [`Alarm`] has a severity, a stable key identifying the kind of problem,
+//! a message and optional metrics. Alarms are raised with
+//! [`MartianRover::raise_alarm()`]:
+//! ```rust
+//! # use martian::{Alarm, MartianRover, Resource};
+//! # let rover = MartianRover::new("/tmp", Resource::with_mem_gb(1).threads(1).vmem_gb(2));
+//! # let (mapped, total) = (12, 100);
+//! rover.raise_alarm(
+//!     Alarm::warning("low_mapping_rate", format!("only {mapped} of {total} reads mapped"))
+//!         .metric("mapping_rate", mapped as f64 / total as f64),
+//! )?;
+//! # Ok::<(), martian::Error>(())
+//! ```
+//! The first occurrence of a key is written to `_alarm` right away.
+//! Repeated occurrences are counted and written as a single line with the
+//! number of occurrences at most once a minute, and when the stage finishes,
+//! so that a loop which raises an alarm for every record does not flood
+//! `_alarm`:
+//! ```text
+//! 2024-05-02 10:31:07 [warning] low_mapping_rate: only 12 of 100 reads mapped (mapping_rate=0.12)
+//! 2024-05-02 10:32:09 [warning] low_mapping_rate: only 3 of 100 reads mapped (mapping_rate=0.03) (raised 5312 times)
+//! ```
+//! When the stage finishes, every alarm it raised is also summarized in
+//! `_alarm_summary`, as an [`AlarmSummary`], for pipeline reports.
+//!
+//! [`MartianRover::raise_alarm()`]: crate::MartianRover::raise_alarm
+use crate::metadata::{make_timestamp_now, SharedFile};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Minimum time between two lines written for the same alarm key.
+const REPEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlarmSeverity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for AlarmSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AlarmSeverity::Info => "info",
+            AlarmSeverity::Warning => "warning",
+            AlarmSeverity::Error => "error",
+        })
+    }
+}
+
+/// An alarm raised by a stage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alarm {
+    pub severity: AlarmSeverity,
+    /// Identifies the kind of alarm. Alarms with the same key are aggregated.
+    pub key: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metrics: BTreeMap<String, f64>,
+}
+
+impl Alarm {
+    pub fn new(
+        severity: AlarmSeverity,
+        key: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        Alarm {
+            severity,
+            key: key.into(),
+            message: message.into(),
+            metrics: BTreeMap::new(),
+        }
+    }
+
+    pub fn info(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Alarm::new(AlarmSeverity::Info, key, message)
+    }
+
+    pub fn warning(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Alarm::new(AlarmSeverity::Warning, key, message)
+    }
+
+    pub fn error(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Alarm::new(AlarmSeverity::Error, key, message)
+    }
+
+    /// Attach a metric to the alarm.
+    pub fn metric(mut self, name: impl Into<String>, value: f64) -> Self {
+        self.metrics.insert(name.into(), value);
+        self
+    }
+}
+
+/// `[severity] key: message (metric=value, ..)`. The key is omitted if it is
+/// the message itself.
+impl fmt::Display for Alarm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] ", self.severity)?;
+        if self.key != self.message {
+            write!(f, "{}: ", self.key)?;
+        }
+        f.write_str(&self.message)?;
+        if !self.metrics.is_empty() {
+            let metrics: Vec<_> = self
+                .metrics
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect();
+            write!(f, " ({})", metrics.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Every alarm raised by a stage, written to `_alarm_summary` when the
+/// stage finishes.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AlarmSummary {
+    /// In the order in which the keys were first raised.
+    pub alarms: Vec<AlarmRecord>,
+}
+
+/// All the occurrences of one alarm key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlarmRecord {
+    /// The last occurrence of the alarm.
+    #[serde(flatten)]
+    pub alarm: Alarm,
+    pub count: u64,
+    pub first_raised: String,
+    pub last_raised: String,
+}
+
+/// Writes the alarms of a stage to `_alarm`, aggregating repeated keys.
+#[derive(Debug, Clone)]
+pub(crate) struct AlarmLog {
+    file: SharedFile,
+    state: Arc<Mutex<AlarmState>>,
+}
+
+#[derive(Debug)]
+struct AlarmState {
+    repeat_interval: Duration,
+    entries: Vec<Entry>,
+    index: HashMap<String, usize>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    record: AlarmRecord,
+    last_written: Instant,
+    /// Occurrences since the last line written for this key.
+    unwritten: u64,
+    /// Written as the bare message, see [`AlarmLog::raise_message()`].
+    plain: bool,
+}
+
+impl Entry {
+    fn line(&self) -> String {
+        let alarm = if self.plain {
+            self.record.alarm.message.clone()
+        } else {
+            self.record.alarm.to_string()
+        };
+        if self.record.count == 1 {
+            alarm
+        } else {
+            format!("{alarm} (raised {} times)", self.record.count)
+        }
+    }
+}
+
+impl AlarmLog {
+    pub fn new(file: SharedFile) -> Self {
+        AlarmLog::with_repeat_interval(file, REPEAT_INTERVAL)
+    }
+
+    fn with_repeat_interval(file: SharedFile, repeat_interval: Duration) -> Self {
+        AlarmLog {
+            file,
+            state: Arc::new(Mutex::new(AlarmState {
+                repeat_interval,
+                entries: Vec::new(),
+                index: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn raise(&self, alarm: Alarm) -> Result<()> {
+        self.raise_entry(alarm, false)
+    }
+
+    /// Raise a warning keyed by `message`, which is written to `_alarm` as
+    /// the bare message, the format of `_alarm` from before structured
+    /// alarms. Used by the `alarm()` methods, whose output Martian shows to
+    /// users as is.
+    pub fn raise_message(&self, message: &str) -> Result<()> {
+        self.raise_entry(Alarm::warning(message, message), true)
+    }
+
+    fn raise_entry(&self, alarm: Alarm, plain: bool) -> Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        let timestamp = make_timestamp_now();
+        if let Some(&i) = state.index.get(&alarm.key) {
+            let repeat_interval = state.repeat_interval;
+            let entry = &mut state.entries[i];
+            entry.record.alarm = alarm;
+            entry.record.count += 1;
+            entry.record.last_raised = timestamp;
+            entry.unwritten += 1;
+            if now.duration_since(entry.last_written) >= repeat_interval {
+                entry.last_written = now;
+                entry.unwritten = 0;
+                self.file.appendln(&entry.line(), true)?;
+            }
+        } else {
+            let entry = Entry {
+                record: AlarmRecord {
+                    alarm,
+                    count: 1,
+                    first_raised: timestamp.clone(),
+                    last_raised: timestamp,
+                },
+                last_written: now,
+                unwritten: 0,
+                plain,
+            };
+            self.file.appendln(&entry.line(), true)?;
+            let i = state.entries.len();
+            state.index.insert(entry.record.alarm.key.clone(), i);
+            state.entries.push(entry);
+        }
+        Ok(())
+    }
+
+    /// Write the repeated alarms which were not written yet and summarize
+    /// all the alarms raised so far.
+    pub fn finish(&self) -> Result<AlarmSummary> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        for entry in state.entries.iter_mut().filter(|e| e.unwritten > 0) {
+            entry.unwritten = 0;
+            self.file.appendln(&entry.line(), true)?;
+        }
+        Ok(AlarmSummary {
+            alarms: state.entries.iter().map(|e| e.record.clone()).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alarm_display() {
+        let alarm = Alarm::warning("low_mapping_rate", "only 12 of 100 reads mapped")
+            .metric("mapping_rate", 0.12)
+            .metric("mapped", 12.0);
+        assert_eq!(
+            alarm.to_string(),
+            "[warning] low_mapping_rate: only 12 of 100 reads mapped (mapped=12, mapping_rate=0.12)"
+        );
+        assert_eq!(
+            Alarm::info("no reads", "no reads").to_string(),
+            "[info] no reads"
+        );
+    }
+
+    #[test]
+    fn test_alarm_log() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("_alarm");
+        let log = AlarmLog::new(SharedFile::new(path.clone()));
+        for i in 0..1000 {
+            log.raise(Alarm::warning("bad_record", format!("record {i} is bad")))?;
+        }
+        log.raise(Alarm::error("no_output", "no output").metric("records", 1000.0))?;
+        let lines = std::fs::read_to_string(&path)?;
+        assert_eq!(lines.lines().count(), 2, "{lines}");
+
+        let summary = log.finish()?;
+        let lines = std::fs::read_to_string(&path)?;
+        let lines: Vec<_> = lines.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with(" [warning] bad_record: record 0 is bad"));
+        assert!(lines[1].ends_with(" [error] no_output: no output (records=1000)"));
+        assert!(lines[2].ends_with(" [warning] bad_record: record 999 is bad (raised 1000 times)"));
+
+        assert_eq!(summary.alarms.len(), 2);
+        assert_eq!(summary.alarms[0].count, 1000);
+        assert_eq!(summary.alarms[0].alarm.message, "record 999 is bad");
+        assert_eq!(summary.alarms[1].alarm.severity, AlarmSeverity::Error);
+        let json = serde_json::to_value(&summary)?;
+        assert_eq!(json["alarms"][1]["severity"], "error");
+        assert_eq!(json["alarms"][1]["metrics"]["records"], 1000.0);
+        assert_eq!(serde_json::from_value::<AlarmSummary>(json)?, summary);
+
+        // Nothing left to write.
+        log.finish()?;
+        assert_eq!(std::fs::read_to_string(&path)?.lines().count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_alarm_repeat_interval() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("_alarm");
+        let log = AlarmLog::with_repeat_interval(SharedFile::new(path.clone()), Duration::ZERO);
+        for _ in 0..3 {
+            log.raise(Alarm::warning("slow", "slow"))?;
+        }
+        log.finish()?;
+        let lines = std::fs::read_to_string(&path)?;
+        let lines: Vec<_> = lines.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].ends_with(" [warning] slow (raised 3 times)"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_alarm_message() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("_alarm");
+        let log = AlarmLog::new(SharedFile::new(path.clone()));
+        for _ in 0..3 {
+            log.raise_message("low coverage")?;
+        }
+        let summary = log.finish()?;
+        let lines = std::fs::read_to_string(&path)?;
+        let lines: Vec<_> = lines.lines().collect();
+        assert_eq!(lines.len(), 2);
+        // Same format as before structured alarms, apart from the repeats.
+        assert!(lines[0].ends_with(" low coverage"), "{}", lines[0]);
+        assert!(!lines[0].contains("[warning]"));
+        assert!(lines[1].ends_with(" low coverage (raised 3 times)"));
+        assert_eq!(summary.alarms[0].alarm.severity, AlarmSeverity::Warning);
+        Ok(())
+    }
+}
@@ -0,0 +1,545 @@
+//! Global allocator which keeps track of the heap usage of the stage.
+//!
+//! Enabled by the `tracking-allocator` feature. The allocator has to be
+//! installed by the adapter binary:
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOCATOR: martian::alloc::TrackingAllocator = martian::alloc::TrackingAllocator::new();
+//! ```
+//! Once installed:
+//! - `mrp --profile=mem` produces a sampled heap profile of the stage in
+//!   `_profile.out`. The format is the "folded stacks" format used by
+//!   flamegraph tools, where each line is a `;` separated call stack followed
+//!   by the number of bytes allocated from that call stack.
+//! - The heap usage is compared against the `mem_gb` reservation of the stage
+//!   (and the size of the address space against `vmem_gb`), and an alarm is
+//!   raised when it crosses the thresholds of the [`MemoryGuard`]. Optionally,
+//!   the stage can be failed with a clear error message when its heap grows
+//!   past a hard limit, rather than being killed by the job manager.
+//! - The high-water mark of the heap is recorded in the `_jobinfo`, as
+//!   `rust.usage.max_heap_bytes`.
+use crate::alarm::{Alarm, AlarmLog};
+use crate::errors_closed;
+use crate::usage::spawn_helper_thread;
+use log::{info, warn};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// On average, one call stack is recorded per `SAMPLE_INTERVAL` bytes
+/// allocated by a thread while heap profiling.
+const SAMPLE_INTERVAL: usize = 512 * 1024;
+
+/// Maximum number of frames recorded per sample.
+const MAX_FRAMES: usize = 64;
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static CURRENT_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PROFILING: AtomicBool = AtomicBool::new(false);
+// Heap usage at which the stage is failed, usize::MAX if disabled.
+static ABORT_LIMIT_BYTES: AtomicUsize = AtomicUsize::new(usize::MAX);
+static ABORTING: AtomicBool = AtomicBool::new(false);
+// Bytes allocated from each sampled call stack.
+static SAMPLES: Mutex<Option<HashMap<Vec<usize>, usize>>> = Mutex::new(None);
+
+thread_local! {
+    // Bytes allocated by this thread since the last sample.
+    static UNSAMPLED_BYTES: Cell<usize> = const { Cell::new(0) };
+    // Set while this thread is recording a sample, so that the allocations
+    // made by the sampler itself are not sampled.
+    static IN_SAMPLER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Wrapper around the system allocator which tracks the number of bytes
+/// allocated on the heap. See the [module documentation](self).
+#[derive(Debug, Default)]
+pub struct TrackingAllocator;
+
+impl TrackingAllocator {
+    pub const fn new() -> Self {
+        TrackingAllocator
+    }
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            on_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            on_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            if new_size > layout.size() {
+                on_alloc(new_size - layout.size());
+            } else {
+                CURRENT_BYTES.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+            }
+        }
+        new_ptr
+    }
+}
+
+#[inline]
+fn on_alloc(size: usize) {
+    if !INSTALLED.load(Ordering::Relaxed) {
+        INSTALLED.store(true, Ordering::Relaxed);
+    }
+    let current = CURRENT_BYTES.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_BYTES.fetch_max(current, Ordering::Relaxed);
+    if current > ABORT_LIMIT_BYTES.load(Ordering::Relaxed) {
+        abort_over_limit(current);
+    }
+    if PROFILING.load(Ordering::Relaxed) {
+        maybe_sample(size);
+    }
+}
+
+#[cold]
+fn maybe_sample(size: usize) {
+    // try_with fails during thread teardown, in which case the allocation
+    // is just not sampled.
+    let Ok(weight) = UNSAMPLED_BYTES.try_with(|unsampled| {
+        let total = unsampled.get() + size;
+        if total < SAMPLE_INTERVAL {
+            unsampled.set(total);
+            0
+        } else {
+            unsampled.set(0);
+            total
+        }
+    }) else {
+        return;
+    };
+    if weight == 0 || IN_SAMPLER.try_with(|s| s.replace(true)) != Ok(false) {
+        return;
+    }
+
+    let mut frames = [0usize; MAX_FRAMES];
+    let mut depth = 0;
+    backtrace::trace(|frame| {
+        frames[depth] = frame.ip() as usize;
+        depth += 1;
+        depth < MAX_FRAMES
+    });
+    if let Some(samples) = SAMPLES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_mut()
+    {
+        *samples.entry(frames[..depth].to_vec()).or_default() += weight;
+    }
+
+    let _ = IN_SAMPLER.try_with(|s| s.set(false));
+}
+
+/// Fail the stage because its heap grew past the abort limit.
+///
+/// This is called from within the allocator, so it must neither allocate nor
+/// take a lock which the allocating thread may already hold, like the one of
+/// `write_errors`. The message is written straight to the _errors and _log
+/// file descriptors instead.
+#[cold]
+fn abort_over_limit(current: usize) {
+    if ABORTING.swap(true, Ordering::Relaxed) {
+        // Another thread is already taking care of it.
+        return;
+    }
+    let mut buf = [0u8; 256];
+    let mut cursor = std::io::Cursor::new(&mut buf[..]);
+    let _ = std::io::Write::write_fmt(
+        &mut cursor,
+        format_args!(
+            "stage aborted by the memory guard: heap usage of {} bytes \
+            exceeded the limit of {} bytes",
+            current,
+            ABORT_LIMIT_BYTES.load(Ordering::Relaxed),
+        ),
+    );
+    let len = cursor.position() as usize;
+    if !errors_closed() {
+        write_fd(4, &buf[..len]);
+    }
+    // The logger is not flushed, as it takes locks too. It flushes after
+    // every record anyway.
+    write_fd(3, &buf[..len]);
+    write_fd(3, b"\n");
+    unsafe { libc::_exit(1) }
+}
+
+fn write_fd(fd: libc::c_int, mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        let written = unsafe { libc::write(fd, bytes.as_ptr().cast(), bytes.len()) };
+        if written <= 0 {
+            return;
+        }
+        bytes = &bytes[written as usize..];
+    }
+}
+
+/// Whether the tracking allocator is installed as the global allocator.
+pub fn is_installed() -> bool {
+    INSTALLED.load(Ordering::Relaxed)
+}
+
+/// Number of bytes currently allocated on the heap.
+pub fn current_bytes() -> usize {
+    CURRENT_BYTES.load(Ordering::Relaxed)
+}
+
+/// Highest number of bytes allocated on the heap at any one time.
+pub fn peak_bytes() -> usize {
+    PEAK_BYTES.load(Ordering::Relaxed)
+}
+
+const GB: f64 = (1u64 << 30) as f64;
+
+/// Thresholds at which the memory usage of the stage is reported, as a
+/// fraction of the `mem_gb` and `vmem_gb` reserved for the stage.
+///
+/// By default an alarm is raised when the heap usage crosses 100% of `mem_gb`
+/// or the address space crosses 100% of `vmem_gb`, and the stage is never
+/// aborted.
+/// ```rust
+/// use martian::alloc::MemoryGuard;
+/// // Alarm at 80% and 100% of the reservation, and fail the stage with a
+/// // clear message once the heap reaches 150% of mem_gb.
+/// let guard = MemoryGuard::new().alarm_at(&[0.8, 1.0]).abort_at(1.5);
+/// ```
+/// Pass it to [`MartianAdapter::memory_guard()`](crate::MartianAdapter::memory_guard).
+/// The guard is only active if [`TrackingAllocator`] is the global allocator.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryGuard {
+    alarm_thresholds: Vec<f64>,
+    abort_threshold: Option<f64>,
+    poll_interval: Duration,
+}
+
+impl Default for MemoryGuard {
+    fn default() -> Self {
+        MemoryGuard {
+            alarm_thresholds: vec![1.0],
+            abort_threshold: None,
+            poll_interval: Duration::from_millis(200),
+        }
+    }
+}
+
+impl MemoryGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Raise an alarm the first time the memory usage crosses each of the
+    /// `thresholds`, given as fractions of the reservation.
+    pub fn alarm_at(self, thresholds: &[f64]) -> Self {
+        let mut alarm_thresholds = thresholds.to_vec();
+        alarm_thresholds.sort_by(f64::total_cmp);
+        MemoryGuard {
+            alarm_thresholds,
+            ..self
+        }
+    }
+
+    /// Fail the stage as soon as the heap usage exceeds this fraction of `mem_gb`.
+    pub fn abort_at(self, threshold: f64) -> Self {
+        MemoryGuard {
+            abort_threshold: Some(threshold),
+            ..self
+        }
+    }
+
+    /// Disable all the checks.
+    pub fn disabled() -> Self {
+        MemoryGuard {
+            alarm_thresholds: Vec::new(),
+            abort_threshold: None,
+            ..Self::default()
+        }
+    }
+
+    /// How often the memory usage is compared against the alarm thresholds.
+    pub fn poll_interval(self, poll_interval: Duration) -> Self {
+        MemoryGuard {
+            poll_interval,
+            ..self
+        }
+    }
+
+    /// Start enforcing the guard for a stage with the given reservation.
+    /// Alarms are raised through `alarms`.
+    pub(crate) fn start(&self, mem_gb: usize, vmem_gb: usize, alarms: AlarmLog) {
+        if !is_installed() {
+            return;
+        }
+        let mem_bytes = mem_gb as f64 * GB;
+        let vmem_bytes = vmem_gb as f64 * GB;
+        if let (Some(threshold), true) = (self.abort_threshold, mem_gb > 0) {
+            let limit = (threshold * mem_bytes) as usize;
+            info!("the stage will be aborted if its heap exceeds {limit} bytes");
+            ABORT_LIMIT_BYTES.store(limit, Ordering::Relaxed);
+        }
+        if self.alarm_thresholds.is_empty() {
+            return;
+        }
+
+        let mut heap_alarms = Thresholds::new(&self.alarm_thresholds, mem_bytes);
+        let mut vmem_alarms = Thresholds::new(&self.alarm_thresholds, vmem_bytes);
+        let poll_interval = self.poll_interval;
+        let result = spawn_helper_thread("martian-memory", move || loop {
+            let heap = current_bytes();
+            if let Some(threshold) = heap_alarms.crossed(heap as f64) {
+                let _ = alarms.raise(
+                    Alarm::warning(
+                        format!("heap_usage_{:.0}", threshold * 100.0),
+                        format!(
+                            "heap usage of {:.2} GB exceeded {:.0}% of the {mem_gb} GB memory \
+                             reservation (mem_gb)",
+                            heap as f64 / GB,
+                            threshold * 100.0
+                        ),
+                    )
+                    .metric("heap_bytes", heap as f64)
+                    .metric("threshold", threshold),
+                );
+            }
+            if let Some(vmem) = virtual_memory_bytes() {
+                if let Some(threshold) = vmem_alarms.crossed(vmem as f64) {
+                    let _ = alarms.raise(
+                        Alarm::warning(
+                            format!("vmem_usage_{:.0}", threshold * 100.0),
+                            format!(
+                                "virtual memory size of {:.2} GB exceeded {:.0}% of the \
+                                 {vmem_gb} GB virtual memory reservation (vmem_gb)",
+                                vmem as f64 / GB,
+                                threshold * 100.0
+                            ),
+                        )
+                        .metric("vmem_bytes", vmem as f64)
+                        .metric("threshold", threshold),
+                    );
+                }
+            }
+            if heap_alarms.is_done() && vmem_alarms.is_done() {
+                break;
+            }
+            std::thread::sleep(poll_interval);
+        });
+        if let Err(e) = result {
+            warn!("failed to start the memory guard: {e}");
+        }
+    }
+}
+
+/// Alarm thresholds which have not been crossed yet, in bytes.
+#[derive(Debug)]
+struct Thresholds {
+    // Sorted in decreasing order, so that the next one to cross is last.
+    pending: Vec<(f64, f64)>,
+}
+
+impl Thresholds {
+    fn new(fractions: &[f64], reservation: f64) -> Self {
+        // A reservation of 0 means that it is unknown.
+        let pending = if reservation > 0.0 {
+            fractions
+                .iter()
+                .rev()
+                .map(|&fraction| (fraction, fraction * reservation))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Thresholds { pending }
+    }
+
+    /// Return the highest newly crossed threshold, if any.
+    fn crossed(&mut self, usage: f64) -> Option<f64> {
+        let mut crossed = None;
+        while let Some(&(fraction, bytes)) = self.pending.last() {
+            if usage < bytes {
+                break;
+            }
+            crossed = Some(fraction);
+            self.pending.pop();
+        }
+        crossed
+    }
+
+    fn is_done(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Size of the address space of the process, from `/proc/self/status`.
+fn virtual_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb: u64 = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmSize:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+/// Start recording a sampled heap profile.
+pub(crate) fn start_heap_profile() {
+    *SAMPLES.lock().unwrap_or_else(PoisonError::into_inner) = Some(HashMap::new());
+    PROFILING.store(true, Ordering::Relaxed);
+}
+
+/// Stop recording the heap profile and return it in the folded stacks format.
+pub(crate) fn finish_heap_profile() -> String {
+    PROFILING.store(false, Ordering::Relaxed);
+    let samples = SAMPLES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()
+        .unwrap_or_default();
+
+    let mut names: HashMap<usize, String> = HashMap::new();
+    let mut folded: BTreeMap<String, usize> = BTreeMap::new();
+    for (frames, bytes) in samples {
+        let mut stack = String::new();
+        // Root of the call stack first.
+        for &ip in frames.iter().rev().filter(|&&ip| ip != 0) {
+            let name = names.entry(ip).or_insert_with(|| symbol_name(ip));
+            if is_allocator_frame(name) {
+                // Frames from the allocator and the sampler itself are
+                // always at the top of the stack.
+                break;
+            }
+            if !stack.is_empty() {
+                stack.push(';');
+            }
+            stack.push_str(name);
+        }
+        *folded.entry(stack).or_default() += bytes;
+    }
+
+    let mut out = String::new();
+    for (stack, bytes) in folded {
+        writeln!(&mut out, "{stack} {bytes}").unwrap();
+    }
+    out
+}
+
+fn symbol_name(ip: usize) -> String {
+    let mut name = None;
+    backtrace::resolve(ip as *mut std::ffi::c_void, |symbol| {
+        if name.is_none() {
+            name = symbol.name().map(|n| format!("{n:#}"));
+        }
+    });
+    name.unwrap_or_else(|| format!("{ip:#x}"))
+}
+
+fn is_allocator_frame(name: &str) -> bool {
+    name.starts_with("alloc::alloc::")
+        || name.starts_with("__rust_")
+        || name.starts_with("__rustc::")
+        || name.starts_with("martian::alloc::")
+        || name.starts_with("<martian::alloc::")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::self_adapter;
+    use crate::{Error, Metadata, RawMartianStage, Resource};
+
+    #[global_allocator]
+    static ALLOCATOR: TrackingAllocator = TrackingAllocator::new();
+
+    #[test]
+    fn test_allocator_frames() {
+        assert!(is_allocator_frame("alloc::alloc::exchange_malloc"));
+        assert!(is_allocator_frame("__rustc::__rust_alloc"));
+        assert!(is_allocator_frame(
+            "<martian::alloc::TrackingAllocator as core::alloc::global::GlobalAlloc>::alloc"
+        ));
+        assert!(!is_allocator_frame("alloc::vec::Vec<T>::push"));
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let mut thresholds = Thresholds::new(&[0.5, 0.9, 1.0], 100.0);
+        assert_eq!(thresholds.crossed(10.0), None);
+        assert_eq!(thresholds.crossed(50.0), Some(0.5));
+        assert_eq!(thresholds.crossed(60.0), None);
+        // Jumping over several thresholds reports the highest one.
+        assert_eq!(thresholds.crossed(200.0), Some(1.0));
+        assert!(thresholds.is_done());
+
+        // Unknown reservation
+        assert!(Thresholds::new(&[1.0], 0.0).is_done());
+    }
+
+    const OVER_LIMIT_STAGE: &str = "exceed_memory_limit";
+
+    struct ExceedMemoryLimit;
+
+    impl RawMartianStage for ExceedMemoryLimit {
+        fn split(&self, _: &mut Metadata) -> Result<(), Error> {
+            unimplemented!()
+        }
+
+        fn main(&self, _: &mut Metadata) -> Result<(), Error> {
+            let heap = vec![1u8; 64 << 20];
+            std::hint::black_box(&heap);
+            Ok(())
+        }
+
+        fn join(&self, _: &mut Metadata) -> Result<(), Error> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_abort_over_limit() -> Result<(), Error> {
+        self_adapter::run_if_child(OVER_LIMIT_STAGE, ExceedMemoryLimit, |adapter| {
+            adapter.memory_guard(MemoryGuard::disabled().abort_at(0.01))
+        });
+        let tmp = tempfile::tempdir()?;
+        let run = self_adapter::executor("alloc::tests::test_abort_over_limit", OVER_LIMIT_STAGE)
+            .split_resource(Resource::with_mem_gb(1))
+            .run_with_args(tmp.path().join("run"), Default::default())?;
+
+        let failure = run.failures().next().unwrap();
+        assert_eq!(failure.status.code(), Some(1));
+        let errors = failure.errors.as_deref().unwrap();
+        assert!(
+            errors.starts_with("stage aborted by the memory guard: heap usage of "),
+            "{errors}"
+        );
+        assert!(
+            errors.ends_with("exceeded the limit of 10737418 bytes"),
+            "{errors}"
+        );
+        assert!(failure.log.contains(errors), "{}", failure.log);
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+//! Provenance of the adapter binary, captured when it is compiled.
+//!
+//! The `rust` section of the `_jobinfo` records how the adapter was built.
+//! Compute nodes usually don't have a rust toolchain, so this information is
+//! captured by the build script of the adapter crate:
+//! ```ignore
+//! // build.rs, with `martian` in the [build-dependencies]
+//! fn main() {
+//!     martian::build::emit_build_info();
+//! }
+//! ```
+//! and handed to the adapter with [`build_info!`](crate::build_info):
+//! ```ignore
+//! MartianCli::new(stage_registry, mro_registry)
+//!     .build_info(martian::build_info!())
+//!     .run()
+//! ```
+//! which results in
+//! ```json
+//! "rust": {
+//!     "binpath": "/path/to/adapter",
+//!     "version": "1.75.0",
+//!     "target": "x86_64-unknown-linux-gnu",
+//!     "profile": "release",
+//!     "features": ["rayon"],
+//!     "crate_name": "my_adapter",
+//!     "crate_version": "0.3.1",
+//!     "git_commit": "8f3a2c1e4b..."
+//! }
+//! ```
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Command;
+
+const RUSTC_VERSION_VAR: &str = "MARTIAN_BUILD_RUSTC_VERSION";
+const TARGET_VAR: &str = "MARTIAN_BUILD_TARGET";
+const PROFILE_VAR: &str = "MARTIAN_BUILD_PROFILE";
+const FEATURES_VAR: &str = "MARTIAN_BUILD_FEATURES";
+const GIT_COMMIT_VAR: &str = "MARTIAN_BUILD_GIT_COMMIT";
+
+/// How the adapter binary was built. Created with [`build_info!`](crate::build_info).
+///
+/// Fields are `None` if the build script of the adapter crate did not call
+/// [`emit_build_info()`]. The `_jobinfo` then reports the version of the
+/// rustc found when the stage runs, as it does for adapters without build
+/// info.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    pub rustc_version: Option<String>,
+    pub target: Option<String>,
+    pub profile: Option<String>,
+    /// Enabled cargo features of the adapter crate.
+    pub features: Vec<String>,
+    pub crate_name: String,
+    pub crate_version: String,
+    pub git_commit: Option<String>,
+}
+
+impl BuildInfo {
+    /// Used by [`build_info!`](crate::build_info), which reads the variables
+    /// set by [`emit_build_info()`] at compile time.
+    #[doc(hidden)]
+    pub fn from_build_vars(
+        crate_name: &str,
+        crate_version: &str,
+        vars: [Option<&str>; 5],
+    ) -> BuildInfo {
+        let [rustc_version, target, profile, features, git_commit] = vars;
+        BuildInfo {
+            rustc_version: rustc_version.map(String::from),
+            target: target.map(String::from),
+            profile: profile.map(String::from),
+            features: features
+                .unwrap_or_default()
+                .split(',')
+                .filter(|f| !f.is_empty())
+                .map(String::from)
+                .collect(),
+            crate_name: crate_name.to_string(),
+            crate_version: crate_version.to_string(),
+            git_commit: git_commit.map(String::from),
+        }
+    }
+}
+
+/// Capture the [`BuildInfo`] of the crate being compiled. Call this from the
+/// `build.rs` of the adapter crate.
+///
+/// The rustc version, target triple, profile, enabled features and the git
+/// commit of the crate are passed to the compiler as environment variables
+/// which are read by [`build_info!`](crate::build_info).
+pub fn emit_build_info() {
+    if let Ok(rustc) = rustc_version::version() {
+        emit_var(RUSTC_VERSION_VAR, &rustc.to_string());
+    }
+    for (var, cargo_var) in [(TARGET_VAR, "TARGET"), (PROFILE_VAR, "PROFILE")] {
+        if let Ok(value) = std::env::var(cargo_var) {
+            emit_var(var, &value);
+        }
+    }
+    let features = features_from_env(std::env::vars());
+    emit_var(FEATURES_VAR, &features.join(","));
+
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".into());
+    let manifest_dir = Path::new(&manifest_dir);
+    if let Some(commit) = git(manifest_dir, &["rev-parse", "HEAD"]) {
+        emit_var(GIT_COMMIT_VAR, &commit);
+    }
+    // Capture the commit again when HEAD moves.
+    if let Some(git_dir) = git(manifest_dir, &["rev-parse", "--absolute-git-dir"]) {
+        let git_dir = Path::new(&git_dir);
+        let mut watched = vec![git_dir.join("HEAD"), git_dir.join("packed-refs")];
+        if let Some(head_ref) = git(manifest_dir, &["symbolic-ref", "-q", "HEAD"]) {
+            watched.push(git_dir.join(head_ref));
+        }
+        for path in watched.into_iter().filter(|p| p.exists()) {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+fn emit_var(name: &str, value: &str) {
+    println!("cargo:rustc-env={name}={value}");
+}
+
+/// Feature names from the `CARGO_FEATURE_<NAME>` variables set by cargo for
+/// build scripts. Cargo upper cases the names and replaces `-` with `_`, so
+/// this reports them in lower case.
+fn features_from_env(vars: impl Iterator<Item = (String, String)>) -> Vec<String> {
+    let mut features: Vec<_> = vars
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|name| name.to_lowercase())
+        })
+        .collect();
+    features.sort();
+    features
+}
+
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(stdout.trim().to_string()).filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_build_vars() {
+        let info = BuildInfo::from_build_vars(
+            "my_adapter",
+            "0.3.1",
+            [
+                Some("1.75.0"),
+                None,
+                Some("release"),
+                Some("rayon,tokio"),
+                None,
+            ],
+        );
+        assert_eq!(info.rustc_version.as_deref(), Some("1.75.0"));
+        assert_eq!(info.target, None);
+        assert_eq!(info.features, ["rayon", "tokio"]);
+        assert_eq!(info.crate_name, "my_adapter");
+
+        let info = BuildInfo::from_build_vars("my_adapter", "0.3.1", [Some(""); 5]);
+        assert!(info.features.is_empty());
+    }
+
+    #[test]
+    fn test_features_from_env() {
+        let vars = [
+            ("CARGO_FEATURE_TRACKING_ALLOCATOR", "1"),
+            ("CARGO_PKG_NAME", "my_adapter"),
+            ("CARGO_FEATURE_RAYON", "1"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        assert_eq!(features_from_env(vars), ["rayon", "tracking_allocator"]);
+    }
+}
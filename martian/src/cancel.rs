@@ -0,0 +1,177 @@
+//! Cooperative cancellation of stages terminated by the job manager.
+//!
+//! When the cluster scheduler or `mrp` decides to kill a job (for example
+//! because it exceeded its walltime or memory reservation) it typically sends
+//! `SIGTERM` (or `SIGXCPU` for cpu time limits) and follows up with `SIGKILL`
+//! a little while later. The adapter installs handlers for these signals which
+//! record the reason in `_errors`, flush the `_log` and flag the
+//! [`CancellationToken`] available from [`MartianRover::cancellation_token()`].
+//! Long running stage loops can poll the token to exit cleanly. If the stage
+//! has not exited by the end of the grace period, the process exits with the
+//! conventional `128 + signal` exit code.
+//!
+//! [`MartianRover::cancellation_token()`]: crate::MartianRover::cancellation_token
+use crate::usage::spawn_helper_thread;
+use crate::write_errors;
+use anyhow::Result;
+use log::error;
+use signal_hook::consts::signal::{SIGINT, SIGTERM, SIGXCPU};
+use signal_hook::iterator::Signals;
+use signal_hook::low_level::signal_name;
+use std::fmt;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Signals which are interpreted as a request to terminate the stage.
+pub const TERMINATION_SIGNALS: [i32; 3] = [SIGTERM, SIGINT, SIGXCPU];
+
+/// Default time given to a stage to exit on its own after a termination
+/// signal is received.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Shared flag which is set when the stage has been asked to terminate.
+///
+/// Cloning the token is cheap and all the clones observe the same state,
+/// so it can be handed to worker threads.
+/// ```rust
+/// use martian::CancellationToken;
+///
+/// let token = CancellationToken::new();
+/// let worker_token = token.clone();
+/// assert!(!worker_token.is_cancelled());
+/// token.cancel();
+/// assert!(worker_token.is_cancelled());
+/// assert!(worker_token.check().is_err());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicI32>);
+
+// Values stored in the token, other than the number of the signal which
+// cancelled the stage.
+const NOT_CANCELLED: i32 = 0;
+const CANCELLED_WITHOUT_SIGNAL: i32 = -1;
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the stage has been asked to terminate.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed) != NOT_CANCELLED
+    }
+
+    /// The signal which cancelled the stage, if any.
+    pub fn signal(&self) -> Option<i32> {
+        match self.0.load(Ordering::Relaxed) {
+            NOT_CANCELLED | CANCELLED_WITHOUT_SIGNAL => None,
+            sig => Some(sig),
+        }
+    }
+
+    /// Return a [`Cancelled`] error if the stage has been asked to terminate.
+    /// Intended to be used with `?` inside long running loops.
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled {
+                signal: self.signal(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Flag the token as cancelled.
+    pub fn cancel(&self) {
+        let _ = self.0.compare_exchange(
+            NOT_CANCELLED,
+            CANCELLED_WITHOUT_SIGNAL,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    fn cancel_with_signal(&self, signal: i32) {
+        let _ =
+            self.0
+                .compare_exchange(NOT_CANCELLED, signal, Ordering::Relaxed, Ordering::Relaxed);
+    }
+}
+
+/// Error returned by [`CancellationToken::check()`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled {
+    pub signal: Option<i32>,
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.signal {
+            Some(sig) => write!(f, "stage cancelled: {}", termination_message(sig)),
+            None => write!(f, "stage cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// e.g. `terminated by signal SIGTERM`
+pub(crate) fn termination_message(signal: i32) -> String {
+    match signal_name(signal) {
+        Some(name) => format!("terminated by signal {name}"),
+        None => format!("terminated by signal {signal}"),
+    }
+}
+
+/// Spawn a thread which handles the termination signals by cancelling
+/// `token`, and exits the process if the stage does not finish within
+/// `grace_period`. A second signal exits the process immediately.
+pub(crate) fn install_signal_handlers(
+    token: CancellationToken,
+    grace_period: Duration,
+) -> Result<()> {
+    let mut signals = Signals::new(TERMINATION_SIGNALS)?;
+    spawn_helper_thread("martian-signals", move || {
+        let Some(signal) = signals.forever().next() else {
+            return;
+        };
+        token.cancel_with_signal(signal);
+        let msg = termination_message(signal);
+        error!("{msg}");
+        let _ = write_errors(&msg, false);
+        log::logger().flush();
+
+        let deadline = Instant::now() + grace_period;
+        while Instant::now() < deadline {
+            if signals.pending().next().is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        error!("stage did not exit within {grace_period:?} of being {msg}");
+        log::logger().flush();
+        std::process::exit(128 + signal);
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_with_signal() {
+        let token = CancellationToken::new();
+        assert_eq!(token.check(), Ok(()));
+        token.cancel_with_signal(SIGTERM);
+        // The first cancellation wins.
+        token.cancel();
+        assert_eq!(token.signal(), Some(SIGTERM));
+        let err = token.check().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "stage cancelled: terminated by signal SIGTERM"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Checkpoints of the progress of a chunk, so that it can resume after being
+//! preempted rather than restart from scratch.
+//!
+//! The checkpoint is stored in the files directory of the chunk, which is
+//! kept when the job manager restarts the chunk. A stage saves its progress
+//! periodically with [`CheckpointStore::save()`] and gets the last saved
+//! state back from [`MartianRover::resume()`] when it is restarted:
+//! ```rust
+//! # use martian::{Error, MartianRover, Resource};
+//! # use serde::{Deserialize, Serialize};
+//! #[derive(Serialize, Deserialize, Default)]
+//! struct Progress {
+//!     next_record: usize,
+//!     total: u64,
+//! }
+//!
+//! fn process(rover: &MartianRover, records: &[u64]) -> Result<u64, Error> {
+//!     let mut progress: Progress = rover.resume()?.unwrap_or_default();
+//!     for (i, record) in records.iter().enumerate().skip(progress.next_record) {
+//!         progress.total += record;
+//!         progress.next_record = i + 1;
+//!         if progress.next_record % 1000 == 0 {
+//!             rover.checkpoint().save(&progress)?;
+//!         }
+//!     }
+//!     Ok(progress.total)
+//! }
+//! # let tmp = tempfile::tempdir().unwrap();
+//! # let rover = MartianRover::new(tmp.path(), Resource::with_mem_gb(1).threads(1).vmem_gb(2));
+//! # assert_eq!(process(&rover, &vec![1; 2500]).unwrap(), 2500);
+//! # assert!(MartianRover::new(tmp.path(), Resource::with_mem_gb(1).threads(1).vmem_gb(2)).is_resumed());
+//! ```
+//! The checkpoint is deleted once the stage completes successfully.
+//!
+//! [`MartianRover::resume()`]: crate::MartianRover::resume
+use crate::metadata::make_timestamp_now;
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{rename, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+const CHECKPOINT_FN: &str = ".martian_checkpoint.json";
+
+/// Saves the progress of a chunk into its files directory. Cloning the store
+/// is cheap, so it can be handed to worker threads.
+#[derive(Debug, Clone)]
+pub struct CheckpointStore {
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint<T> {
+    saved: String,
+    state: T,
+}
+
+impl CheckpointStore {
+    /// Store for the checkpoint of the chunk with the given files directory.
+    pub fn new(files_path: impl AsRef<Path>) -> Self {
+        CheckpointStore {
+            path: files_path.as_ref().join(CHECKPOINT_FN),
+        }
+    }
+
+    /// Path of the checkpoint file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether a checkpoint has been saved.
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Replace the checkpoint with `state`. Like the journal entries written
+    /// by the adapter, the file is written under a temporary name and then
+    /// renamed, so a chunk killed while saving resumes from the previous
+    /// checkpoint.
+    pub fn save<T: Serialize>(&self, state: &T) -> Result<()> {
+        let checkpoint = Checkpoint {
+            saved: make_timestamp_now(),
+            state,
+        };
+        let tmp_path = self.path.with_extension("json.tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            serde_json::to_writer(&mut writer, &checkpoint)?;
+            writer.into_inner()?.sync_all()?;
+        }
+        rename(&tmp_path, &self.path)?;
+        log::debug!("saved checkpoint {}", self.path.display());
+        Ok(())
+    }
+
+    /// The last saved checkpoint, if any.
+    pub fn load<T: DeserializeOwned>(&self) -> Result<Option<T>> {
+        if !self.exists() {
+            return Ok(None);
+        }
+        let buf = std::fs::read_to_string(&self.path)?;
+        let checkpoint: Checkpoint<T> = serde_json::from_str(&buf)
+            .with_context(|| format!("Failed to read the checkpoint {}", self.path.display()))?;
+        Ok(Some(checkpoint.state))
+    }
+
+    /// Time at which the last checkpoint was saved.
+    pub fn saved_at(&self) -> Option<String> {
+        let buf = std::fs::read_to_string(&self.path).ok()?;
+        let checkpoint: Checkpoint<serde::de::IgnoredAny> = serde_json::from_str(&buf).ok()?;
+        Some(checkpoint.saved)
+    }
+
+    /// Delete the checkpoint.
+    pub fn clear(&self) -> Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Progress {
+        done: Vec<u32>,
+    }
+
+    #[test]
+    fn test_checkpoint_store() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let store = CheckpointStore::new(tmp.path());
+        assert_eq!(store.load::<Progress>()?, None);
+        assert_eq!(store.saved_at(), None);
+
+        store.save(&Progress { done: vec![1] })?;
+        store.save(&Progress { done: vec![1, 2] })?;
+        assert!(store.exists());
+        assert!(store.saved_at().is_some());
+        assert_eq!(
+            CheckpointStore::new(tmp.path()).load::<Progress>()?,
+            Some(Progress { done: vec![1, 2] })
+        );
+        // No temporary file is left behind.
+        assert_eq!(std::fs::read_dir(tmp.path())?.count(), 1);
+
+        assert!(store.load::<String>().is_err());
+        store.clear()?;
+        store.clear()?;
+        assert_eq!(store.load::<Progress>()?, None);
+        Ok(())
+    }
+}
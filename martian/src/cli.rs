@@ -0,0 +1,701 @@
+//! Command line front end for martian adapter binaries
+//!
+//! Every adapter needs a `main()` which dispatches between running a stage
+//! (invoked by the martian runtime) and generating the mro for the stages it
+//! contains. [`MartianCli`] owns that `main()` given the registries returned by
+//! the [`martian_stages!`](crate::martian_stages) macro:
+//!
+//! ```no_run
+//! use martian::prelude::*;
+//! # let (stage_registry, mro_registry) = martian_stages![];
+//!
+//! MartianCli::new(stage_registry, mro_registry)
+//!     .mro_header("# Copyright (c) 2021 10X Genomics, Inc. All rights reserved.")
+//!     .log_level(LevelFilter::Info)
+//!     .run()
+//! ```
+//!
+//! which understands the following subcommands:
+//! ```text
+//! <adapter> martian <adapter>...
+//! <adapter> check <stage> <phase> <metadata-path>
+//! <adapter> mro [--file=<filename>] [--rewrite]
+//! <adapter> mro --check --file=<filename>
+//! <adapter> compat <previous-mro>
+//! <adapter> interface [--file=<filename>] [--rewrite]
+//! <adapter> schema <stage> [--outputs] [--file=<filename>] [--rewrite]
+//! <adapter> stages
+//! <adapter> --help
+//! <adapter> --version
+//! ```
+//! along with any custom subcommands registered with [`MartianCli::command`].
+use crate::build::BuildInfo;
+use crate::disk::DiskBudget;
+use crate::utils::current_executable;
+use crate::{
+    make_mro_string_with_pipelines, martian_check_mro, martian_compatibility_report, write_output,
+    AdapterInterface, Error, MartianAdapter, PipelineMro, RawMartianStage, StageMro,
+};
+use anyhow::{Context, Result};
+use docopt::Docopt;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// Handler for a custom subcommand. It receives the command line arguments
+/// which follow the subcommand name.
+pub type CommandHandler = Box<dyn Fn(Vec<String>) -> Result<()>>;
+
+struct CustomCommand {
+    name: String,
+    description: String,
+    handler: CommandHandler,
+}
+
+const STANDARD_COMMANDS: &[&str] = &[
+    "martian",
+    "check",
+    "mro",
+    "compat",
+    "interface",
+    "schema",
+    "stages",
+];
+
+#[derive(Debug, Deserialize)]
+struct StandardArgs {
+    cmd_check: bool,
+    cmd_mro: bool,
+    cmd_compat: bool,
+    cmd_interface: bool,
+    cmd_schema: bool,
+    cmd_stages: bool,
+    flag_file: Option<String>,
+    flag_rewrite: bool,
+    flag_check: bool,
+    flag_outputs: bool,
+    arg_stage: Option<String>,
+    arg_phase: Option<String>,
+    arg_metadata_path: Option<String>,
+    arg_previous_mro: Option<String>,
+}
+
+/// Command line interface for a martian adapter binary.
+///
+/// Wraps a [`MartianAdapter`] together with the mro registry of the stages
+/// it contains, and dispatches the command line arguments to the appropriate
+/// entry point.
+pub struct MartianCli<S> {
+    adapter: MartianAdapter<S>,
+    mro_registry: Vec<StageMro>,
+    pipelines: Vec<PipelineMro>,
+    mro_header: String,
+    about: Option<String>,
+    version: Option<String>,
+    commands: Vec<CustomCommand>,
+}
+
+impl MartianCli<std::collections::hash_map::RandomState> {
+    /// Build a new command line interface from the registries returned
+    /// by the `martian_stages!` macro.
+    pub fn new(
+        stage_map: HashMap<String, Box<dyn RawMartianStage>>,
+        mro_registry: Vec<StageMro>,
+    ) -> Self {
+        Self::from_adapter(MartianAdapter::new(stage_map), mro_registry)
+    }
+}
+
+impl<S: std::hash::BuildHasher> MartianCli<S> {
+    /// Build a new command line interface from an already configured adapter.
+    pub fn from_adapter(adapter: MartianAdapter<S>, mro_registry: Vec<StageMro>) -> Self {
+        MartianCli {
+            adapter,
+            mro_registry,
+            pipelines: Vec::new(),
+            mro_header: String::new(),
+            about: None,
+            version: None,
+            commands: Vec::new(),
+        }
+    }
+
+    /// Set the minimum severity level of log messages that are emitted to the Martian
+    /// _log file. See [`MartianAdapter::log_level`].
+    pub fn log_level(self, log_level: LevelFilter) -> Self {
+        MartianCli {
+            adapter: self.adapter.log_level(log_level),
+            ..self
+        }
+    }
+
+    /// Set the predicate determining whether to emit an error as an ASSERT.
+    /// See [`MartianAdapter::assert_if`].
+    pub fn assert_if<F: 'static + Fn(&Error) -> bool>(self, predicate: F) -> Self {
+        MartianCli {
+            adapter: self.adapter.assert_if(predicate),
+            ..self
+        }
+    }
+
+    /// Set how long a stage is given to exit on its own after a termination
+    /// signal. See [`MartianAdapter::signal_grace_period`].
+    pub fn signal_grace_period(self, grace_period: Duration) -> Self {
+        MartianCli {
+            adapter: self.adapter.signal_grace_period(grace_period),
+            ..self
+        }
+    }
+
+    /// Record how the adapter was built in the _jobinfo.
+    /// See [`MartianAdapter::build_info`].
+    pub fn build_info(self, build_info: BuildInfo) -> Self {
+        MartianCli {
+            adapter: self.adapter.build_info(build_info),
+            ..self
+        }
+    }
+
+    /// Raise an alarm when a stage uses more disk space than its budget.
+    /// See [`MartianAdapter::disk_budget`].
+    pub fn disk_budget(self, disk_budget: DiskBudget) -> Self {
+        MartianCli {
+            adapter: self.adapter.disk_budget(disk_budget),
+            ..self
+        }
+    }
+
+    /// Record the hashes of the file inputs and outputs of the stages.
+    /// See [`MartianAdapter::provenance`].
+    pub fn provenance(self, provenance: bool) -> Self {
+        MartianCli {
+            adapter: self.adapter.provenance(provenance),
+            ..self
+        }
+    }
+
+    /// Set the memory usage alarms and limit of the stages.
+    /// See [`MartianAdapter::memory_guard`].
+    #[cfg(feature = "tracking-allocator")]
+    pub fn memory_guard(self, memory_guard: crate::alloc::MemoryGuard) -> Self {
+        MartianCli {
+            adapter: self.adapter.memory_guard(memory_guard),
+            ..self
+        }
+    }
+
+    /// Log through a `tracing` subscriber. See [`MartianAdapter::tracing`].
+    #[cfg(feature = "tracing")]
+    pub fn tracing(self, format: crate::LogFormat) -> Self {
+        MartianCli {
+            adapter: self.adapter.tracing(format),
+            ..self
+        }
+    }
+
+    /// Header comment placed at the top of the generated mro. All the non-empty
+    /// lines need to start with `#`.
+    pub fn mro_header(self, header: impl Into<String>) -> Self {
+        MartianCli {
+            mro_header: header.into(),
+            ..self
+        }
+    }
+
+    /// Pipelines calling the stages of the adapter, which are declared after
+    /// the stages in the generated mro, as checked by
+    /// [`PipelineBuilder::build`](crate::PipelineBuilder::build).
+    pub fn pipelines(self, pipelines: Vec<PipelineMro>) -> Self {
+        MartianCli { pipelines, ..self }
+    }
+
+    /// One line description of the binary shown in the `--help` output.
+    pub fn about(self, about: impl Into<String>) -> Self {
+        MartianCli {
+            about: Some(about.into()),
+            ..self
+        }
+    }
+
+    /// Version reported by `--version`, typically `env!("CARGO_PKG_VERSION")`.
+    pub fn version(self, version: impl Into<String>) -> Self {
+        MartianCli {
+            version: Some(version.into()),
+            ..self
+        }
+    }
+
+    /// Register a custom subcommand. The `handler` is called with the
+    /// arguments which follow `name` on the command line.
+    ///
+    /// Panics if `name` clashes with a standard or previously registered subcommand.
+    pub fn command<F>(mut self, name: &str, description: &str, handler: F) -> Self
+    where
+        F: 'static + Fn(Vec<String>) -> Result<()>,
+    {
+        assert!(
+            !STANDARD_COMMANDS.contains(&name) && self.commands.iter().all(|c| c.name != name),
+            "Subcommand {name} is already defined"
+        );
+        assert!(
+            !name.is_empty() && !name.starts_with('-') && !name.contains(char::is_whitespace),
+            "Invalid subcommand name '{name}'"
+        );
+        self.commands.push(CustomCommand {
+            name: name.to_string(),
+            description: description.to_string(),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// The docopt usage string for the binary `name`.
+    pub fn usage(&self, name: &str) -> String {
+        let mut usage = String::new();
+        match self.about {
+            Some(ref about) => writeln!(&mut usage, "{about}").unwrap(),
+            None => writeln!(&mut usage, "Martian adapter for {name} executable").unwrap(),
+        }
+        writeln!(&mut usage, "\nUsage:").unwrap();
+        writeln!(&mut usage, "  {name} martian <adapter>...").unwrap();
+        writeln!(&mut usage, "  {name} check <stage> <phase> <metadata-path>").unwrap();
+        writeln!(&mut usage, "  {name} mro [--file=<filename>] [--rewrite]").unwrap();
+        writeln!(&mut usage, "  {name} mro --check --file=<filename>").unwrap();
+        writeln!(&mut usage, "  {name} compat <previous-mro>").unwrap();
+        writeln!(
+            &mut usage,
+            "  {name} interface [--file=<filename>] [--rewrite]"
+        )
+        .unwrap();
+        writeln!(
+            &mut usage,
+            "  {name} schema <stage> [--outputs] [--file=<filename>] [--rewrite]"
+        )
+        .unwrap();
+        writeln!(&mut usage, "  {name} stages").unwrap();
+        for cmd in &self.commands {
+            writeln!(&mut usage, "  {name} {} [<args>...]", cmd.name).unwrap();
+        }
+        writeln!(&mut usage, "  {name} --help").unwrap();
+        if self.version.is_some() {
+            writeln!(&mut usage, "  {name} --version").unwrap();
+        }
+
+        let width = self
+            .commands
+            .iter()
+            .map(|c| c.name.len())
+            .chain(STANDARD_COMMANDS.iter().map(|c| c.len()))
+            .max()
+            .unwrap_or_default()
+            + 2;
+        writeln!(&mut usage, "\nCommands:").unwrap();
+        for (cmd, description) in [
+            ("martian", "Run a stage. Invoked by the martian runtime."),
+            (
+                "check",
+                "Check the inputs of a stage in a metadata directory without running it.",
+            ),
+            (
+                "mro",
+                "Generate the mro for all the stages in this adapter.",
+            ),
+            (
+                "compat",
+                "Report the changes to the stages since a previous mro, and whether they are breaking.",
+            ),
+            (
+                "interface",
+                "Export the interface of all the stages in this adapter as JSON.",
+            ),
+            (
+                "schema",
+                "Generate the JSON Schema of the inputs (or outputs) of a stage.",
+            ),
+            ("stages", "List the stages in this adapter."),
+        ] {
+            writeln!(&mut usage, "  {cmd:<width$}{description}").unwrap();
+        }
+        for cmd in &self.commands {
+            writeln!(&mut usage, "  {:<width$}{}", cmd.name, cmd.description).unwrap();
+        }
+
+        writeln!(&mut usage, "\nOptions:").unwrap();
+        writeln!(&mut usage, "  -h --help           Show this screen.").unwrap();
+        if self.version.is_some() {
+            writeln!(&mut usage, "  --version           Show the version.").unwrap();
+        }
+        writeln!(
+            &mut usage,
+            "  --file=<filename>   Output filename for the mro, the interface or the schema."
+        )
+        .unwrap();
+        writeln!(
+            &mut usage,
+            "  --rewrite           Whether to rewrite the file if it exists."
+        )
+        .unwrap();
+        writeln!(
+            &mut usage,
+            "  --check             Check that the mro file matches the stages, ignoring formatting."
+        )
+        .unwrap();
+        writeln!(
+            &mut usage,
+            "  --outputs           Generate the schema of the outputs rather than the inputs."
+        )
+        .unwrap();
+        usage
+    }
+
+    /// Parse the command line arguments of this process and run the requested
+    /// subcommand, then exit the process with the appropriate return code.
+    pub fn run(self) -> ! {
+        let code = match self.run_args(std::env::args().collect()) {
+            Ok(code) => code,
+            Err(e) => {
+                eprintln!("Error: {e:#}");
+                1
+            }
+        };
+        std::process::exit(code)
+    }
+
+    /// Run the subcommand requested by `args`, where the first element is the
+    /// name of the executable as in `std::env::args()`. Returns the code with
+    /// which the process should exit.
+    pub fn run_args(self, args: Vec<String>) -> Result<i32> {
+        let name = args
+            .first()
+            .and_then(|arg0| Path::new(arg0).file_name())
+            .map_or_else(current_executable, |f| f.to_string_lossy().into_owned());
+
+        // The arguments to `martian` and custom subcommands are passed through
+        // verbatim, so they must not be interpreted by docopt.
+        match args.get(1).map(String::as_str) {
+            Some("martian") => {
+                return Ok(self.adapter.run(args[2..].to_vec()));
+            }
+            Some(cmd) => {
+                if let Some(custom) = self.commands.iter().find(|c| c.name == cmd) {
+                    (custom.handler)(args[2..].to_vec())?;
+                    return Ok(0);
+                }
+            }
+            None => {}
+        }
+
+        let parsed: StandardArgs = match Docopt::new(self.usage(&name))
+            .map(|d| d.argv(args).help(true).version(self.version.clone()))
+            .and_then(|d| d.deserialize())
+        {
+            Ok(parsed) => parsed,
+            // --help and --version
+            Err(e) if !e.fatal() => {
+                println!("{e}");
+                return Ok(0);
+            }
+            Err(e) => return Err(anyhow::anyhow!("{e}")),
+        };
+
+        if parsed.cmd_check {
+            let (stage, phase, metadata_path) = (
+                parsed.arg_stage.unwrap(),
+                parsed.arg_phase.unwrap(),
+                parsed.arg_metadata_path.unwrap(),
+            );
+            self.adapter.check(&stage, &phase, &metadata_path)?;
+            println!("The {phase} inputs of {stage} in {metadata_path} are valid");
+        } else if parsed.cmd_mro && parsed.flag_check {
+            let filename = parsed
+                .flag_file
+                .context("mro --check requires the --file to check")?;
+            let differences = martian_check_mro(&filename, &self.mro_registry)?;
+            for difference in &differences {
+                println!("{difference}");
+            }
+            if !differences.is_empty() {
+                println!(
+                    "{filename} is out of date, regenerate it with `{name} mro --file={filename} --rewrite`"
+                );
+                return Ok(1);
+            }
+            println!("{filename} matches the stages in this adapter");
+        } else if parsed.cmd_mro {
+            let mro = make_mro_string_with_pipelines(
+                &self.mro_header,
+                &self.mro_registry,
+                &self.pipelines,
+            );
+            write_output(parsed.flag_file, parsed.flag_rewrite, &mro)?;
+        } else if parsed.cmd_compat {
+            let previous_mro = parsed.arg_previous_mro.unwrap();
+            let report = martian_compatibility_report(&previous_mro, &self.mro_registry)?;
+            if report.changes.is_empty() {
+                println!("The stages have not changed since {previous_mro}");
+            }
+            print!("{report}");
+            if report.is_breaking() {
+                return Ok(1);
+            }
+        } else if parsed.cmd_interface {
+            let mut json = AdapterInterface::new(&self.mro_registry).to_json()?;
+            json.push('\n');
+            write_output(parsed.flag_file, parsed.flag_rewrite, &json)?;
+        } else if parsed.cmd_schema {
+            let stage = parsed.arg_stage.unwrap();
+            let stage_mro = self
+                .mro_registry
+                .iter()
+                .find(|s| s.stage_key() == stage || s.stage_name() == stage)
+                .with_context(|| format!("There is no stage {stage} in this adapter"))?;
+            let schema = if parsed.flag_outputs {
+                stage_mro.outputs_json_schema()
+            } else {
+                stage_mro.inputs_json_schema()
+            };
+            let mut json = serde_json::to_string_pretty(&schema)?;
+            json.push('\n');
+            write_output(parsed.flag_file, parsed.flag_rewrite, &json)?;
+        } else if parsed.cmd_stages {
+            let mut keys: Vec<_> = self.adapter.stage_map.keys().collect();
+            keys.sort();
+            for key in keys {
+                println!("{key}");
+            }
+        } else {
+            unreachable!("docopt accepted an unknown subcommand");
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_usage() {
+        let cli = MartianCli::new(HashMap::new(), vec![])
+            .version("1.2.3")
+            .command("bench", "Run the benchmarks.", |_| Ok(()));
+        let usage = cli.usage("my_adapter");
+        assert!(usage.contains("  my_adapter martian <adapter>...\n"));
+        assert!(usage.contains("  my_adapter bench [<args>...]\n"));
+        assert!(usage.contains("  bench      Run the benchmarks.\n"));
+        assert!(usage.contains("  my_adapter --version\n"));
+        assert!(Docopt::new(usage).is_ok());
+    }
+
+    #[test]
+    fn test_custom_command() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let seen_in_handler = seen.clone();
+        let cli = MartianCli::new(HashMap::new(), vec![]).command(
+            "bench",
+            "Run the benchmarks.",
+            move |args| {
+                seen_in_handler.borrow_mut().extend(args);
+                Ok(())
+            },
+        );
+        let code = cli
+            .run_args(args(&["adapter", "bench", "--iters", "3"]))
+            .unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(*seen.borrow(), args(&["--iters", "3"]));
+    }
+
+    #[test]
+    fn test_custom_command_error() {
+        let cli = MartianCli::new(HashMap::new(), vec![])
+            .command("fail", "Always fails.", |_| anyhow::bail!("failed"));
+        assert!(cli.run_args(args(&["adapter", "fail"])).is_err());
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        let cli = MartianCli::new(HashMap::new(), vec![]);
+        assert!(cli.run_args(args(&["adapter", "nope"])).is_err());
+        let cli = MartianCli::new(HashMap::new(), vec![]);
+        assert!(cli.run_args(args(&["adapter", "mro", "--bogus"])).is_err());
+    }
+
+    #[test]
+    fn test_mro_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("stages.mro");
+        let file_arg = format!("--file={}", file.display());
+        let cli = MartianCli::new(HashMap::new(), vec![]).mro_header("# header");
+        assert_eq!(
+            cli.run_args(args(&["adapter", "mro", &file_arg])).unwrap(),
+            0
+        );
+        assert!(std::fs::read_to_string(&file)
+            .unwrap()
+            .starts_with("# header\n"));
+
+        // Refuses to overwrite without --rewrite
+        let cli = MartianCli::new(HashMap::new(), vec![]);
+        assert!(cli.run_args(args(&["adapter", "mro", &file_arg])).is_err());
+        let cli = MartianCli::new(HashMap::new(), vec![]);
+        assert_eq!(
+            cli.run_args(args(&["adapter", "mro", &file_arg, "--rewrite"]))
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_mro_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("stages.mro");
+        let file_arg = format!("--file={}", file.display());
+        let cli = MartianCli::new(HashMap::new(), vec![]);
+        assert_eq!(
+            cli.run_args(args(&["adapter", "mro", &file_arg])).unwrap(),
+            0
+        );
+        let cli = MartianCli::new(HashMap::new(), vec![]);
+        assert_eq!(
+            cli.run_args(args(&["adapter", "mro", "--check", &file_arg]))
+                .unwrap(),
+            0
+        );
+
+        std::fs::write(
+            &file,
+            "stage OLD(\n    in int x,\n    src comp \"adapter martian old\",\n)\n",
+        )
+        .unwrap();
+        let cli = MartianCli::new(HashMap::new(), vec![]);
+        assert_eq!(
+            cli.run_args(args(&["adapter", "mro", "--check", &file_arg]))
+                .unwrap(),
+            1
+        );
+        // --check needs a file
+        let cli = MartianCli::new(HashMap::new(), vec![]);
+        assert!(cli.run_args(args(&["adapter", "mro", "--check"])).is_err());
+    }
+
+    #[test]
+    fn test_compat() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("previous.mro");
+        let previous = file.to_str().unwrap();
+        std::fs::write(&file, "# no stages\n").unwrap();
+        let cli = MartianCli::new(HashMap::new(), vec![]);
+        assert_eq!(
+            cli.run_args(args(&["adapter", "compat", previous]))
+                .unwrap(),
+            0
+        );
+
+        // Removing a stage is breaking.
+        std::fs::write(
+            &file,
+            "stage OLD(\n    in int x,\n    src comp \"adapter martian old\",\n)\n",
+        )
+        .unwrap();
+        let cli = MartianCli::new(HashMap::new(), vec![]);
+        assert_eq!(
+            cli.run_args(args(&["adapter", "compat", previous]))
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_interface() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("interface.json");
+        let file_arg = format!("--file={}", file.display());
+        let cli = MartianCli::new(HashMap::new(), vec![]);
+        assert_eq!(
+            cli.run_args(args(&["adapter", "interface", &file_arg]))
+                .unwrap(),
+            0
+        );
+        let interface: AdapterInterface =
+            serde_json::from_reader(std::fs::File::open(&file).unwrap()).unwrap();
+        assert_eq!(interface, AdapterInterface::default());
+    }
+
+    #[test]
+    fn test_schema() {
+        let stages = || {
+            crate::MroFile::parse(
+                "stage SUM_SQUARES(\n    in float[] values,\n    out float sum,\n    \
+                 src comp \"adapter martian sum_squares\",\n)\n",
+            )
+            .unwrap()
+            .stages
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("schema.json");
+        let file_arg = format!("--file={}", file.display());
+        let cli = MartianCli::new(HashMap::new(), stages());
+        assert_eq!(
+            cli.run_args(args(&["adapter", "schema", "sum_squares", &file_arg]))
+                .unwrap(),
+            0
+        );
+        let schema: serde_json::Value =
+            serde_json::from_reader(std::fs::File::open(&file).unwrap()).unwrap();
+        assert_eq!(schema["title"], "SUM_SQUARES inputs");
+        assert_eq!(schema["required"], serde_json::json!(["values"]));
+
+        let cli = MartianCli::new(HashMap::new(), stages());
+        let outputs = [
+            "adapter",
+            "schema",
+            "SUM_SQUARES",
+            "--outputs",
+            &file_arg,
+            "--rewrite",
+        ];
+        assert_eq!(cli.run_args(args(&outputs)).unwrap(), 0);
+        let schema: serde_json::Value =
+            serde_json::from_reader(std::fs::File::open(&file).unwrap()).unwrap();
+        assert_eq!(schema["title"], "SUM_SQUARES outputs");
+
+        let cli = MartianCli::new(HashMap::new(), stages());
+        assert!(cli.run_args(args(&["adapter", "schema", "nope"])).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "already defined")]
+    fn test_duplicate_command() {
+        let _ = MartianCli::new(HashMap::new(), vec![]).command("mro", "", |_| Ok(()));
+    }
+
+    #[test]
+    fn test_adapter_options() {
+        let cli = MartianCli::new(HashMap::new(), vec![])
+            .signal_grace_period(Duration::from_secs(3))
+            .provenance(true);
+        #[cfg(feature = "tracking-allocator")]
+        let cli = cli.memory_guard(crate::alloc::MemoryGuard::disabled());
+        #[cfg(feature = "tracing")]
+        let cli = cli.tracing(crate::LogFormat::Json);
+        assert_eq!(cli.adapter.grace_period, Duration::from_secs(3));
+        assert!(cli.adapter.provenance);
+        #[cfg(feature = "tracking-allocator")]
+        assert_eq!(
+            cli.adapter.memory_guard,
+            crate::alloc::MemoryGuard::disabled()
+        );
+        #[cfg(feature = "tracing")]
+        assert!(cli.adapter.log_format.is_some());
+    }
+}
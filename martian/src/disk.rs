@@ -0,0 +1,217 @@
+//! Disk space used by the outputs of a stage.
+//!
+//! When a split, chunk or join completes, the adapter walks its files
+//! directory and writes a [`DiskUsage`] manifest of the size of every file to
+//! `_disk_usage`. The total and the largest files are also logged to `_log`.
+//!
+//! A [`DiskBudget`] passed to
+//! [`MartianAdapter::disk_budget()`](crate::MartianAdapter::disk_budget)
+//! raises an alarm when the files directory of a split, chunk or join
+//! exceeds the budget of the stage:
+//! ```rust
+//! use martian::disk::DiskBudget;
+//! // 50 GB for every stage, except SORT_READS which may use 500 GB.
+//! let budget = DiskBudget::new().default_gb(50.0).stage_gb("SORT_READS", 500.0);
+//! ```
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const GB: f64 = (1u64 << 30) as f64;
+
+/// Number of files listed in `_log`.
+const LOGGED_FILES: usize = 5;
+
+/// Size of the files in a directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskUsage {
+    pub total_bytes: u64,
+    /// Every file, largest first.
+    pub files: Vec<FileUsage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileUsage {
+    /// Relative to the measured directory.
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+impl DiskUsage {
+    /// Measure the files under `dir`. Symlinks are not followed, since they
+    /// point to files which are accounted for elsewhere.
+    pub fn measure(dir: impl AsRef<Path>) -> Result<DiskUsage> {
+        let dir = dir.as_ref();
+        let mut files = Vec::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(current) = pending.pop() {
+            for entry in std::fs::read_dir(&current)? {
+                let entry = entry?;
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    pending.push(entry.path());
+                } else if file_type.is_file() {
+                    let path = entry.path();
+                    files.push(FileUsage {
+                        bytes: entry.metadata()?.len(),
+                        path: path.strip_prefix(dir).unwrap_or(&path).to_path_buf(),
+                    });
+                }
+            }
+        }
+        files.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+        Ok(DiskUsage {
+            total_bytes: files.iter().map(|f| f.bytes).sum(),
+            files,
+        })
+    }
+
+    /// The `n` largest files.
+    pub fn largest(&self, n: usize) -> &[FileUsage] {
+        &self.files[..n.min(self.files.len())]
+    }
+
+    /// Log the total and the largest files.
+    pub(crate) fn log(&self) {
+        log::info!(
+            "the stage wrote {} in {} files",
+            format_bytes(self.total_bytes),
+            self.files.len()
+        );
+        for file in self.largest(LOGGED_FILES) {
+            log::info!(
+                "  {:>10}  {}",
+                format_bytes(file.bytes),
+                file.path.display()
+            );
+        }
+    }
+}
+
+/// Disk space allowed for the files directory of each split, chunk and join
+/// of a stage.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiskBudget {
+    default_bytes: Option<u64>,
+    stage_bytes: HashMap<String, u64>,
+}
+
+impl DiskBudget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Budget of the stages without their own budget.
+    pub fn default_gb(self, gb: f64) -> Self {
+        DiskBudget {
+            default_bytes: Some(gb_to_bytes(gb)),
+            ..self
+        }
+    }
+
+    /// Budget of the stage named `stage_name` in the mro, e.g. `SORT_READS`.
+    /// The key of the stage in the registry, `sort_reads`, works as well.
+    pub fn stage_gb(mut self, stage_name: &str, gb: f64) -> Self {
+        self.stage_bytes
+            .insert(stage_name.to_lowercase(), gb_to_bytes(gb));
+        self
+    }
+
+    /// Budget in bytes of the stage with the given name or key, if any.
+    pub fn for_stage(&self, stage_name: &str) -> Option<u64> {
+        self.stage_bytes
+            .get(&stage_name.to_lowercase())
+            .copied()
+            .or(self.default_bytes)
+    }
+}
+
+fn gb_to_bytes(gb: f64) -> u64 {
+    (gb * GB) as u64
+}
+
+/// `bytes` in the largest unit in which it is at least 1, e.g. `1.5 GB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Message of the alarm raised when `usage` exceeds `budget`.
+pub(crate) fn over_budget_message(usage: &DiskUsage, budget: u64) -> Option<String> {
+    if usage.total_bytes <= budget {
+        return None;
+    }
+    let largest = usage
+        .largest(1)
+        .first()
+        .map(|f| format!(", the largest file being {}", f.path.display()))
+        .unwrap_or_default();
+    Some(format!(
+        "the stage wrote {}, more than its disk budget of {}{largest}",
+        format_bytes(usage.total_bytes),
+        format_bytes(budget),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_usage() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::create_dir(tmp.path().join("nested"))?;
+        std::fs::write(tmp.path().join("small.txt"), [0; 10])?;
+        std::fs::write(tmp.path().join("nested/large.bin"), [0; 1000])?;
+        std::fs::write(tmp.path().join("empty"), [])?;
+        std::os::unix::fs::symlink(
+            tmp.path().join("nested/large.bin"),
+            tmp.path().join("link.bin"),
+        )?;
+
+        let usage = DiskUsage::measure(tmp.path())?;
+        assert_eq!(usage.total_bytes, 1010);
+        let paths: Vec<_> = usage.files.iter().map(|f| f.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            [
+                Path::new("nested/large.bin"),
+                Path::new("small.txt"),
+                Path::new("empty")
+            ]
+        );
+        assert_eq!(usage.largest(1)[0].bytes, 1000);
+        assert_eq!(usage.largest(10).len(), 3);
+
+        assert_eq!(over_budget_message(&usage, 1010), None);
+        assert_eq!(
+            over_budget_message(&usage, 1000).unwrap(),
+            "the stage wrote 1010 B, more than its disk budget of 1000 B, \
+             the largest file being nested/large.bin"
+        );
+        assert_eq!(format_bytes(3 << 29), "1.5 GB");
+        assert_eq!(format_bytes(1 << 60), "1048576.0 TB");
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_budget() {
+        let budget = DiskBudget::new().stage_gb("SORT_READS", 2.0);
+        assert_eq!(budget.for_stage("SORT_READS"), Some(2 << 30));
+        assert_eq!(budget.for_stage("sort_reads"), Some(2 << 30));
+        assert_eq!(budget.for_stage("SUM_SQUARES"), None);
+        let budget = budget.default_gb(0.5);
+        assert_eq!(budget.for_stage("SUM_SQUARES"), Some(1 << 29));
+    }
+}
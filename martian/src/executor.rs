@@ -0,0 +1,660 @@
+//! Run a stage through an adapter binary, locally, using the same file
+//! protocol as `mrp`.
+//!
+//! `MartianStage::test_run()` calls the stage functions in-process, which
+//! means it never exercises the `_args`/`_outs` serialization, the `_jobinfo`
+//! handling, the journal files or the log/error file descriptors. The
+//! [`LocalExecutor`] instead spawns the adapter executable once for the split,
+//! once per chunk and once for the join, exactly as the martian runtime would,
+//! and collects the metadata files written by each invocation.
+//!
+//! ```no_run
+//! use martian::executor::LocalExecutor;
+//!
+//! let run = LocalExecutor::new("target/debug/sum_sq", "sum_squares")
+//!     .run("/tmp/sum_squares", "args.json")
+//!     .unwrap();
+//! assert!(run.is_success(), "{:?}", run.failures().collect::<Vec<_>>());
+//! println!("{:?}", run.outs);
+//! ```
+//!
+//! The layout of the run directory mirrors that of a pipestance fork:
+//! ```text
+//! <run_directory>/
+//! ├── journal/
+//! ├── split/      _args, _jobinfo, _stage_defs, _log, ...
+//! │   └── files/
+//! ├── chnk0/      _args, _jobinfo, _outs, _log, ...
+//! │   └── files/
+//! └── join/       _args, _chunk_defs, _chunk_outs, _outs, ...
+//!     └── files/
+//! ```
+
+use crate::alarm::AlarmSummary;
+use crate::disk::DiskUsage;
+use crate::metadata::{JsonDict, StagePhase, Version};
+use crate::provenance::Provenance;
+use crate::stage::{fill_defaults, StageKind};
+use crate::{Error, Resource};
+use anyhow::{ensure, Context};
+use serde_json::{json, Value};
+use std::fs::{create_dir, create_dir_all, File};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+
+const ASSERT_PREFIX: &str = "ASSERT:";
+
+/// Spawns an adapter binary for each phase of a stage.
+#[derive(Debug, Clone)]
+pub struct LocalExecutor {
+    adapter: PathBuf,
+    adapter_args: Vec<String>,
+    stage_key: String,
+    stage_kind: StageKind,
+    split_resource: Resource,
+    version: Version,
+}
+
+impl LocalExecutor {
+    /// Create an executor for the stage registered as `stage_key` in the
+    /// adapter binary `adapter`.
+    ///
+    /// By default the binary is invoked as `<adapter> martian <stage_key> ...`,
+    /// which is what `martian_stages!` based adapters expect, and the stage is
+    /// assumed to have a split.
+    pub fn new(adapter: impl AsRef<Path>, stage_key: impl ToString) -> Self {
+        LocalExecutor {
+            adapter: adapter.as_ref().to_path_buf(),
+            adapter_args: vec!["martian".to_string()],
+            stage_key: stage_key.to_string(),
+            stage_kind: StageKind::WithSplit,
+            split_resource: Resource::new(),
+            version: Version {
+                martian: "local".into(),
+                pipelines: "local".into(),
+            },
+        }
+    }
+
+    /// Set the arguments passed to the adapter before the stage key.
+    /// Defaults to `["martian"]`.
+    pub fn adapter_args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        self.adapter_args = args.into_iter().map(|a| a.to_string()).collect();
+        self
+    }
+
+    /// Set whether the stage has a split. Use `StageKind::MainOnly` for
+    /// stages implementing `MartianMain`.
+    pub fn stage_kind(mut self, stage_kind: StageKind) -> Self {
+        self.stage_kind = stage_kind;
+        self
+    }
+
+    /// Set the resources reserved for the split (or for the main of a
+    /// `MainOnly` stage). Unset fields use the martian defaults.
+    pub fn split_resource(mut self, resource: Resource) -> Self {
+        self.split_resource = resource;
+        self
+    }
+
+    /// Set the versions reported to the stage through `_jobinfo`.
+    pub fn version(mut self, martian: impl ToString, pipelines: impl ToString) -> Self {
+        self.version = Version {
+            martian: martian.to_string(),
+            pipelines: pipelines.to_string(),
+        };
+        self
+    }
+
+    /// Run the stage with the arguments in the JSON file `args_file`.
+    /// The directory `run_directory` is created and must not already exist.
+    ///
+    /// An `Err` is only returned if the executor itself failed. Failures of
+    /// the stage are reported in the returned `LocalRun`, which holds every
+    /// invocation up to and including the first one that failed.
+    pub fn run(
+        &self,
+        run_directory: impl AsRef<Path>,
+        args_file: impl AsRef<Path>,
+    ) -> Result<LocalRun, Error> {
+        let args_file = args_file.as_ref();
+        let args: JsonDict = serde_json::from_reader(
+            File::open(args_file).with_context(|| args_file.display().to_string())?,
+        )
+        .with_context(|| format!("{} is not a JSON object", args_file.display()))?;
+        self.run_with_args(run_directory, args)
+    }
+
+    /// Same as `run()`, with the stage arguments given as a JSON object.
+    pub fn run_with_args(
+        &self,
+        run_directory: impl AsRef<Path>,
+        args: JsonDict,
+    ) -> Result<LocalRun, Error> {
+        let run_directory = run_directory.as_ref();
+        ensure!(
+            !run_directory.exists(),
+            "Run directory {} already exists",
+            run_directory.display()
+        );
+        create_dir_all(run_directory.join("journal"))?;
+
+        let mut run = LocalRun {
+            run_directory: run_directory.to_path_buf(),
+            invocations: Vec::new(),
+            outs: None,
+        };
+
+        if let StageKind::MainOnly = self.stage_kind {
+            let main = self.invoke(
+                run_directory,
+                StagePhase::Main,
+                "chnk0",
+                &args,
+                self.split_resource,
+            )?;
+            run.outs = main.outs.clone();
+            run.invocations.push(main);
+            return Ok(run);
+        }
+
+        let split = self.invoke(
+            run_directory,
+            StagePhase::Split,
+            "split",
+            &args,
+            self.split_resource,
+        )?;
+        let stage_defs = split.outs.clone();
+        run.invocations.push(split);
+        let Some(stage_defs) = stage_defs else {
+            return Ok(run);
+        };
+
+        let chunk_defs: Vec<JsonDict> = match stage_defs.get("chunks") {
+            Some(chunks) => serde_json::from_value(chunks.clone())
+                .context("_stage_defs contains invalid chunk definitions")?,
+            None => Vec::new(),
+        };
+        let join_resource = resource_from_def(stage_defs.get("join"))?;
+
+        let mut chunk_outs = Vec::with_capacity(chunk_defs.len());
+        for (chunk_idx, chunk_def) in chunk_defs.iter().enumerate() {
+            // Chunk arguments are the stage arguments overlaid with the
+            // chunk inputs, without the resource reservations.
+            let mut chunk_args = args.clone();
+            for (k, v) in chunk_def {
+                if !k.starts_with("__") {
+                    chunk_args.insert(k.clone(), v.clone());
+                }
+            }
+            let resource = resource_from_def(Some(&Value::Object(chunk_def.clone())))?;
+            let chunk = self.invoke(
+                run_directory,
+                StagePhase::Main,
+                &format!("chnk{chunk_idx}"),
+                &chunk_args,
+                resource,
+            )?;
+            let outs = chunk.outs.clone();
+            run.invocations.push(chunk);
+            match outs {
+                Some(outs) => chunk_outs.push(Value::Object(outs)),
+                None => return Ok(run),
+            }
+        }
+
+        let join_dir = run_directory.join("join");
+        create_dir(&join_dir)?;
+        write_json(&join_dir.join("_chunk_defs"), &chunk_defs)?;
+        write_json(&join_dir.join("_chunk_outs"), &chunk_outs)?;
+        let join = self.invoke(
+            run_directory,
+            StagePhase::Join,
+            "join",
+            &args,
+            join_resource,
+        )?;
+        run.outs = join.outs.clone();
+        run.invocations.push(join);
+        Ok(run)
+    }
+
+    fn invoke(
+        &self,
+        run_directory: &Path,
+        phase: StagePhase,
+        subdir: &str,
+        args: &JsonDict,
+        resource: Resource,
+    ) -> Result<Invocation, Error> {
+        let metadata_path = run_directory.join(subdir);
+        let files_path = metadata_path.join("files");
+        create_dir_all(&files_path)?;
+        let run_file = run_directory
+            .join("journal")
+            .join(format!("{}.{subdir}", self.stage_key));
+
+        let resource = fill_defaults(resource);
+        write_json(&metadata_path.join("_args"), args)?;
+        write_json(
+            &metadata_path.join("_jobinfo"),
+            &json!({
+                "name": format!("{}.{subdir}", self.stage_key),
+                "type": "local",
+                "threads": resource.get_threads(),
+                "memGB": resource.get_mem_gb(),
+                "vmemGB": resource.get_vmem_gb(),
+                "profile_mode": "disable",
+                "version": self.version,
+            }),
+        )?;
+
+        let log_file = File::create(metadata_path.join("_log"))?;
+        let err_file = File::create(metadata_path.join("_errors"))?;
+        let mut command = Command::new(&self.adapter);
+        command
+            .args(&self.adapter_args)
+            .arg(&self.stage_key)
+            .arg(phase.as_str())
+            .arg(&metadata_path)
+            .arg(&files_path)
+            .arg(&run_file)
+            .stdin(Stdio::null())
+            .stdout(File::create(metadata_path.join("_stdout"))?)
+            .stderr(File::create(metadata_path.join("_stderr"))?);
+        let (log_fd, err_fd) = (log_file.as_raw_fd(), err_file.as_raw_fd());
+        // Safety: the closure only makes async-signal-safe libc calls.
+        unsafe {
+            command.pre_exec(move || install_martian_fds(log_fd, err_fd));
+        }
+        let status = command
+            .status()
+            .with_context(|| format!("Failed to execute {}", self.adapter.display()))?;
+        drop((log_file, err_file));
+
+        Invocation::collect(phase, subdir, metadata_path, files_path, status)
+    }
+}
+
+/// Make the log and error files available to the child process as file
+/// descriptors 3 and 4, which is where the adapter expects them.
+fn install_martian_fds(log_fd: RawFd, err_fd: RawFd) -> std::io::Result<()> {
+    fn check(ret: libc::c_int) -> std::io::Result<libc::c_int> {
+        if ret < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+    // Move both descriptors out of the way first, in case either of them
+    // already is 3 or 4.  F_DUPFD does not copy the close-on-exec flag.
+    unsafe {
+        let log_fd = check(libc::fcntl(log_fd, libc::F_DUPFD, 10))?;
+        let err_fd = check(libc::fcntl(err_fd, libc::F_DUPFD, 10))?;
+        check(libc::dup2(log_fd, 3))?;
+        check(libc::dup2(err_fd, 4))?;
+        libc::close(log_fd);
+        libc::close(err_fd);
+    }
+    Ok(())
+}
+
+fn write_json(path: &Path, value: &impl serde::Serialize) -> Result<(), Error> {
+    serde_json::to_writer_pretty(File::create(path)?, value)
+        .with_context(|| path.display().to_string())
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(s) if s.is_empty() => Ok(None),
+        Ok(s) => Ok(Some(s)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::new(e).context(path.display().to_string())),
+    }
+}
+
+fn resource_from_def(def: Option<&Value>) -> Result<Resource, Error> {
+    Ok(match def {
+        Some(def) => serde_json::from_value(def.clone()).context("Invalid resource request")?,
+        None => Resource::new(),
+    })
+}
+
+/// The result of one invocation of the adapter binary.
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    /// The phase passed to the adapter.
+    pub phase: StagePhase,
+    /// Name of the directory of this invocation, e.g. `chnk3`.
+    pub name: String,
+    pub metadata_path: PathBuf,
+    pub files_path: PathBuf,
+    pub status: ExitStatus,
+    /// Content of `_outs`, or `_stage_defs` for the split.
+    pub outs: Option<JsonDict>,
+    /// Content of `_errors`, if the invocation failed.
+    pub errors: Option<String>,
+    /// Content of `_assert`, if the invocation failed with an assertion.
+    pub assert: Option<String>,
+    /// Content of `_alarm`, if the stage raised any alarms.
+    pub alarm: Option<String>,
+    /// Content of `_alarm_summary`, if the stage raised any alarms.
+    pub alarm_summary: Option<AlarmSummary>,
+    /// Content of `_disk_usage`, if the invocation succeeded.
+    pub disk_usage: Option<DiskUsage>,
+    /// Content of `_provenance`, if the adapter records the provenance.
+    pub provenance: Option<Provenance>,
+    /// Content of `_log`.
+    pub log: String,
+}
+
+impl Invocation {
+    fn collect(
+        phase: StagePhase,
+        name: &str,
+        metadata_path: PathBuf,
+        files_path: PathBuf,
+        status: ExitStatus,
+    ) -> Result<Self, Error> {
+        // The runtime splits assertions out of the error channel into _assert.
+        let mut errors = read_optional(&metadata_path.join("_errors"))?;
+        let mut assert = None;
+        if let Some(msg) = errors
+            .as_deref()
+            .and_then(|e| e.strip_prefix(ASSERT_PREFIX))
+        {
+            std::fs::write(metadata_path.join("_assert"), msg)?;
+            std::fs::remove_file(metadata_path.join("_errors"))?;
+            assert = Some(msg.to_string());
+            errors = None;
+        }
+
+        let outs_name = if phase == StagePhase::Split {
+            "_stage_defs"
+        } else {
+            "_outs"
+        };
+        let outs = if status.success() && errors.is_none() && assert.is_none() {
+            match read_optional(&metadata_path.join(outs_name))? {
+                Some(outs) => Some(
+                    serde_json::from_str(&outs)
+                        .with_context(|| format!("{name}/{outs_name} is not a JSON object"))?,
+                ),
+                None => {
+                    errors = Some(format!("{outs_name} was not written"));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        if outs.is_none() && errors.is_none() && assert.is_none() {
+            errors = Some(format!("adapter exited with {status}"));
+        }
+
+        Ok(Invocation {
+            phase,
+            name: name.to_string(),
+            alarm: read_optional(&metadata_path.join("_alarm"))?,
+            alarm_summary: read_optional(&metadata_path.join("_alarm_summary"))?
+                .map(|summary| serde_json::from_str(&summary))
+                .transpose()
+                .with_context(|| format!("{name}/_alarm_summary is not an alarm summary"))?,
+            disk_usage: read_optional(&metadata_path.join("_disk_usage"))?
+                .map(|usage| serde_json::from_str(&usage))
+                .transpose()
+                .with_context(|| format!("{name}/_disk_usage is not a disk usage manifest"))?,
+            provenance: read_optional(&metadata_path.join("_provenance"))?
+                .map(|provenance| serde_json::from_str(&provenance))
+                .transpose()
+                .with_context(|| format!("{name}/_provenance is not a provenance manifest"))?,
+            log: read_optional(&metadata_path.join("_log"))?.unwrap_or_default(),
+            metadata_path,
+            files_path,
+            status,
+            outs,
+            errors,
+            assert,
+        })
+    }
+
+    /// True if the invocation exited cleanly and produced its outputs.
+    pub fn is_success(&self) -> bool {
+        self.outs.is_some()
+    }
+}
+
+/// The collected results of running a stage with the `LocalExecutor`.
+#[derive(Debug, Clone)]
+pub struct LocalRun {
+    pub run_directory: PathBuf,
+    /// All the invocations in the order they were run.
+    pub invocations: Vec<Invocation>,
+    /// The stage outputs, if every invocation succeeded.
+    pub outs: Option<JsonDict>,
+}
+
+impl LocalRun {
+    pub fn is_success(&self) -> bool {
+        self.outs.is_some()
+    }
+
+    /// The invocations which did not succeed.
+    pub fn failures(&self) -> impl Iterator<Item = &Invocation> {
+        self.invocations.iter().filter(|inv| !inv.is_success())
+    }
+
+    /// All the alarms raised by the stage, across invocations.
+    pub fn alarms(&self) -> impl Iterator<Item = &str> {
+        self.invocations
+            .iter()
+            .filter_map(|inv| inv.alarm.as_deref())
+    }
+}
+
+/// Runs the test binary itself as the adapter of a `MainOnly` stage, for the
+/// tests which need a real adapter process. The child process re-runs the
+/// test which spawned it, in which [`run_if_child`](self_adapter::run_if_child)
+/// runs the stage and exits before the test does anything else.
+#[cfg(test)]
+pub(crate) mod self_adapter {
+    use super::LocalExecutor;
+    use crate::{MartianAdapter, RawMartianStage, StageKind};
+    use std::collections::hash_map::RandomState;
+    use std::collections::HashMap;
+
+    /// Executor re-running the test `test`, given by its full path, e.g.
+    /// `executor::tests::test_local_executor_signal`.
+    pub(crate) fn executor(test: &str, stage_key: &str) -> LocalExecutor {
+        LocalExecutor::new(std::env::current_exe().unwrap(), stage_key)
+            .adapter_args(["--exact", test, "--nocapture"])
+            .stage_kind(StageKind::MainOnly)
+    }
+
+    /// In the child process spawned by [`executor`], run `stage` through the
+    /// adapter returned by `configure` and exit. Does nothing otherwise.
+    pub(crate) fn run_if_child(
+        stage_key: &str,
+        stage: impl RawMartianStage + 'static,
+        configure: impl FnOnce(MartianAdapter<RandomState>) -> MartianAdapter<RandomState>,
+    ) {
+        // The stage key is followed by the phase and the three paths.
+        let args: Vec<String> = std::env::args()
+            .skip_while(|arg| arg != stage_key)
+            .collect();
+        if args.len() != 5 {
+            return;
+        }
+        let mut stage_map: HashMap<String, Box<dyn RawMartianStage>> = HashMap::new();
+        stage_map.insert(stage_key.to_string(), Box::new(stage));
+        std::process::exit(configure(MartianAdapter::new(stage_map)).run(args));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cancelled, Metadata, RawMartianStage};
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, Instant};
+
+    // A minimal stand-in for an adapter binary, which squares the `value`
+    // of every chunk and asserts if it finds a negative value.
+    const FAKE_ADAPTER: &str = r#"#!/bin/sh
+[ "$1" = martian ] || exit 2
+phase=$3; md=$4; files=$5
+echo "running $2 $phase" >&3
+case "$phase" in
+split)
+    echo '{"chunks": [{"value": 2, "__mem_gb": 3}, {"value": 3}], "join": {"__threads": 2}}' > "$md/_stage_defs"
+    ;;
+main)
+    value=$(sed -n 's/.*"value": *\(-*[0-9]*\).*/\1/p' "$md/_args" | head -n 1)
+    if [ "$value" -lt 0 ]; then
+        printf 'ASSERT:negative value %s' "$value" >&4
+        exit 1
+    fi
+    if grep -q '"memGB": 3' "$md/_jobinfo"; then
+        echo "big chunk" > "$md/_alarm"
+        echo '{"alarms": [{"severity": "info", "key": "big", "message": "big chunk", "count": 1,
+            "first_raised": "2024-05-02 10:31:07", "last_raised": "2024-05-02 10:31:07"}]}' \
+            > "$md/_alarm_summary"
+    fi
+    echo "{\"square\": $((value * value))}" > "$md/_outs"
+    ;;
+join)
+    [ -f "$md/_chunk_defs" ] && [ -f "$md/_chunk_outs" ] || exit 3
+    grep -q '"threads": 2' "$md/_jobinfo" || exit 4
+    echo '{"sum": 13}' > "$md/_outs"
+    ;;
+esac
+touch "$6.$phase"
+"#;
+
+    fn fake_adapter(dir: &Path) -> PathBuf {
+        let path = dir.join("adapter.sh");
+        std::fs::write(&path, FAKE_ADAPTER).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn args(value: i64) -> JsonDict {
+        serde_json::from_value(json!({ "value": value })).unwrap()
+    }
+
+    #[test]
+    fn test_local_executor_split() -> Result<(), Error> {
+        let tmp = tempfile::tempdir()?;
+        let executor = LocalExecutor::new(fake_adapter(tmp.path()), "sum_squares");
+        let run = executor.run_with_args(tmp.path().join("run"), args(1))?;
+
+        assert!(run.is_success(), "{:?}", run.failures().collect::<Vec<_>>());
+        assert_eq!(run.outs.as_ref().unwrap()["sum"], 13);
+        let names: Vec<_> = run.invocations.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["split", "chnk0", "chnk1", "join"]);
+        assert_eq!(run.invocations[2].outs.as_ref().unwrap()["square"], 9);
+        assert_eq!(run.invocations[1].log, "running sum_squares main\n");
+        assert_eq!(run.alarms().collect::<Vec<_>>(), ["big chunk\n"]);
+        let summary = run.invocations[1].alarm_summary.as_ref().unwrap();
+        assert_eq!(summary.alarms[0].alarm.key, "big");
+        assert!(run.invocations[2].alarm_summary.is_none());
+        assert!(tmp
+            .path()
+            .join("run/journal/sum_squares.join.join")
+            .exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_executor_assert() -> Result<(), Error> {
+        let tmp = tempfile::tempdir()?;
+        let executor = LocalExecutor::new(fake_adapter(tmp.path()), "sum_squares")
+            .stage_kind(StageKind::MainOnly);
+        let run = executor.run_with_args(tmp.path().join("run"), args(-4))?;
+
+        assert!(!run.is_success());
+        let failure = run.failures().next().unwrap();
+        assert_eq!(failure.name, "chnk0");
+        assert_eq!(failure.assert.as_deref(), Some("negative value -4"));
+        assert_eq!(failure.errors, None);
+        assert!(failure.metadata_path.join("_assert").exists());
+        Ok(())
+    }
+
+    const SIGNAL_STAGE: &str = "wait_for_signal";
+
+    // Runs until it is cancelled, after telling the test its pid.
+    struct WaitForSignal;
+
+    impl RawMartianStage for WaitForSignal {
+        fn split(&self, _: &mut Metadata) -> Result<(), Error> {
+            unimplemented!()
+        }
+
+        fn main(&self, md: &mut Metadata) -> Result<(), Error> {
+            let pid_file = md.make_path("pid");
+            std::fs::write(
+                pid_file.with_extension("tmp"),
+                std::process::id().to_string(),
+            )?;
+            std::fs::rename(pid_file.with_extension("tmp"), pid_file)?;
+            let deadline = Instant::now() + Duration::from_secs(30);
+            while !md.cancellation_token().is_cancelled() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(Cancelled {
+                signal: md.cancellation_token().signal(),
+            }
+            .into())
+        }
+
+        fn join(&self, _: &mut Metadata) -> Result<(), Error> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_local_executor_signal() -> Result<(), Error> {
+        self_adapter::run_if_child(SIGNAL_STAGE, WaitForSignal, |adapter| adapter);
+        let tmp = tempfile::tempdir()?;
+        let executor =
+            self_adapter::executor("executor::tests::test_local_executor_signal", SIGNAL_STAGE);
+        for (signal, name) in [(libc::SIGTERM, "SIGTERM"), (libc::SIGINT, "SIGINT")] {
+            let run_directory = tmp.path().join(name);
+            let pid_file = run_directory.join("chnk0/_pid");
+            let killer = std::thread::spawn(move || {
+                let deadline = Instant::now() + Duration::from_secs(30);
+                while Instant::now() < deadline {
+                    if let Ok(pid) = std::fs::read_to_string(&pid_file) {
+                        unsafe { libc::kill(pid.parse().unwrap(), signal) };
+                        return;
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                panic!("the adapter did not start");
+            });
+            let run = executor.run_with_args(&run_directory, args(1))?;
+            killer.join().unwrap();
+
+            let failure = run.failures().next().unwrap();
+            assert_eq!(failure.status.code(), Some(128 + signal));
+            assert_eq!(
+                failure.errors.as_deref(),
+                Some(format!("terminated by signal {name}").as_str())
+            );
+            assert!(
+                failure.log.contains("terminated by signal"),
+                "{}",
+                failure.log
+            );
+        }
+        Ok(())
+    }
+}
@@ -7,6 +7,8 @@
 pub use anyhow::Error;
 use anyhow::{ensure, Context, Result};
 use backtrace::Backtrace;
+use build::BuildInfo;
+use disk::DiskBudget;
 use log::{error, info};
 use std::collections::HashMap;
 use std::fmt::Write as FmtWrite;
@@ -14,6 +16,9 @@ use std::fs::File;
 use std::io::Write as IoWrite;
 use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
 use std::{io, panic};
 use time::format_description::modifier::{Day, Hour, Minute, Month, Second, Year};
 use time::format_description::FormatItem::Literal;
@@ -24,6 +29,34 @@ use utils::current_executable;
 mod metadata;
 pub use metadata::*;
 
+pub mod alarm;
+pub use alarm::{Alarm, AlarmSeverity};
+#[cfg(feature = "tracking-allocator")]
+pub mod alloc;
+pub mod build;
+mod cancel;
+pub mod checkpoint;
+pub mod disk;
+#[cfg(feature = "tracing")]
+mod logging;
+#[cfg(feature = "tracing")]
+pub use logging::LogFormat;
+mod outputs;
+mod profile;
+pub mod progress;
+pub mod provenance;
+pub use progress::ProgressReporter;
+#[cfg(feature = "tokio")]
+pub mod runtime;
+#[cfg(feature = "tokio")]
+pub use runtime::{MartianMainAsync, MartianStageAsync};
+mod usage;
+pub mod validate;
+pub use cancel::{CancellationToken, Cancelled};
+pub use checkpoint::CheckpointStore;
+pub use usage::ResourceUsage;
+pub use validate::{ValidationError, ValidationErrors};
+
 #[macro_use]
 mod macros;
 
@@ -34,6 +67,10 @@ pub use temporary_file::*;
 pub mod utils;
 pub use stage::*;
 
+pub mod cli;
+pub use cli::MartianCli;
+pub mod executor;
+
 pub mod mro;
 pub use log::LevelFilter;
 /// For convenience
@@ -41,14 +78,39 @@ pub use mro::*;
 pub mod prelude;
 
 pub fn initialize(args: Vec<String>) -> Result<Metadata> {
-    let mut md = Metadata::new(args);
-    md.update_jobinfo()?;
+    initialize_with_build_info(args, None)
+}
+
+fn initialize_with_build_info(
+    args: Vec<String>,
+    build_info: Option<BuildInfo>,
+) -> Result<Metadata> {
+    let mut md = Metadata::new(args).context("Invalid command line arguments")?;
+    md.set_build_info(build_info);
+    md.update_jobinfo().context("IO Error initializing stage")?;
 
     Ok(md)
 }
 
+// Set once the _errors file descriptor has been closed, so that a late
+// write (e.g. from the signal handler thread) cannot end up in whatever file
+// now has that descriptor.
+static ERRORS_CLOSED: Mutex<bool> = Mutex::new(false);
+// Same as ERRORS_CLOSED, for writers which must not block on the lock.
+static ERRORS_CLOSED_FLAG: AtomicBool = AtomicBool::new(false);
+
+/// Whether the _errors file descriptor has been closed, without locking.
+#[cfg(feature = "tracking-allocator")]
+pub(crate) fn errors_closed() -> bool {
+    ERRORS_CLOSED_FLAG.load(Ordering::Relaxed)
+}
+
 #[cold]
 fn write_errors(msg: &str, is_assert: bool) -> Result<()> {
+    let closed = ERRORS_CLOSED.lock().unwrap_or_else(PoisonError::into_inner);
+    if *closed {
+        return Ok(());
+    }
     let mut err_file: File = unsafe { File::from_raw_fd(4) };
 
     // We want to aggressively avoid allocations here if we can, since one
@@ -68,6 +130,18 @@ fn write_errors(msg: &str, is_assert: bool) -> Result<()> {
     Ok(())
 }
 
+/// Close the _errors file descriptor, indicating success to martian.
+fn close_errors() {
+    let mut closed = ERRORS_CLOSED.lock().unwrap_or_else(PoisonError::into_inner);
+    if !*closed {
+        unsafe {
+            File::from_raw_fd(4);
+        }
+        *closed = true;
+        ERRORS_CLOSED_FLAG.store(true, Ordering::Relaxed);
+    }
+}
+
 // e.g. 2006-01-02 15:04:05.  Note that this is only crate-public, not fully
 // public, because this is a bad date format that is used only to retain
 // backwards compatibility.  For use cases where that is not a concern, use
@@ -117,6 +191,14 @@ pub struct MartianAdapter<S> {
     stage_map: HashMap<String, Box<dyn RawMartianStage>, S>,
     log_level: LevelFilter,
     is_error_assert: Box<dyn (Fn(&Error) -> bool) + 'static>,
+    grace_period: Duration,
+    build_info: Option<BuildInfo>,
+    disk_budget: DiskBudget,
+    provenance: bool,
+    #[cfg(feature = "tracking-allocator")]
+    memory_guard: alloc::MemoryGuard,
+    #[cfg(feature = "tracing")]
+    log_format: Option<LogFormat>,
 }
 
 impl<S: std::hash::BuildHasher> MartianAdapter<S> {
@@ -128,6 +210,14 @@ impl<S: std::hash::BuildHasher> MartianAdapter<S> {
             stage_map,
             log_level: LevelFilter::Warn,
             is_error_assert: Box::new(|_| false),
+            grace_period: cancel::DEFAULT_GRACE_PERIOD,
+            build_info: None,
+            disk_budget: DiskBudget::default(),
+            provenance: false,
+            #[cfg(feature = "tracking-allocator")]
+            memory_guard: alloc::MemoryGuard::default(),
+            #[cfg(feature = "tracing")]
+            log_format: None,
         }
     }
 
@@ -148,6 +238,86 @@ impl<S: std::hash::BuildHasher> MartianAdapter<S> {
         }
     }
 
+    /// Set how long a stage is given to exit on its own after receiving a
+    /// termination signal (`SIGTERM`, `SIGINT` or `SIGXCPU`) before the process
+    /// exits. The stage can poll [`MartianRover::cancellation_token()`] to
+    /// notice the signal. Defaults to 10 seconds.
+    pub fn signal_grace_period(self, grace_period: Duration) -> MartianAdapter<S> {
+        MartianAdapter {
+            grace_period,
+            ..self
+        }
+    }
+
+    /// Record how the adapter was built in the `rust` section of the _jobinfo,
+    /// typically with `.build_info(martian::build_info!())`. See [`build`].
+    pub fn build_info(self, build_info: BuildInfo) -> MartianAdapter<S> {
+        MartianAdapter {
+            build_info: Some(build_info),
+            ..self
+        }
+    }
+
+    /// Raise an alarm when the files directory of a split, chunk or join is
+    /// larger than the disk budget of the stage when it completes. See
+    /// [`disk`].
+    pub fn disk_budget(self, disk_budget: DiskBudget) -> MartianAdapter<S> {
+        MartianAdapter {
+            disk_budget,
+            ..self
+        }
+    }
+
+    /// Write the sha256 hashes of the file inputs and outputs of every split,
+    /// chunk and join to `_provenance`, along with the pipeline version and
+    /// the build info of the adapter. See [`provenance`].
+    pub fn provenance(self, provenance: bool) -> MartianAdapter<S> {
+        MartianAdapter { provenance, ..self }
+    }
+
+    /// Set the thresholds at which alarms are raised about the memory usage of
+    /// the stage, and optionally the limit at which the stage is aborted. Only
+    /// effective when [`alloc::TrackingAllocator`] is the global allocator.
+    #[cfg(feature = "tracking-allocator")]
+    pub fn memory_guard(self, memory_guard: alloc::MemoryGuard) -> MartianAdapter<S> {
+        MartianAdapter {
+            memory_guard,
+            ..self
+        }
+    }
+
+    /// Log through a `tracing` subscriber rather than the default `log` based
+    /// logger, so that events are written to the _log file along with the
+    /// spans they occurred in. Events from the `log` crate are forwarded to the
+    /// subscriber. A summary of the time spent in each span is logged when
+    /// the stage finishes.
+    #[cfg(feature = "tracing")]
+    pub fn tracing(self, format: LogFormat) -> MartianAdapter<S> {
+        MartianAdapter {
+            log_format: Some(format),
+            ..self
+        }
+    }
+
+    /// Check that the files in `metadata_path` can be loaded as the inputs of
+    /// the `phase` of the stage `stage_name`, and pass its validation, without
+    /// running the stage. Nothing is written to `metadata_path`.
+    pub fn check(&self, stage_name: &str, phase: &str, metadata_path: &str) -> Result<()> {
+        let stage = self.stage_map.get(stage_name).with_context(
+            #[cold]
+            || format!("Couldn't find requested Martian stage: {stage_name}"),
+        )?;
+        // The files path and journal are not used by the check.
+        let md = Metadata::new(vec![
+            stage_name.to_string(),
+            phase.to_string(),
+            metadata_path.to_string(),
+            String::new(),
+            String::new(),
+        ])?;
+        stage.check(&md)
+    }
+
     /// Run the martian adapter using the given cmdline args
     /// provided by the martian runtime. The caller should call sys::exit() witih
     /// the returncode returned by this function.
@@ -162,35 +332,66 @@ impl<S: std::hash::BuildHasher> MartianAdapter<S> {
     /// for unit testing purposes.
     #[must_use = "Martian stage binaries should call std::process::exit() on the return_code"]
     pub fn run_get_error(self, args: Vec<String>) -> (i32, Option<Error>) {
-        martian_entry_point(args, self.stage_map, self.log_level, self.is_error_assert)
+        martian_entry_point(args, self)
     }
 }
 
 /// See docs on MartianAdapter methods for details.
 fn martian_entry_point<S: std::hash::BuildHasher>(
     args: Vec<String>,
-    stage_map: HashMap<String, Box<dyn RawMartianStage>, S>,
-    level: LevelFilter,
-    is_error_assert: Box<dyn Fn(&Error) -> bool>,
+    adapter: MartianAdapter<S>,
 ) -> (i32, Option<Error>) {
     info!("got args: {:?}", args);
+    let MartianAdapter {
+        stage_map,
+        log_level: level,
+        is_error_assert,
+        grace_period,
+        build_info,
+        disk_budget,
+        provenance,
+        #[cfg(feature = "tracking-allocator")]
+        memory_guard,
+        #[cfg(feature = "tracing")]
+        log_format,
+    } = adapter;
 
     // turn on backtrace capture
     std::env::set_var("RUST_BACKTRACE", "1");
 
     // Hook rust logging up to Martian _log file
     let log_file: File = unsafe { File::from_raw_fd(3) };
+    #[cfg(feature = "tracing")]
+    match log_format {
+        Some(format) => logging::setup_tracing(log_file, level, format),
+        None => setup_logging(log_file, level),
+    }
+    #[cfg(not(feature = "tracing"))]
     setup_logging(log_file, level);
 
     // setup Martian metadata
     // special handler for error in stage setup
-    let mut md = match initialize(args).context("IO Error initializing stage") {
+    let mut md = match initialize_with_build_info(args, build_info) {
         Ok(m) => m,
         Err(e) => {
             let _ = write_errors(&format!("{e:?}"), false);
             return (1, Some(e));
         }
     };
+    md.set_disk_budget(disk_budget.for_stage(&md.stage_name));
+    md.set_provenance(provenance);
+
+    // Record termination signals from the job manager in _errors and let the
+    // stage know through the cancellation token.
+    if let Err(e) = cancel::install_signal_handlers(md.cancellation_token().clone(), grace_period) {
+        error!("failed to install signal handlers: {e:#}");
+    }
+
+    #[cfg(feature = "tracking-allocator")]
+    memory_guard.start(md.jobinfo.mem_gb, md.jobinfo.vmem_gb, md.alarms().clone());
+
+    #[cfg(feature = "rayon")]
+    configure_rayon(md.jobinfo.threads);
 
     // Get the stage implementation
     let stage = stage_map.get(&md.stage_name).with_context(
@@ -255,30 +456,51 @@ fn martian_entry_point<S: std::hash::BuildHasher>(
         },
     ));
 
-    let result = if md.stage_type == "split" {
-        stage.split(&mut md)
-    } else if md.stage_type == "main" {
-        stage.main(&mut md)
-    } else if md.stage_type == "join" {
-        stage.join(&mut md)
-    } else {
-        panic!("Unrecognized stage type");
+    md.start_instrumentation();
+
+    let result = match md.phase {
+        StagePhase::Split => stage.split(&mut md),
+        StagePhase::Main | StagePhase::Chunk => stage.main(&mut md),
+        StagePhase::Join => stage.join(&mut md),
+        StagePhase::Preflight => stage.preflight(&mut md),
     };
 
+    // The termination message was already written to _errors.
+    if let Some(signal) = md.cancellation_token().signal() {
+        return (128 + signal, result.err());
+    }
+
     match result {
         // exit code = 0
         Ok(()) => (0, None),
 
         // write message and stack trace, exit code = 1;
         Err(e) => {
-            report_error(&mut md, &e, is_error_assert(&e));
+            // Invalid stage inputs will not be fixed by restarting the pipeline.
+            let is_assert = e.is::<ValidationErrors>() || is_error_assert(&e);
+            report_error(&mut md, &e, is_assert);
             (1, Some(e))
         }
     }
 }
 
+/// Size the global rayon thread pool to the number of threads allotted to the stage.
+#[cfg(feature = "rayon")]
+fn configure_rayon(threads: usize) {
+    let threads = threads.max(1);
+    match rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("{}-{i}", usage::RAYON_THREAD_NAME))
+        .build_global()
+    {
+        Ok(()) => info!("configured the global rayon thread pool with {threads} threads"),
+        Err(e) => log::warn!("failed to configure the global rayon thread pool: {e}"),
+    }
+}
+
 #[cold]
 fn report_error(md: &mut Metadata, e: &Error, is_assert: bool) {
+    md.finish_instrumentation();
     let bt = e.backtrace();
     let _ = md.stackvars(&bt.to_string());
     let _ = write_errors(&format!("{e:#}"), is_assert);
@@ -295,6 +517,19 @@ pub fn martian_make_mro(
     filename: Option<impl AsRef<Path>>,
     rewrite: bool,
     mro_registry: Vec<StageMro>,
+) -> Result<()> {
+    write_output(
+        filename,
+        rewrite,
+        &make_mro_string(header_comment, &mro_registry),
+    )
+}
+
+/// Write the `contents`, e.g. an mro, to filename or stdout.
+pub(crate) fn write_output(
+    filename: Option<impl AsRef<Path>>,
+    rewrite: bool,
+    contents: &str,
 ) -> Result<()> {
     if let Some(filename) = &filename {
         let filename = filename.as_ref();
@@ -310,23 +545,54 @@ pub fn martian_make_mro(
         );
     }
 
-    let mro = make_mro_string(header_comment, &mro_registry);
     match filename {
         Some(filename) => {
             let filename = filename.as_ref();
             File::create(filename)
                 .with_context(|| filename.display().to_string())?
-                .write_all(mro.as_bytes())
+                .write_all(contents.as_bytes())
                 .with_context(|| filename.display().to_string())?;
         }
         None => {
-            print!("{mro}");
+            print!("{contents}");
         }
     }
     Ok(())
 }
 
+/// Compare the stages in `mro_registry` with those declared in the mro file
+/// `filename`, ignoring formatting. See [`compare_stages`].
+pub fn martian_check_mro(
+    filename: impl AsRef<Path>,
+    mro_registry: &[StageMro],
+) -> Result<Vec<MroDifference>> {
+    let mro = MroFile::read(filename)?;
+    Ok(compare_stages(mro_registry, &mro.stages))
+}
+
+/// Report the changes from the stages declared in the mro file `filename`,
+/// e.g. that of the previous release, to the stages in `mro_registry`.
+/// See [`compatibility_report`].
+pub fn martian_compatibility_report(
+    filename: impl AsRef<Path>,
+    mro_registry: &[StageMro],
+) -> Result<CompatibilityReport> {
+    let mro = MroFile::read(filename)?;
+    Ok(compatibility_report(&mro.stages, mro_registry))
+}
+
 pub fn make_mro_string(header_comment: &str, mro_registry: &[StageMro]) -> String {
+    make_mro_string_with_pipelines(header_comment, mro_registry, &[])
+}
+
+/// Generate the mro for the stages in `mro_registry`, followed by the
+/// declarations of the `pipelines` which call them. The pipelines have been
+/// checked against the stages they call by [`PipelineBuilder::build`].
+pub fn make_mro_string_with_pipelines(
+    header_comment: &str,
+    mro_registry: &[StageMro],
+    pipelines: &[PipelineMro],
+) -> String {
     let mut filetype_header = FiletypeHeader::default();
     let mut struct_header = StructHeader::default();
     let mut mro_string = String::new();
@@ -335,6 +601,11 @@ pub fn make_mro_string(header_comment: &str, mro_registry: &[StageMro]) -> Strin
         struct_header.add_stage(stage_mro);
         writeln!(&mut mro_string, "{stage_mro}").unwrap();
     }
+    for pipeline in pipelines {
+        filetype_header.add_pipeline(pipeline);
+        struct_header.add_pipeline(pipeline);
+        writeln!(&mut mro_string, "{pipeline}").unwrap();
+    }
     mro_string.pop();
 
     if header_comment.is_empty() {
@@ -0,0 +1,267 @@
+//! Structured logging of stages with `tracing`.
+//!
+//! Enabled with the `tracing` feature and [`MartianAdapter::tracing()`]. Events
+//! from both `tracing` and the `log` crate are written to the `_log` file (and
+//! stdout) along with the spans they are emitted in, either in a human
+//! readable format or as JSON lines. When the stage finishes, a summary of the
+//! time spent in each span is written to the `_log` as well, whatever the log
+//! level:
+//! ```text
+//! 2024-01-02 15:04:05  INFO martian::timing: span="my_stage::align" count=12 busy_secs=40.1 total_secs=42.3
+//! ```
+//!
+//! [`MartianAdapter::tracing()`]: crate::MartianAdapter::tracing
+use crate::DATE_FORMAT;
+use log::LevelFilter;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tracing::span::{Attributes, Id};
+use tracing::Subscriber;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Target of the events summarizing the time spent in each span.
+const TIMING_TARGET: &str = "martian::timing";
+
+/// Format of the events written to the `_log`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// One human readable line per event, prefixed with the enclosing spans.
+    #[default]
+    Human,
+    /// One JSON object per line, including the fields of the enclosing spans.
+    Json,
+}
+
+/// Time spent in all the spans with the same name.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct SpanTiming {
+    count: u64,
+    /// Time during which the span was entered.
+    busy: Duration,
+    /// Time between the creation and the closing of the span.
+    total: Duration,
+}
+
+type SpanTimings = Arc<Mutex<BTreeMap<String, SpanTiming>>>;
+
+static SPAN_TIMINGS: OnceLock<SpanTimings> = OnceLock::new();
+
+/// Per span state, stored in the span extensions.
+struct Timing {
+    created: Instant,
+    entered: Option<Instant>,
+    busy: Duration,
+}
+
+/// Layer aggregating the time spent in each span by name.
+struct SpanTimingLayer {
+    timings: SpanTimings,
+}
+
+impl<S> Layer<S> for SpanTimingLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Timing {
+                created: Instant::now(),
+                entered: None,
+                busy: Duration::ZERO,
+            });
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+                timing.entered = Some(Instant::now());
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+                if let Some(entered) = timing.entered.take() {
+                    timing.busy += entered.elapsed();
+                }
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        let Some(timing) = extensions.get::<Timing>() else {
+            return;
+        };
+        let name = format!("{}::{}", span.metadata().target(), span.name());
+        let mut timings = self.timings.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = timings.entry(name).or_default();
+        entry.count += 1;
+        entry.busy += timing.busy;
+        entry.total += timing.created.elapsed();
+    }
+}
+
+/// Formats the event timestamps like the default martian logger.
+struct MartianTime;
+
+impl FormatTime for MartianTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+        write!(
+            w,
+            "{}",
+            now.format(DATE_FORMAT).map_err(|_| std::fmt::Error)?
+        )
+    }
+}
+
+fn tracing_level(level: LevelFilter) -> tracing_subscriber::filter::LevelFilter {
+    use tracing_subscriber::filter::LevelFilter as Tracing;
+    match level {
+        LevelFilter::Off => Tracing::OFF,
+        LevelFilter::Error => Tracing::ERROR,
+        LevelFilter::Warn => Tracing::WARN,
+        LevelFilter::Info => Tracing::INFO,
+        LevelFilter::Debug => Tracing::DEBUG,
+        LevelFilter::Trace => Tracing::TRACE,
+    }
+}
+
+/// Install a global `tracing` subscriber writing to `log_file` and stdout,
+/// and forward the events of the `log` crate to it.
+pub(crate) fn setup_tracing(log_file: File, level: LevelFilter, format: LogFormat) {
+    let writer = Mutex::new(log_file).and(std::io::stdout);
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_timer(MartianTime)
+        .with_ansi(false)
+        .with_writer(writer);
+    let fmt_layer = match format {
+        LogFormat::Human => fmt_layer.boxed(),
+        LogFormat::Json => fmt_layer.json().with_current_span(true).boxed(),
+    };
+    // The log level only applies to what is written: spans of all levels are
+    // timed, and the summary of the timings is always written.
+    let filter = Targets::new()
+        .with_default(tracing_level(level))
+        .with_target(TIMING_TARGET, tracing::Level::INFO);
+    let timings = SPAN_TIMINGS.get_or_init(SpanTimings::default).clone();
+    let subscriber = tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(filter))
+        .with(SpanTimingLayer { timings });
+
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+        panic!("Failed to initialize global tracing subscriber: {e}");
+    }
+    if let Err(e) = tracing_log::LogTracer::init_with_filter(level) {
+        panic!("Failed to initialize global logger: {e}");
+    }
+}
+
+/// Write the time spent in each span to the log, if the tracing subscriber
+/// is installed.
+pub(crate) fn log_span_timings() {
+    let Some(timings) = SPAN_TIMINGS.get() else {
+        return;
+    };
+    let timings = timings.lock().unwrap_or_else(PoisonError::into_inner);
+    for (span, timing) in timings.iter() {
+        tracing::info!(
+            target: TIMING_TARGET,
+            span = span.as_str(),
+            count = timing.count,
+            busy_secs = timing.busy.as_secs_f64(),
+            total_secs = timing.total.as_secs_f64(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::self_adapter;
+    use crate::{Error, Metadata, RawMartianStage};
+
+    const SPANNED_STAGE: &str = "spanned";
+
+    struct Spanned;
+
+    impl RawMartianStage for Spanned {
+        fn split(&self, _: &mut Metadata) -> Result<(), Error> {
+            unimplemented!()
+        }
+
+        fn main(&self, md: &mut Metadata) -> Result<(), Error> {
+            tracing::info_span!("stage_work").in_scope(|| {
+                std::thread::sleep(Duration::from_millis(5));
+            });
+            md.complete_with("outs", &Default::default())
+        }
+
+        fn join(&self, _: &mut Metadata) -> Result<(), Error> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_span_timings_at_default_level() -> Result<(), Error> {
+        self_adapter::run_if_child(SPANNED_STAGE, Spanned, |adapter| {
+            adapter.tracing(LogFormat::Human)
+        });
+        let tmp = tempfile::tempdir()?;
+        let run = self_adapter::executor(
+            "logging::tests::test_span_timings_at_default_level",
+            SPANNED_STAGE,
+        )
+        .run_with_args(tmp.path().join("run"), Default::default())?;
+
+        assert!(run.is_success());
+        let log = &run.invocations[0].log;
+        assert!(
+            log.contains(
+                "INFO martian::timing: span=\"martian::logging::tests::stage_work\" count=1"
+            ),
+            "{log}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_span_timings() {
+        let timings = SpanTimings::default();
+        let subscriber = tracing_subscriber::registry().with(SpanTimingLayer {
+            timings: timings.clone(),
+        });
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..3 {
+                let span = tracing::info_span!("work");
+                let _guard = span.enter();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            let _idle = tracing::info_span!("idle");
+        });
+
+        let timings = timings.lock().unwrap();
+        let work = timings["martian::logging::tests::work"];
+        assert_eq!(work.count, 3);
+        assert!(work.busy >= Duration::from_millis(15));
+        assert!(work.total >= work.busy);
+        let idle = timings["martian::logging::tests::idle"];
+        assert_eq!(idle.count, 1);
+        assert_eq!(idle.busy, Duration::ZERO);
+    }
+}
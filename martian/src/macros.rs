@@ -21,3 +21,28 @@ macro_rules! martian_stages {
     };
     ( $( $x: path, )*) => ( martian_stages![$($x),*]);
 }
+
+/// Capture the [`BuildInfo`](crate::build::BuildInfo) of the crate calling this macro,
+/// from the variables set by [`martian::build::emit_build_info()`](crate::build::emit_build_info)
+/// in its build script.
+///
+/// ```rust
+/// let info = martian::build_info!();
+/// assert_eq!(info.crate_name, "martian");
+/// ```
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        ::martian::build::BuildInfo::from_build_vars(
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            [
+                option_env!("MARTIAN_BUILD_RUSTC_VERSION"),
+                option_env!("MARTIAN_BUILD_TARGET"),
+                option_env!("MARTIAN_BUILD_PROFILE"),
+                option_env!("MARTIAN_BUILD_FEATURES"),
+                option_env!("MARTIAN_BUILD_GIT_COMMIT"),
+            ],
+        )
+    };
+}
@@ -1,14 +1,24 @@
-use crate::{write_errors, Error, DATE_FORMAT};
+use crate::alarm::{Alarm, AlarmLog};
+use crate::build::BuildInfo;
+use crate::checkpoint::CheckpointStore;
+use crate::disk::{over_budget_message, DiskUsage};
+use crate::mro::MroField;
+use crate::profile::StageProfiler;
+use crate::provenance::{hash_files, FileHash, Provenance};
+use crate::usage::{self, ResourceUsage, UsageMonitor};
+use crate::utils::obj_encode;
+use crate::{close_errors, write_errors, CancellationToken, Error, DATE_FORMAT};
+use anyhow::{bail, ensure};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::map::Map;
 use serde_json::{self, Value};
 use std::any::type_name;
-use std::borrow::Cow;
+use std::fmt;
 use std::fs::{rename, File, OpenOptions};
-use std::io::{ErrorKind, Write};
-use std::os::unix::io::FromRawFd;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 use time::{OffsetDateTime, UtcOffset};
@@ -22,14 +32,97 @@ const METADATA_PREFIX: &str = "_";
 #[derive(Debug)]
 pub struct Metadata {
     pub stage_name: String,
-    pub stage_type: String,
+    pub phase: StagePhase,
     metadata_path: String,
     pub files_path: String,
     run_file: String,
     raw_jobinfo: JsonDict,
     pub jobinfo: JobInfo, // Partially parsed Job info
-    /// Shared reference to the alarm file.
-    alarm_file: SharedFile,
+    /// Writes the alarms raised by the stage to the alarm file.
+    alarms: AlarmLog,
+    /// Flagged when the job manager asks the stage to terminate.
+    cancellation: CancellationToken,
+    /// Active profiler when running with `mrp --profile`.
+    profiler: Option<StageProfiler>,
+    /// Tracks the resources used by the stage.
+    usage_monitor: Option<UsageMonitor>,
+    /// How the adapter was built, recorded in the _jobinfo.
+    build_info: Option<BuildInfo>,
+    /// Disk space allowed for the files directory, in bytes.
+    disk_budget: Option<u64>,
+    /// Whether to write the hashes of the inputs and outputs to _provenance.
+    provenance: bool,
+}
+
+/// The phase of the stage which the runtime asks the adapter to run.
+///
+/// The Martian runtime only sends `split`, `main` and `join`. `chunk` and
+/// `preflight` are extensions of the adapter which no released runtime
+/// sends, for runtimes or tools which invoke the adapter directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum StagePhase {
+    Split,
+    /// A chunk of the stage.
+    Main,
+    /// The only chunk of a stage without a split. Adapter extension: the
+    /// Martian runtime sends `main` for it, like for the chunks of a split.
+    Chunk,
+    Join,
+    /// Validate the inputs of the stage without running it. Adapter
+    /// extension: it completes with empty outputs and a `preflight_outs`
+    /// journal entry, which the Martian runtime does not read.
+    Preflight,
+}
+
+impl StagePhase {
+    const ALL: [StagePhase; 5] = [
+        StagePhase::Split,
+        StagePhase::Main,
+        StagePhase::Chunk,
+        StagePhase::Join,
+        StagePhase::Preflight,
+    ];
+
+    /// The name of the phase on the adapter command line.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StagePhase::Split => "split",
+            StagePhase::Main => "main",
+            StagePhase::Chunk => "chunk",
+            StagePhase::Join => "join",
+            StagePhase::Preflight => "preflight",
+        }
+    }
+
+    /// Whether the phase runs a chunk, and so writes the same files as `main`.
+    pub fn is_chunk(self) -> bool {
+        matches!(self, StagePhase::Main | StagePhase::Chunk)
+    }
+}
+
+impl fmt::Display for StagePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for StagePhase {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match StagePhase::ALL
+            .into_iter()
+            .find(|phase| phase.as_str() == s)
+        {
+            Some(phase) => Ok(phase),
+            None => bail!(
+                "Unrecognized stage phase '{s}', expected one of {}",
+                StagePhase::ALL.map(StagePhase::as_str).join(", ")
+            ),
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -59,7 +152,7 @@ impl Default for Version {
     }
 }
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[non_exhaustive]
 pub enum ProfileMode {
@@ -77,21 +170,56 @@ pub enum ProfileMode {
 struct RustAdapterInfo {
     // Path to the binary executable
     binpath: String,
-    // rustc version
+    // rustc version, captured when the adapter was built, or else the rustc
+    // found at runtime
     version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    features: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crate_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crate_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    git_commit: Option<String>,
 }
 
 impl RustAdapterInfo {
-    fn new() -> Self {
+    fn new(build_info: Option<&BuildInfo>) -> Self {
+        let binpath = match std::env::current_exe() {
+            Ok(exe) => exe.display().to_string(),
+            Err(_) => "unknown".into(),
+        };
+        // Without build info, or if the build script could not run rustc,
+        // fall back to the version of the rustc found at runtime.
+        let rustc_version = || match rustc_version::version() {
+            Ok(v) => v.to_string(),
+            Err(_) => "unknown".into(),
+        };
+        let Some(build_info) = build_info.cloned() else {
+            return RustAdapterInfo {
+                binpath,
+                version: rustc_version(),
+                target: None,
+                profile: None,
+                features: None,
+                crate_name: None,
+                crate_version: None,
+                git_commit: None,
+            };
+        };
         RustAdapterInfo {
-            binpath: match std::env::current_exe() {
-                Ok(exe) => exe.display().to_string(),
-                Err(_) => "unknown".into(),
-            },
-            version: match rustc_version::version() {
-                Ok(v) => v.to_string(),
-                Err(_) => "unknown".into(),
-            },
+            binpath,
+            version: build_info.rustc_version.unwrap_or_else(rustc_version),
+            target: build_info.target,
+            profile: build_info.profile,
+            features: Some(build_info.features),
+            crate_name: Some(build_info.crate_name),
+            crate_version: Some(build_info.crate_version),
+            git_commit: build_info.git_commit,
         }
     }
 }
@@ -114,6 +242,23 @@ pub fn make_timestamp_now() -> String {
     make_timestamp(SystemTime::now())
 }
 
+/// Write the journal entry at `run_file`.
+pub(crate) fn write_journal(run_file: &str) -> Result<()> {
+    let tmp_run_file = format!("{run_file}.tmp");
+    {
+        let mut f = File::create(&tmp_run_file)?;
+        if let Err(err) = f.write_all(make_timestamp_now().as_bytes()) {
+            // Pretty much ignore this error.  The only reason we need
+            // any content at all in this file is because some
+            // filesystems behave strangely with completely empty files.
+            eprintln!("Writing journal file {tmp_run_file}: {err}");
+        }
+    }
+    rename(tmp_run_file.as_str(), run_file).or_else(ignore_not_found)?;
+
+    Ok(())
+}
+
 fn ignore_not_found(err: std::io::Error) -> std::io::Result<()> {
     match err.kind() {
         ErrorKind::NotFound => {
@@ -135,29 +280,110 @@ fn ignore_not_found(err: std::io::Error) -> std::io::Result<()> {
     }
 }
 
+/// Number of lines shown before and after the line of a decoding error.
+const ERROR_CONTEXT_LINES: usize = 2;
+/// Number of bytes shown on either side of the column of a decoding error.
+/// Files written by martian may be a single very long line.
+const ERROR_CONTEXT_COLUMNS: usize = 80;
+
+/// The lines of `file` around `line`, with a marker under `column`, both
+/// starting at 1. Only the part of each line near `column` is kept, so that
+/// the excerpt stays small however large the file is.
+#[cold]
+fn error_excerpt(file: &Path, line: usize, column: usize) -> std::io::Result<String> {
+    let first = line.saturating_sub(ERROR_CONTEXT_LINES).max(1);
+    let last = line + ERROR_CONTEXT_LINES;
+    let window_start = column.saturating_sub(ERROR_CONTEXT_COLUMNS + 1);
+    let window = |line_no: usize| {
+        let start = if line_no == line { window_start } else { 0 };
+        start..start + 2 * ERROR_CONTEXT_COLUMNS
+    };
+
+    // (line number, kept bytes, whether bytes were dropped after them)
+    let mut lines: Vec<(usize, Vec<u8>, bool)> = Vec::new();
+    let mut reader = BufReader::new(File::open(file)?);
+    let (mut line_no, mut col) = (1, 0);
+    'read: loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        let len = buf.len();
+        for &byte in buf {
+            if byte == b'\n' {
+                line_no += 1;
+                col = 0;
+                if line_no > last {
+                    break 'read;
+                }
+                continue;
+            }
+            if line_no >= first {
+                if lines.last().map(|l| l.0) != Some(line_no) {
+                    lines.push((line_no, Vec::new(), false));
+                }
+                let current = lines.last_mut().unwrap();
+                if window(line_no).contains(&col) {
+                    current.1.push(byte);
+                } else if col >= window(line_no).end {
+                    current.2 = true;
+                }
+            }
+            col += 1;
+        }
+        reader.consume(len);
+    }
+
+    let mut excerpt = String::new();
+    for (line_no, bytes, truncated) in lines {
+        let start = window(line_no).start;
+        let prefix = if start > 0 { "..." } else { "" };
+        let suffix = if truncated { "..." } else { "" };
+        let text = String::from_utf8_lossy(&bytes);
+        excerpt.push_str(&format!(
+            "{line_no:>4}: {prefix}{}{suffix}\n",
+            text.trim_end()
+        ));
+        if line_no == line {
+            let offset = 6 + prefix.len() + column.max(1) - 1 - start;
+            excerpt.push_str(&format!("{:offset$}^\n", ""));
+        }
+    }
+    Ok(excerpt.trim_end().to_string())
+}
+
 impl Metadata {
-    pub fn new(mut args: Vec<String>) -> Metadata {
+    pub fn new(mut args: Vec<String>) -> Result<Metadata> {
         // # Take options from command line.
         // shell_cmd, stagecode_path, metadata_path, files_path, run_file = argv
         args.truncate(5);
-        assert_eq!(args.len(), 5, "expected 5 arguments, got {}", args.len());
+        ensure!(args.len() == 5, "expected 5 arguments, got {}", args.len());
         let run_file = args.pop().unwrap();
         let files_path = args.pop().unwrap();
         let metadata_path = args.pop().unwrap();
-        let stage_type = args.pop().unwrap();
+        let phase = args.pop().unwrap().parse()?;
         let stage_name = args.pop().unwrap();
-        let alarm_file = SharedFile::new(make_metadata_file_path(metadata_path.as_ref(), "alarm"));
+        let alarms = AlarmLog::new(SharedFile::new(make_metadata_file_path(
+            metadata_path.as_ref(),
+            "alarm",
+        )));
 
-        Metadata {
+        Ok(Metadata {
             stage_name,
-            stage_type,
+            phase,
             metadata_path,
             files_path,
             run_file,
             raw_jobinfo: Map::new(),
             jobinfo: Default::default(),
-            alarm_file,
-        }
+            alarms,
+            cancellation: CancellationToken::new(),
+            profiler: None,
+            usage_monitor: None,
+            build_info: None,
+            disk_budget: None,
+            provenance: false,
+        })
     }
 
     /// Path within chunk
@@ -179,27 +405,17 @@ impl Metadata {
 
     /// Update the Martian journal -- so that Martian knows what we've updated
     fn update_journal(&self, name: &str) -> Result<()> {
-        let journal_name: Cow<'_, str> = if self.stage_type != "main" {
-            format!("{}_{name}", self.stage_type).into()
-        } else {
-            name.into()
-        };
-
-        let tmp_run_file = format!("{}.{journal_name}.tmp", self.run_file);
-        let run_file = &tmp_run_file[..tmp_run_file.len() - 4];
+        write_journal(&self.journal_path(name))
+    }
 
-        {
-            let mut f = File::create(&tmp_run_file)?;
-            if let Err(err) = f.write_all(make_timestamp_now().as_bytes()) {
-                // Pretty much ignore this error.  The only reason we need
-                // any content at all in this file is because some
-                // filesystems behave strangely with completely empty files.
-                eprintln!("Writing journal file {tmp_run_file}: {err}");
-            }
+    /// Path of the journal entry which tells Martian that the metadata file
+    /// `name` was updated.
+    pub(crate) fn journal_path(&self, name: &str) -> String {
+        if self.phase.is_chunk() {
+            format!("{}.{name}", self.run_file)
+        } else {
+            format!("{}.{}_{name}", self.run_file, self.phase)
         }
-        rename(tmp_run_file.as_str(), run_file).or_else(ignore_not_found)?;
-
-        Ok(())
     }
 
     /// Write JSON to a chunk file
@@ -214,59 +430,85 @@ impl Metadata {
     }
 
     fn _decode<T: Sized + DeserializeOwned>(file: PathBuf) -> Result<T> {
-        let buf = Self::_read_buf_err(&file)?;
-        serde_json::from_str(&buf).map_err(
-            #[cold]
-            |e| Self::_format_buf_err(buf, e, file, type_name::<T>()),
-        )
-    }
-
-    fn _read_buf_err(file: &Path) -> Result<String> {
-        std::fs::read_to_string(file).map_err(
+        let reader = File::open(&file).map_err(
             #[cold]
             |e| {
                 let context = format!("Failed to read file {file:?} due to {e}:");
                 Error::new(e).context(context)
             },
+        )?;
+        let mut de = serde_json::Deserializer::from_reader(BufReader::new(reader));
+        // Reject trailing characters, like serde_json::from_str.
+        let value = T::deserialize(&mut de).and_then(|value| de.end().map(|()| value));
+        value.map_err(
+            #[cold]
+            |e| Self::_decode_err::<T>(e, &file),
         )
     }
 
+    /// Locate the error in the JSON by reading the file again while tracking
+    /// the path to each value, which is too slow for every decode of large
+    /// files like `_chunk_outs`.
+    #[cold]
+    fn _decode_err<T: DeserializeOwned>(e: serde_json::Error, file: &Path) -> Error {
+        let json_path = File::open(file).ok().and_then(|reader| {
+            let mut de = serde_json::Deserializer::from_reader(BufReader::new(reader));
+            serde_path_to_error::deserialize::<_, T>(&mut de)
+                .err()
+                .map(|e| e.path().to_string())
+        });
+        // The value itself is fine if the error is after it.
+        let json_path = json_path.as_deref().unwrap_or(".");
+        Self::_format_decode_err(e, json_path, file, type_name::<T>())
+    }
+
     #[cold]
-    fn _format_buf_err(
-        buf: String,
+    fn _format_decode_err(
         e: serde_json::Error,
-        file: PathBuf,
+        json_path: &str,
+        file: &Path,
         tname: &'static str,
     ) -> Error {
         // Non-generic so that we don't generate copy of this code for every
         // type we `_decode` into.  This is a slight hack to improve compile
         // times.
-        let buf_lines: Vec<_> = buf
-            .lines()
-            .enumerate()
-            .map(|(i, line)| format!("{:>4}: {line}", i + 1))
-            .collect();
-        let context = format!(
-            "The martian-rust adapter failed while deserializing the file {:?} as {} due to the \
-            following error:\n\n{}\n\nThis typically happens when one or more fields in the \
-            struct {} cannot be built from the JSON. The contents of the JSON are shown below: \
-            \n{}",
-            file.file_name().unwrap(),
-            tname,
-            e,
-            tname,
-            buf_lines.join("\n")
+        let excerpt = if e.line() == 0 {
+            None
+        } else {
+            error_excerpt(file, e.line(), e.column()).ok()
+        };
+        let mut context = format!(
+            "The martian-rust adapter failed while deserializing the file {:?} as {tname} due to \
+            the following error:\n\n{e}\n\nThe error is at `{json_path}` in the JSON. This \
+            typically happens when one or more fields in the struct {tname} cannot be built from \
+            the JSON.",
+            file.file_name().unwrap_or(file.as_os_str()),
         );
+        if let Some(excerpt) = excerpt {
+            context.push_str(" The lines around the error are shown below:\n");
+            context.push_str(&excerpt);
+        }
         Error::new(e).context(context)
     }
 
-    pub(crate) fn alarm_file(&self) -> &SharedFile {
-        &self.alarm_file
+    pub(crate) fn alarms(&self) -> &AlarmLog {
+        &self.alarms
+    }
+
+    /// Token which is cancelled when the stage receives a termination signal.
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation
     }
 
-    /// Write a message to the stage alarms.
+    /// Write a message to the stage alarms. Repeats of the same message are
+    /// aggregated, and it is summarized as a warning keyed by the message.
     pub fn alarm(&self, message: &str) -> Result<()> {
-        self.alarm_file.appendln(message, true)
+        self.alarms.raise_message(message)
+    }
+
+    /// Raise a structured alarm. See [`alarm`](crate::alarm).
+    pub fn raise_alarm(&self, alarm: Alarm) -> Result<()> {
+        self.alarms.raise(alarm)
     }
 
     #[cold]
@@ -284,7 +526,7 @@ impl Metadata {
         let mut raw_jobinfo: JsonDict = self.decode("jobinfo")?;
         let jobinfo: JobInfo = serde_json::from_value(Value::Object(raw_jobinfo.clone()))?;
 
-        let info = RustAdapterInfo::new();
+        let info = RustAdapterInfo::new(self.build_info.as_ref());
         raw_jobinfo.insert("rust".to_string(), serde_json::to_value(info)?);
 
         self.write_json_obj("jobinfo", &raw_jobinfo)?;
@@ -293,16 +535,141 @@ impl Metadata {
         Ok(())
     }
 
+    /// Record how the adapter was built in the _jobinfo, when it is updated.
+    pub(crate) fn set_build_info(&mut self, build_info: Option<BuildInfo>) {
+        self.build_info = build_info;
+    }
+
+    /// Raise an alarm if the files directory is larger than `disk_budget`
+    /// bytes when the stage completes.
+    pub(crate) fn set_disk_budget(&mut self, disk_budget: Option<u64>) {
+        self.disk_budget = disk_budget;
+    }
+
+    /// Record the hashes of the inputs and outputs of the stage in
+    /// _provenance. See [`provenance`](crate::provenance).
+    pub(crate) fn set_provenance(&mut self, provenance: bool) {
+        self.provenance = provenance;
+    }
+
+    /// Hash the files in the _args, described by `fields`, if provenance is
+    /// enabled.
+    pub(crate) fn hash_inputs(&self, fields: &[MroField]) -> Result<Option<Vec<FileHash>>> {
+        if !self.provenance {
+            return Ok(None);
+        }
+        let args: JsonDict = self.decode("args")?;
+        hash_files(fields, &args).map(Some)
+    }
+
+    /// Hash the files in `outs`, described by `fields`, and write them to
+    /// _provenance along with the hashes of the `inputs`, if provenance is
+    /// enabled.
+    pub(crate) fn write_provenance(
+        &self,
+        inputs: Option<Vec<FileHash>>,
+        fields: &[MroField],
+        outs: &JsonDict,
+    ) -> Result<()> {
+        let Some(inputs) = inputs else {
+            return Ok(());
+        };
+        let provenance = Provenance {
+            stage: self.stage_name.clone(),
+            phase: self.phase,
+            version: self.jobinfo.version.clone(),
+            build_info: self.build_info.clone(),
+            inputs,
+            outputs: hash_files(fields, outs)?,
+        };
+        self.write_json_obj("provenance", &obj_encode(&provenance)?)
+    }
+
+    /// Start monitoring the resource usage of the stage, and profiling
+    /// it if requested by the `profile_mode` in the _jobinfo.
+    pub(crate) fn start_instrumentation(&mut self) {
+        self.usage_monitor = Some(UsageMonitor::start(
+            usage::SAMPLE_INTERVAL,
+            self.jobinfo.threads,
+        ));
+        self.profiler = StageProfiler::start(self.jobinfo.profile_mode);
+    }
+
+    /// Write the profile and the resource usage of the stage, if they
+    /// were started, and the summary of the alarms.
+    pub(crate) fn finish_instrumentation(&mut self) {
+        if let Err(e) = self.write_alarm_summary() {
+            log::warn!("failed to write the alarm summary: {e:#}");
+        }
+        if let Some(profiler) = self.profiler.take() {
+            if let Err(e) = profiler.finish(self) {
+                log::warn!("failed to write the stage profile: {e:#}");
+            }
+        }
+        if let Some(monitor) = self.usage_monitor.take() {
+            if let Err(e) = self.record_usage(&monitor.finish()) {
+                log::warn!("failed to record the resource usage: {e:#}");
+            }
+        }
+        #[cfg(feature = "tracing")]
+        crate::logging::log_span_timings();
+    }
+
+    /// Write the alarms which are still pending, and the summary of all the
+    /// alarms to _alarm_summary if any were raised.
+    fn write_alarm_summary(&self) -> Result<()> {
+        let summary = self.alarms.finish()?;
+        if summary.alarms.is_empty() {
+            return Ok(());
+        }
+        self.write_json_obj("alarm_summary", &obj_encode(&summary)?)
+    }
+
+    /// Write the manifest of the files left in the files directory to
+    /// _disk_usage, and raise an alarm if they exceed the disk budget.
+    fn record_disk_usage(&self) -> Result<()> {
+        let usage = DiskUsage::measure(&self.files_path)?;
+        usage.log();
+        if let Some(budget) = self.disk_budget {
+            if let Some(message) = over_budget_message(&usage, budget) {
+                self.raise_alarm(
+                    Alarm::warning("disk_budget", message)
+                        .metric("total_bytes", usage.total_bytes as f64)
+                        .metric("budget_bytes", budget as f64),
+                )?;
+            }
+        }
+        self.write_json_obj("disk_usage", &obj_encode(&usage)?)
+    }
+
+    /// Add the resource usage of the stage to the _jobinfo, under `rust.usage`.
+    fn record_usage(&mut self, usage: &ResourceUsage) -> Result<()> {
+        log::info!("resource usage: {usage:?}");
+        if let Some(Value::Object(rust)) = self.raw_jobinfo.get_mut("rust") {
+            rust.insert("usage".to_string(), serde_json::to_value(usage)?);
+        }
+        self.write_json_obj("jobinfo", &self.raw_jobinfo)
+    }
+
     /// Completed successfully
     pub fn complete(&mut self) {
-        unsafe {
-            File::from_raw_fd(4); // Close the error file descriptor.
-        }
+        close_errors();
     }
 
     /// Equivalent to write_json_obj() followed by complete()
     pub(crate) fn complete_with(&mut self, out_filename: &str, out_data: &JsonDict) -> Result<()> {
+        // Write the profile and usage before the outs, after which mrp
+        // considers the stage done.
+        if let Err(e) = self.record_disk_usage() {
+            log::warn!("failed to record the disk usage: {e:#}");
+        }
+        self.finish_instrumentation();
         self.write_json_obj(out_filename, out_data)?;
+        // A restart of a completed chunk should not resume from its
+        // checkpoint.
+        if let Err(e) = CheckpointStore::new(&self.files_path).clear() {
+            log::warn!("failed to remove the checkpoint: {e}");
+        }
         self.complete();
         Ok(())
     }
@@ -362,6 +729,21 @@ impl SharedFile {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[test]
+    fn test_rust_adapter_info() {
+        let runtime_version = rustc_version::version().unwrap().to_string();
+        assert_eq!(RustAdapterInfo::new(None).version, runtime_version);
+
+        let vars = [Some("1.75.0"), None, None, None, None];
+        let info = BuildInfo::from_build_vars("my_adapter", "0.3.1", vars);
+        let adapter_info = RustAdapterInfo::new(Some(&info));
+        assert_eq!(adapter_info.version, "1.75.0");
+        assert_eq!(adapter_info.crate_name.as_deref(), Some("my_adapter"));
+
+        let info = BuildInfo::from_build_vars("my_adapter", "0.3.1", [None; 5]);
+        assert_eq!(RustAdapterInfo::new(Some(&info)).version, runtime_version);
+    }
+
     #[test]
     fn test_jobinfo() -> Result<()> {
         let raw_jobinfo: JsonDict = serde_json::from_reader(File::open("tests/jobinfo.json")?)?;
@@ -385,6 +767,105 @@ mod tests {
         }
 
         let e: Result<Foo> = Metadata::_decode("tests/invalid_args.json".into());
-        insta::assert_display_snapshot!(e.unwrap_err());
+        insta::assert_snapshot!(e.unwrap_err());
+    }
+
+    #[test]
+    fn test_decode_err_context() -> Result<()> {
+        #[derive(Debug, Deserialize)]
+        struct ChunkOuts {
+            #[allow(dead_code)]
+            count: u32,
+        }
+
+        // A single line, as written by martian, with the error deep inside.
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("_chunk_outs");
+        let mut outs: Vec<_> = (0..10_000).map(|i| format!(r#"{{"count":{i}}}"#)).collect();
+        outs[5000] = r#"{"count":"oops"}"#.to_string();
+        std::fs::write(&file, format!("[{}]", outs.join(",")))?;
+
+        let e = Metadata::_decode::<Vec<ChunkOuts>>(file.clone()).unwrap_err();
+        let msg = e.to_string();
+        assert!(
+            msg.contains("The error is at `[5000].count` in the JSON."),
+            "{msg}"
+        );
+        assert!(msg.contains(r#"{"count":"oops"}"#), "{msg}");
+        assert!(msg.len() < 1000, "{msg}");
+        assert!(e.downcast_ref::<serde_json::Error>().is_some());
+
+        // Only the lines around the error are shown.
+        let lines: Vec<_> = (1..=100).map(|i| format!("{i}")).collect();
+        std::fs::write(&file, format!("[\n{},\nnull\n]", lines.join(",\n")))?;
+        let excerpt = error_excerpt(&file, 51, 3)?;
+        assert_eq!(
+            excerpt,
+            "  49: 48,\n  50: 49,\n  51: 50,\n        ^\n  52: 51,\n  53: 52,"
+        );
+        assert!(Metadata::_decode::<Vec<u32>>(file).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_large() -> Result<()> {
+        #[derive(Debug, Deserialize)]
+        struct ChunkOuts {
+            count: u32,
+            per_barcode: std::collections::HashMap<String, u32>,
+        }
+
+        // About 6MB, on the success path which does not track the JSON path.
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("_chunk_outs");
+        let outs: Vec<_> = (0..100_000)
+            .map(|i| {
+                format!(r#"{{"count":{i},"per_barcode":{{"AAACCTGA-1":{i},"AAACCTGC-1":1}}}}"#)
+            })
+            .collect();
+        std::fs::write(&file, format!("[{}]", outs.join(",")))?;
+        let outs = Metadata::_decode::<Vec<ChunkOuts>>(file.clone())?;
+        assert_eq!(outs.len(), 100_000);
+        assert_eq!(outs[99_999].count, 99_999);
+        assert_eq!(outs[99_999].per_barcode["AAACCTGA-1"], 99_999);
+
+        // Trailing characters are still rejected.
+        std::fs::write(&file, "[] []")?;
+        let msg = Metadata::_decode::<Vec<ChunkOuts>>(file)
+            .unwrap_err()
+            .to_string();
+        assert!(msg.contains("The error is at `.` in the JSON."), "{msg}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_stage_phase() -> Result<()> {
+        let args = |phase: &str| {
+            ["sum_squares", phase, "/md", "/md/files", "/journal/run"]
+                .map(String::from)
+                .to_vec()
+        };
+        let md = Metadata::new(args("join"))?;
+        assert_eq!(md.phase, StagePhase::Join);
+        assert_eq!(md.journal_path("outs"), "/journal/run.join_outs");
+        let md = Metadata::new(args("chunk"))?;
+        assert!(md.phase.is_chunk());
+        assert_eq!(md.journal_path("outs"), "/journal/run.outs");
+
+        for phase in StagePhase::ALL {
+            assert_eq!(phase.as_str().parse::<StagePhase>()?, phase);
+        }
+        assert_eq!(
+            Metadata::new(args("merge")).unwrap_err().to_string(),
+            "Unrecognized stage phase 'merge', expected one of split, main, chunk, join, preflight"
+        );
+        assert!(Metadata::new(args("main")[..3].to_vec()).is_err());
+        // Reported in _errors by the entry point.
+        assert_eq!(
+            format!("{:#}", crate::initialize(args("merge")).unwrap_err()),
+            "Invalid command line arguments: Unrecognized stage phase 'merge', \
+             expected one of split, main, chunk, join, preflight"
+        );
+        Ok(())
     }
 }
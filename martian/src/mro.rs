@@ -10,8 +10,23 @@
 //! - Attributes (mem_gb, vmem_gb, threads, volatile etc.)
 //!
 
-use crate::{Error, MartianVoid};
+use crate::{Error, MartianVoid, ValidationErrors};
+
+pub mod compat;
+mod diff;
+mod interface;
+mod parse;
+mod pipeline;
+pub mod schema;
 use anyhow::format_err;
+pub use compat::{compatibility_report, CompatibilityReport};
+pub use diff::{compare_stages, FieldSection, MroDifference, MroDifferenceKind};
+pub use interface::{
+    AdapterInterface, FieldInterface, ParamsInterface, StageInterface, StructInterface,
+    TypeInterface,
+};
+pub use parse::MroFile;
+pub use pipeline::{Binding, PipelineBuilder, PipelineCall, PipelineMro};
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Display;
@@ -72,6 +87,14 @@ impl StructDef {
     pub fn new(name: String, fields: Vec<MroField>) -> Self {
         StructDef { name, fields }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn fields(&self) -> &[MroField] {
+        &self.fields
+    }
 }
 
 impl MroDisplay for StructDef {
@@ -306,7 +329,8 @@ pub trait AsMartianPrimaryType {
 /// Not defined for
 /// - Unit, the type of () in Rust.
 /// - Unit Struct For example `struct Unit` or `PhantomData<T>`. It represents
-///     a named value containing no data.
+///   a named value containing no data.
+///
 /// Any type which implements `AsMartianPrimaryType` also implements `AsMartianBlanketType`
 /// It is strongly recommended not to extend any types with this trait, instead
 /// use the `AsMartianPrimaryType` trait.
@@ -407,6 +431,8 @@ pub struct MroField {
     desc: Option<String>,
     mro_filename: Option<String>,
     retain: bool,
+    #[serde(default)]
+    optional: bool,
 }
 
 impl Display for MroField {
@@ -449,6 +475,7 @@ impl MroField {
                 desc,
                 mro_filename,
                 retain: false,
+                optional: false,
             };
             field.verify(); // No use case to resultify this so far
             field
@@ -456,6 +483,26 @@ impl MroField {
         _new_field(name.to_string(), ty, desc, mro_filename)
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn ty(&self) -> &MartianBlanketType {
+        &self.ty
+    }
+
+    /// Whether the field can be left unset, i.e. it is an `Option` in Rust.
+    /// This is not part of the mro, so it is always false for parsed fields.
+    pub fn is_optional(&self) -> bool {
+        self.optional
+    }
+
+    /// Mark the field as optional. `#[derive(MartianStruct)]` does this for
+    /// `Option` fields.
+    pub fn optional(self, optional: bool) -> Self {
+        MroField { optional, ..self }
+    }
+
     fn name_width(&self) -> usize {
         self.name.len()
     }
@@ -543,6 +590,17 @@ impl MroField {
 pub trait MartianStruct {
     /// How to convert this struct into a list of `MroField`s
     fn mro_fields() -> Vec<MroField>;
+
+    /// Check the values of the fields. The derived implementation checks the
+    /// `#[mro_validate(..)]` attributes, see [`crate::validate`].
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        Ok(())
+    }
+
+    /// JSON Schema of the serialized struct, see [`schema`].
+    fn json_schema() -> serde_json::Value {
+        schema::json_schema(None, &Self::mro_fields())
+    }
 }
 
 impl MartianStruct for MartianVoid {
@@ -602,7 +660,7 @@ macro_rules! mro_using {
         ///     threads = 16,
         /// )
         /// ```
-        #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+        #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
         pub struct MroUsing {
             $(pub $property: Option<$type>,)*
         }
@@ -612,6 +670,24 @@ macro_rules! mro_using {
             pub fn need_using(&self) -> bool {
                 !($(self.$property.is_none())&&*)
             }
+
+            /// The name and value of every property, set or not.
+            fn entries(&self) -> Vec<(&'static str, Option<String>)> {
+                vec![$((stringify!($property), self.$property.as_ref().map(ToString::to_string)),)*]
+            }
+
+            /// Set the property `key` from its value in an mro.
+            fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+                match key {
+                    $(stringify!($property) => {
+                        self.$property = Some(value.parse().map_err(|e| {
+                            format_err!("Invalid value {value} for {key}: {e}")
+                        })?);
+                    })*
+                    _ => return Err(format_err!("Unknown property {key} in the using section")),
+                }
+                Ok(())
+            }
         }
 
         impl Display for MroUsing {
@@ -657,7 +733,7 @@ macro_rules! mro_using {
 mro_using! {mem_gb: i16, threads: i16, vmem_gb: i16, special: String, volatile: Volatile}
 
 /// Input and outputs fields together
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct InAndOut {
     pub inputs: Vec<MroField>,
     pub outputs: Vec<MroField>,
@@ -854,7 +930,7 @@ impl Display for StructHeader {
 pub trait MroMaker {
     fn stage_mro(adapter_name: impl ToString, stage_key: impl ToString) -> StageMro {
         let result = StageMro {
-            stage_name: Self::stage_name(),
+            stage_name: Self::stage_name().to_string(),
             adapter_name: adapter_name.to_string(),
             stage_key: stage_key.to_string(),
             stage_in_out: Self::stage_in_and_out(),
@@ -879,7 +955,7 @@ pub trait MroMaker {
 /// All the data needed to create a stage definition mro.
 #[derive(Debug)]
 pub struct StageMro {
-    stage_name: &'static str, // e.g CORRECT_BARCODES in `stage CORRECT_BARCODES(..)`
+    stage_name: String,     // e.g CORRECT_BARCODES in `stage CORRECT_BARCODES(..)`
     adapter_name: String, // Martian adapter e.g `cr_slfe` in `src comp "cr_slfe martian correct_barcodes"
     stage_key: String, // Key used in the hashmap containing all stages e.g `correct_barcodes` in `src comp "cr_slfe martian correct_barcodes"
     stage_in_out: InAndOut, // Inputs and outputs of the stage
@@ -960,6 +1036,21 @@ impl Display for StageMro {
 }
 
 impl StageMro {
+    /// Name of the stage in the mro, e.g. `CORRECT_BARCODES`.
+    pub fn stage_name(&self) -> &str {
+        &self.stage_name
+    }
+
+    /// Key of the stage in the adapter, e.g. `correct_barcodes`.
+    pub fn stage_key(&self) -> &str {
+        &self.stage_key
+    }
+
+    /// Name of the adapter binary which runs the stage.
+    pub fn adapter_name(&self) -> &str {
+        &self.adapter_name
+    }
+
     fn iter_mro_fields(&self) -> impl Iterator<Item = &MroField> {
         self.stage_in_out
             .iter_mro_fields()
@@ -1066,6 +1157,19 @@ mod tests {
         assert!("foo".parse::<Volatile>().is_err());
     }
 
+    #[test]
+    fn test_volatile_serde() {
+        // The mro form is only used by the interface, see `mro::interface`.
+        assert_eq!(
+            serde_json::to_string(&Volatile::Strict).unwrap(),
+            r#""Strict""#
+        );
+        assert_eq!(
+            serde_json::from_str::<Volatile>(r#""False""#).unwrap(),
+            Volatile::False
+        );
+    }
+
     #[test]
     fn test_volatile_display() {
         let vol = Volatile::Strict;
@@ -1165,7 +1269,7 @@ mod tests {
         );
 
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES",
+            stage_name: "SUM_SQUARES".into(),
             adapter_name: "my_adapter".into(),
             stage_key: "sum_squares".into(),
             stage_in_out: InAndOut {
@@ -1196,7 +1300,7 @@ mod tests {
         );
 
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES",
+            stage_name: "SUM_SQUARES".into(),
             adapter_name: "my_adapter".into(),
             stage_key: "sum_squares".into(),
             stage_in_out: InAndOut {
@@ -1223,7 +1327,7 @@ mod tests {
         );
 
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES",
+            stage_name: "SUM_SQUARES".into(),
             adapter_name: "my_adapter".into(),
             stage_key: "sum_squares".into(),
             stage_in_out: InAndOut {
@@ -1253,7 +1357,7 @@ mod tests {
         );
 
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES",
+            stage_name: "SUM_SQUARES".into(),
             adapter_name: "my_adapter".into(),
             stage_key: "sum_squares".into(),
             stage_in_out: InAndOut {
@@ -1289,7 +1393,7 @@ mod tests {
         );
 
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES",
+            stage_name: "SUM_SQUARES".into(),
             adapter_name: "my_adapter".into(),
             stage_key: "sum_squares".into(),
             stage_in_out: InAndOut {
@@ -1311,7 +1415,7 @@ mod tests {
     #[should_panic]
     fn test_stage_mro_display_duplicate_inputs() {
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES",
+            stage_name: "SUM_SQUARES".into(),
             adapter_name: "my_adapter".into(),
             stage_key: "sum_squares".into(),
             stage_in_out: InAndOut {
@@ -1335,7 +1439,7 @@ mod tests {
     #[should_panic]
     fn test_stage_mro_display_duplicate_outputs() {
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES",
+            stage_name: "SUM_SQUARES".into(),
             adapter_name: "my_adapter".into(),
             stage_key: "sum_squares".into(),
             stage_in_out: InAndOut {
@@ -1358,7 +1462,7 @@ mod tests {
     #[test]
     fn test_stage_mro_display_duplicate_outputs_same_type() {
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES",
+            stage_name: "SUM_SQUARES".into(),
             adapter_name: "my_adapter".into(),
             stage_key: "sum_squares".into(),
             stage_in_out: InAndOut {
@@ -1397,7 +1501,7 @@ mod tests {
         // Check field alignment agrees with `mro format` when chunk arg
         // types are narrower than stage arg types.
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES1",
+            stage_name: "SUM_SQUARES1".into(),
             adapter_name: "my_adapter".into(),
             stage_key: "sum_squares".into(),
             stage_in_out: InAndOut {
@@ -1437,7 +1541,7 @@ mod tests {
         // Check field alignment agrees with `mro format` when stage arg
         // types are narrower than stage src type.
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES2",
+            stage_name: "SUM_SQUARES2".into(),
             adapter_name: "my_adapter".into(),
             stage_key: "sum_squares".into(),
             stage_in_out: InAndOut {
@@ -1475,7 +1579,7 @@ mod tests {
         // Check field alignment agrees with `mro format` when chunk arg
         // types are wider than stage arg types.
         let stage_mro = StageMro {
-            stage_name: "SUM_SQUARES3",
+            stage_name: "SUM_SQUARES3".into(),
             adapter_name: "my_adapter".into(),
             stage_key: "sum_squares".into(),
             stage_in_out: InAndOut {
@@ -1760,7 +1864,7 @@ mod tests {
         };
 
         let stage_mro = StageMro {
-            stage_name: "SETUP_CHUNKS",
+            stage_name: "SETUP_CHUNKS".into(),
             adapter_name: "my_adapter".into(),
             stage_key: "setup_chunks".into(),
             stage_in_out: InAndOut {
@@ -0,0 +1,448 @@
+//! Backward compatibility of the interface of the stages between two
+//! versions of an adapter, e.g. the mro of the previous release and the
+//! stages of the current build. See [`compatibility_report`].
+//!
+//! A change is breaking if pipelines which call the stages or pipestances
+//! which are resumed with the new adapter may fail:
+//! - Removing a stage, or an input or output of a stage.
+//! - Adding an input, even if it is optional (an `Option` in Rust), since
+//!   martian requires every input of a stage to be bound where it is called.
+//! - Changing the type of a field, including the extension of a filetype.
+//! - Changing the fields of a struct, other than adding an optional field.
+//! - Adding a chunk input or output, unless it is optional, or changing its
+//!   type. The chunks of a resumed pipestance get their inputs from the
+//!   `_stage_defs` written by the old split, and the join reads the
+//!   `_chunk_outs` written by the old chunks.
+//!
+//! Adding a stage or an output, and removing a chunk input or output, is
+//! compatible. Chunk outputs which are also outputs of the stage are only
+//! compared as outputs. Changes which do not affect the inputs and outputs,
+//! like the `using` section, are not reported.
+use super::{
+    FieldSection, MartianBlanketType, MartianPrimaryType, MroField, StageMro, StructHeader,
+};
+use std::fmt::{self, Display};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Compatibility {
+    Compatible,
+    Breaking,
+}
+
+impl Display for Compatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compatibility::Compatible => "compatible",
+            Compatibility::Breaking => "breaking",
+        })
+    }
+}
+
+/// The stage or struct which changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Declaration {
+    Stage(String),
+    Struct(String),
+}
+
+impl Display for Declaration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Declaration::Stage(name) => write!(f, "stage {name}"),
+            Declaration::Struct(name) => write!(f, "struct {name}"),
+        }
+    }
+}
+
+/// A change to a field, which is an input or output of a stage, or a field
+/// of a struct.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    AddedStage,
+    RemovedStage,
+    AddedField {
+        section: Option<FieldSection>,
+        field: String,
+        ty: String,
+        optional: bool,
+    },
+    RemovedField {
+        section: Option<FieldSection>,
+        field: String,
+        ty: String,
+    },
+    ChangedType {
+        section: Option<FieldSection>,
+        field: String,
+        old: String,
+        new: String,
+    },
+    /// The type is the same filetype or collection of a filetype, with a
+    /// different extension.
+    ChangedFiletype {
+        section: Option<FieldSection>,
+        field: String,
+        old: String,
+        new: String,
+    },
+}
+
+impl ChangeKind {
+    pub fn compatibility(&self) -> Compatibility {
+        match self {
+            ChangeKind::AddedStage
+            | ChangeKind::AddedField {
+                section: Some(FieldSection::Output),
+                ..
+            }
+            | ChangeKind::AddedField {
+                section: Some(FieldSection::ChunkInput | FieldSection::ChunkOutput) | None,
+                optional: true,
+                ..
+            }
+            | ChangeKind::RemovedField {
+                section: Some(FieldSection::ChunkInput | FieldSection::ChunkOutput),
+                ..
+            } => Compatibility::Compatible,
+            _ => Compatibility::Breaking,
+        }
+    }
+}
+
+impl Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn section(section: &Option<FieldSection>) -> String {
+            section.map_or_else(|| "field".to_string(), |s| s.to_string())
+        }
+        match self {
+            ChangeKind::AddedStage => write!(f, "added the stage"),
+            ChangeKind::RemovedStage => write!(f, "removed the stage"),
+            ChangeKind::AddedField {
+                section: s,
+                field,
+                ty,
+                optional,
+            } => {
+                let optional = if *optional { "optional " } else { "" };
+                write!(f, "added {optional}{} `{field}` ({ty})", section(s))
+            }
+            ChangeKind::RemovedField {
+                section: s,
+                field,
+                ty,
+            } => write!(f, "removed {} `{field}` ({ty})", section(s)),
+            ChangeKind::ChangedType {
+                section: s,
+                field,
+                old,
+                new,
+            } => write!(
+                f,
+                "changed the type of {} `{field}` from {old} to {new}",
+                section(s)
+            ),
+            ChangeKind::ChangedFiletype {
+                section: s,
+                field,
+                old,
+                new,
+            } => write!(
+                f,
+                "changed the filetype of {} `{field}` from {old} to {new}",
+                section(s)
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatibilityChange {
+    pub declaration: Declaration,
+    pub kind: ChangeKind,
+}
+
+impl CompatibilityChange {
+    pub fn compatibility(&self) -> Compatibility {
+        self.kind.compatibility()
+    }
+}
+
+impl Display for CompatibilityChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}: {}",
+            self.compatibility(),
+            self.declaration,
+            self.kind
+        )
+    }
+}
+
+/// The changes between two versions of the stages, see [`compatibility_report`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompatibilityReport {
+    pub changes: Vec<CompatibilityChange>,
+}
+
+impl CompatibilityReport {
+    pub fn is_breaking(&self) -> bool {
+        self.breaking().next().is_some()
+    }
+
+    pub fn breaking(&self) -> impl Iterator<Item = &CompatibilityChange> {
+        self.changes
+            .iter()
+            .filter(|c| c.compatibility() == Compatibility::Breaking)
+    }
+}
+
+impl Display for CompatibilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            writeln!(f, "{change}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Classify the changes from the `old` to the `new` stages, e.g. the stages
+/// of the mro of the previous release and the mro registry of the adapter.
+/// Stages are matched by name, and structs are compared by name across all
+/// the stages.
+///
+/// Whether a field is optional is not part of the mro, so a chunk input or
+/// output, or a struct field, added to stages parsed from an mro is always
+/// considered breaking. A stage without
+/// a split is compared as if it had no chunk inputs or outputs.
+pub fn compatibility_report(old: &[StageMro], new: &[StageMro]) -> CompatibilityReport {
+    let mut changes = Vec::new();
+    for old_stage in old {
+        let declaration = Declaration::Stage(old_stage.stage_name.clone());
+        let Some(new_stage) = new.iter().find(|s| s.stage_name == old_stage.stage_name) else {
+            changes.push(CompatibilityChange {
+                declaration,
+                kind: ChangeKind::RemovedStage,
+            });
+            continue;
+        };
+        let (old_in_out, new_in_out) = (&old_stage.stage_in_out, &new_stage.stage_in_out);
+        let old_chunk = old_stage.minified_chunk_in_outs().unwrap_or_default();
+        let new_chunk = new_stage.minified_chunk_in_outs().unwrap_or_default();
+        for kind in [
+            (FieldSection::Input, &old_in_out.inputs, &new_in_out.inputs),
+            (
+                FieldSection::Output,
+                &old_in_out.outputs,
+                &new_in_out.outputs,
+            ),
+            (
+                FieldSection::ChunkInput,
+                &old_chunk.inputs,
+                &new_chunk.inputs,
+            ),
+            (
+                FieldSection::ChunkOutput,
+                &old_chunk.outputs,
+                &new_chunk.outputs,
+            ),
+        ]
+        .into_iter()
+        .flat_map(|(section, old, new)| compare_fields(Some(section), old, new))
+        {
+            changes.push(CompatibilityChange {
+                declaration: declaration.clone(),
+                kind,
+            });
+        }
+    }
+    for new_stage in new {
+        if old.iter().all(|s| s.stage_name != new_stage.stage_name) {
+            changes.push(CompatibilityChange {
+                declaration: Declaration::Stage(new_stage.stage_name.clone()),
+                kind: ChangeKind::AddedStage,
+            });
+        }
+    }
+
+    let (mut old_structs, mut new_structs) = (StructHeader::default(), StructHeader::default());
+    old.iter().for_each(|s| old_structs.add_stage(s));
+    new.iter().for_each(|s| new_structs.add_stage(s));
+    for (name, (old_def, _)) in &old_structs.0 {
+        if let Some((new_def, _)) = new_structs.0.get(name) {
+            for kind in compare_fields(None, &old_def.fields, &new_def.fields) {
+                changes.push(CompatibilityChange {
+                    declaration: Declaration::Struct(name.clone()),
+                    kind,
+                });
+            }
+        }
+    }
+    CompatibilityReport { changes }
+}
+
+/// Compare the fields with the same name. Structs are compared by name here,
+/// and their fields separately.
+fn compare_fields(
+    section: Option<FieldSection>,
+    old: &[MroField],
+    new: &[MroField],
+) -> Vec<ChangeKind> {
+    let mut kinds = Vec::new();
+    for old_field in old {
+        let field = old_field.name.clone();
+        let Some(new_field) = new.iter().find(|f| f.name == old_field.name) else {
+            kinds.push(ChangeKind::RemovedField {
+                section,
+                field,
+                ty: old_field.ty.to_string(),
+            });
+            continue;
+        };
+        let (old_ty, new_ty) = (old_field.ty.to_string(), new_field.ty.to_string());
+        if old_ty == new_ty {
+            continue;
+        }
+        kinds.push(if same_shape_filetypes(&old_field.ty, &new_field.ty) {
+            ChangeKind::ChangedFiletype {
+                section,
+                field,
+                old: old_ty,
+                new: new_ty,
+            }
+        } else {
+            ChangeKind::ChangedType {
+                section,
+                field,
+                old: old_ty,
+                new: new_ty,
+            }
+        });
+    }
+    for new_field in new {
+        if old.iter().all(|f| f.name != new_field.name) {
+            kinds.push(ChangeKind::AddedField {
+                section,
+                field: new_field.name.clone(),
+                ty: new_field.ty.to_string(),
+                optional: new_field.optional,
+            });
+        }
+    }
+    kinds
+}
+
+/// Whether both types are filetypes, or the same collection of filetypes.
+fn same_shape_filetypes(old: &MartianBlanketType, new: &MartianBlanketType) -> bool {
+    use MartianBlanketType::{Array, Primary, TypedMap};
+    match (old, new) {
+        (Primary(MartianPrimaryType::FileType(_)), Primary(MartianPrimaryType::FileType(_))) => {
+            true
+        }
+        (Array(old), Array(new)) | (TypedMap(old), TypedMap(new)) => same_shape_filetypes(old, new),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mro::MroFile;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_compatibility_report() {
+        let old = MroFile::parse(indoc!(
+            r#"
+            filetype bam;
+            filetype txt;
+
+            struct Sample(
+                string name,
+                int    reads,
+            )
+
+            stage ALIGN(
+                in  Sample sample,
+                in  int    threads,
+                out bam[]  bams,
+                out txt    log,
+                src comp   "adapter martian align",
+            ) split (
+                in  int    chunk_id,
+                in  int    offset,
+                out int    count,
+            )
+
+            stage REPORT(
+                in  bam[]  bams,
+                src comp   "adapter martian report",
+            )
+            "#
+        ))
+        .unwrap();
+        let new = MroFile::parse(indoc!(
+            r#"
+            filetype cram;
+            filetype json;
+
+            struct Sample(
+                string name,
+                float  reads,
+                int    lanes,
+            )
+
+            stage ALIGN(
+                in  Sample  sample,
+                in  float   threads,
+                in  string  reference,
+                out cram[]  bams,
+                out json    summary,
+                src comp    "adapter martian align",
+            ) split (
+                in  int     chunk_id,
+                in  string  lane,
+                out float   count,
+            )
+
+            stage SUMMARIZE(
+                in  cram[] bams,
+                src comp   "adapter martian summarize",
+            )
+            "#
+        ))
+        .unwrap();
+        let mut new_stages = new.stages;
+        // Whether a field is optional only comes from the Rust types.
+        new_stages[0].stage_in_out.inputs[2] =
+            new_stages[0].stage_in_out.inputs[2].clone().optional(true);
+
+        let report = compatibility_report(&old.stages, &new_stages);
+        assert_eq!(
+            report.to_string(),
+            indoc!(
+                "
+                breaking: stage ALIGN: changed the type of input `threads` from int to float
+                breaking: stage ALIGN: added optional input `reference` (string)
+                breaking: stage ALIGN: changed the filetype of output `bams` from bam[] to cram[]
+                breaking: stage ALIGN: removed output `log` (txt)
+                compatible: stage ALIGN: added output `summary` (json)
+                compatible: stage ALIGN: removed chunk input `offset` (int)
+                breaking: stage ALIGN: added chunk input `lane` (string)
+                breaking: stage ALIGN: changed the type of chunk output `count` from int to float
+                breaking: stage REPORT: removed the stage
+                compatible: stage SUMMARIZE: added the stage
+                breaking: struct Sample: changed the type of field `reads` from int to float
+                breaking: struct Sample: added field `lanes` (int)
+                "
+            )
+        );
+        assert!(report.is_breaking());
+        assert_eq!(report.breaking().count(), 9);
+
+        let unchanged = compatibility_report(&old.stages, &old.stages);
+        assert_eq!(unchanged, CompatibilityReport::default());
+        assert!(!unchanged.is_breaking());
+    }
+}
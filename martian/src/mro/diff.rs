@@ -0,0 +1,473 @@
+//! Semantic comparison of the stages of an adapter with the stages declared
+//! in an mro file, e.g. to check in CI that a checked-in mro is up to date.
+//!
+//! Unlike a textual diff, the comparison ignores formatting, comments, the
+//! order of the fields and their descriptions. It reports the differences
+//! which matter to martian: missing or extra stages and fields, types,
+//! output file names of struct fields, the split, `src`, `using` and
+//! `retain`. Output file names of stage fields are not compared, since the
+//! generated mro does not contain them.
+use super::{InAndOut, MartianPrimaryType, MroField, StageMro};
+use std::fmt::{self, Display};
+
+/// Where a field is declared in a stage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldSection {
+    Input,
+    Output,
+    ChunkInput,
+    ChunkOutput,
+}
+
+impl Display for FieldSection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FieldSection::Input => "input",
+            FieldSection::Output => "output",
+            FieldSection::ChunkInput => "chunk input",
+            FieldSection::ChunkOutput => "chunk output",
+        })
+    }
+}
+
+/// A difference between a stage of the adapter and its declaration in the
+/// mro.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MroDifference {
+    /// Name of the stage, e.g. `SORT_READS`.
+    pub stage: String,
+    /// Key of the stage in the adapter, e.g. `sort_reads`, which names the
+    /// Rust stage that needs to be updated (or whose mro needs to be
+    /// regenerated).
+    pub stage_key: String,
+    pub kind: MroDifferenceKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MroDifferenceKind {
+    /// The stage is in the adapter but not in the mro.
+    MissingStage,
+    /// The stage is in the mro but not in the adapter.
+    ExtraStage,
+    Src {
+        adapter: String,
+        mro: String,
+    },
+    /// Only one of the adapter and the mro has a split.
+    Split {
+        in_adapter: bool,
+    },
+    /// The field is in the adapter but not in the mro. Fields of structs are
+    /// named `field.subfield`.
+    MissingField {
+        section: FieldSection,
+        field: String,
+        ty: String,
+    },
+    /// The field is in the mro but not in the adapter.
+    ExtraField {
+        section: FieldSection,
+        field: String,
+        ty: String,
+    },
+    Type {
+        section: FieldSection,
+        field: String,
+        adapter: String,
+        mro: String,
+    },
+    OutFilename {
+        section: FieldSection,
+        field: String,
+        adapter: Option<String>,
+        mro: Option<String>,
+    },
+    /// Only one of the adapter and the mro retains the output.
+    Retain {
+        field: String,
+        in_adapter: bool,
+    },
+    /// A property of the `using` section, like `mem_gb`.
+    Using {
+        property: &'static str,
+        adapter: Option<String>,
+        mro: Option<String>,
+    },
+}
+
+impl Display for MroDifferenceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn quoted(value: &Option<String>) -> String {
+            value
+                .as_ref()
+                .map_or_else(|| "unset".to_string(), |v| format!("{v:?}"))
+        }
+        fn plain(value: &Option<String>) -> &str {
+            value.as_deref().unwrap_or("unset")
+        }
+        fn only_in(in_adapter: bool) -> &'static str {
+            if in_adapter {
+                "in the adapter but not in the mro"
+            } else {
+                "in the mro but not in the adapter"
+            }
+        }
+        match self {
+            MroDifferenceKind::MissingStage => write!(f, "the stage is missing from the mro"),
+            MroDifferenceKind::ExtraStage => {
+                write!(f, "the stage is in the mro but not in the adapter")
+            }
+            MroDifferenceKind::Src { adapter, mro } => {
+                write!(
+                    f,
+                    "src is {adapter:?} in the adapter but {mro:?} in the mro"
+                )
+            }
+            MroDifferenceKind::Split { in_adapter } => {
+                write!(f, "the stage has a split {}", only_in(*in_adapter))
+            }
+            MroDifferenceKind::MissingField { section, field, ty } => {
+                write!(f, "{section} `{field}` ({ty}) is missing from the mro")
+            }
+            MroDifferenceKind::ExtraField { section, field, ty } => {
+                write!(f, "{section} `{field}` ({ty}) is {}", only_in(false))
+            }
+            MroDifferenceKind::Type {
+                section,
+                field,
+                adapter,
+                mro,
+            } => write!(
+                f,
+                "{section} `{field}` has type {adapter} in the adapter but {mro} in the mro"
+            ),
+            MroDifferenceKind::OutFilename {
+                section,
+                field,
+                adapter,
+                mro,
+            } => write!(
+                f,
+                "the file name of {section} `{field}` is {} in the adapter but {} in the mro",
+                quoted(adapter),
+                quoted(mro)
+            ),
+            MroDifferenceKind::Retain { field, in_adapter } => {
+                write!(f, "output `{field}` is retained {}", only_in(*in_adapter))
+            }
+            MroDifferenceKind::Using {
+                property,
+                adapter,
+                mro,
+            } => write!(
+                f,
+                "{property} is {} in the adapter but {} in the mro",
+                plain(adapter),
+                plain(mro)
+            ),
+        }
+    }
+}
+
+impl Display for MroDifference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}): {}", self.stage, self.stage_key, self.kind)
+    }
+}
+
+/// Compare the stages of an adapter, e.g. its mro registry, with the stages
+/// declared in an mro. Stages are matched by name.
+pub fn compare_stages(adapter: &[StageMro], mro: &[StageMro]) -> Vec<MroDifference> {
+    let mut differences = Vec::new();
+    for stage in adapter {
+        match mro.iter().find(|s| s.stage_name == stage.stage_name) {
+            Some(declared) => differences.extend(stage.compare(declared)),
+            None => differences.push(stage.difference(MroDifferenceKind::MissingStage)),
+        }
+    }
+    for declared in mro {
+        if adapter.iter().all(|s| s.stage_name != declared.stage_name) {
+            differences.push(declared.difference(MroDifferenceKind::ExtraStage));
+        }
+    }
+    differences
+}
+
+impl StageMro {
+    /// The differences between this stage and its declaration `mro`.
+    pub fn compare(&self, mro: &StageMro) -> Vec<MroDifference> {
+        let mut kinds = Vec::new();
+        let (src, mro_src) = (self.src(), mro.src());
+        if src != mro_src {
+            kinds.push(MroDifferenceKind::Src {
+                adapter: src,
+                mro: mro_src,
+            });
+        }
+
+        let (stage, mro_stage) = (&self.stage_in_out, &mro.stage_in_out);
+        compare_fields(
+            FieldSection::Input,
+            "",
+            &stage.inputs,
+            &mro_stage.inputs,
+            &mut kinds,
+        );
+        compare_fields(
+            FieldSection::Output,
+            "",
+            &stage.outputs,
+            &mro_stage.outputs,
+            &mut kinds,
+        );
+        for field in &stage.outputs {
+            if let Some(declared) = mro_stage.outputs.iter().find(|f| f.name == field.name) {
+                if field.retain != declared.retain {
+                    kinds.push(MroDifferenceKind::Retain {
+                        field: field.name.clone(),
+                        in_adapter: field.retain,
+                    });
+                }
+            }
+        }
+
+        match (self.minified_chunk_in_outs(), mro.minified_chunk_in_outs()) {
+            (Some(chunk), Some(mro_chunk)) => compare_chunk(&chunk, &mro_chunk, &mut kinds),
+            (None, None) => {}
+            (chunk, _) => kinds.push(MroDifferenceKind::Split {
+                in_adapter: chunk.is_some(),
+            }),
+        }
+
+        for ((property, value), (_, mro_value)) in self
+            .using_attrs
+            .entries()
+            .into_iter()
+            .zip(mro.using_attrs.entries())
+        {
+            if value != mro_value {
+                kinds.push(MroDifferenceKind::Using {
+                    property,
+                    adapter: value,
+                    mro: mro_value,
+                });
+            }
+        }
+
+        kinds
+            .into_iter()
+            .map(|kind| self.difference(kind))
+            .collect()
+    }
+
+    fn src(&self) -> String {
+        format!("{} martian {}", self.adapter_name, self.stage_key)
+    }
+
+    fn difference(&self, kind: MroDifferenceKind) -> MroDifference {
+        MroDifference {
+            stage: self.stage_name.clone(),
+            stage_key: self.stage_key.clone(),
+            kind,
+        }
+    }
+}
+
+fn compare_chunk(chunk: &InAndOut, mro: &InAndOut, kinds: &mut Vec<MroDifferenceKind>) {
+    compare_fields(
+        FieldSection::ChunkInput,
+        "",
+        &chunk.inputs,
+        &mro.inputs,
+        kinds,
+    );
+    compare_fields(
+        FieldSection::ChunkOutput,
+        "",
+        &chunk.outputs,
+        &mro.outputs,
+        kinds,
+    );
+}
+
+/// Compare the fields with the same name in `adapter` and `mro`, and those of
+/// the structs they contain. `prefix` is the path of the enclosing struct
+/// field, if any.
+fn compare_fields(
+    section: FieldSection,
+    prefix: &str,
+    adapter: &[MroField],
+    mro: &[MroField],
+    kinds: &mut Vec<MroDifferenceKind>,
+) {
+    let path = |field: &MroField| format!("{prefix}{}", field.name);
+    for field in adapter {
+        let Some(declared) = mro.iter().find(|f| f.name == field.name) else {
+            kinds.push(MroDifferenceKind::MissingField {
+                section,
+                field: path(field),
+                ty: field.ty.to_string(),
+            });
+            continue;
+        };
+        // Structs are displayed by name, so a changed definition is reported
+        // as changes to its fields.
+        let (ty, mro_ty) = (field.ty.to_string(), declared.ty.to_string());
+        if ty != mro_ty {
+            kinds.push(MroDifferenceKind::Type {
+                section,
+                field: path(field),
+                adapter: ty,
+                mro: mro_ty,
+            });
+            continue;
+        }
+        if let (MartianPrimaryType::Struct(def), MartianPrimaryType::Struct(mro_def)) =
+            (field.ty.inner(), declared.ty.inner())
+        {
+            let prefix = format!("{}.", path(field));
+            compare_fields(section, &prefix, &def.fields, &mro_def.fields, kinds);
+        }
+        // The generated mro only has the output file names of struct fields.
+        if !prefix.is_empty() && field.mro_filename != declared.mro_filename {
+            kinds.push(MroDifferenceKind::OutFilename {
+                section,
+                field: path(field),
+                adapter: field.mro_filename.clone(),
+                mro: declared.mro_filename.clone(),
+            });
+        }
+    }
+    for declared in mro {
+        if adapter.iter().all(|f| f.name != declared.name) {
+            kinds.push(MroDifferenceKind::ExtraField {
+                section,
+                field: path(declared),
+                ty: declared.ty.to_string(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::make_mro_string;
+    use crate::mro::{MartianBlanketType, MroFile, MroUsing, StructDef, Volatile};
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use MartianBlanketType::{Array, Primary};
+    use MartianPrimaryType::{FileType, Float, Str, Struct};
+
+    fn sum_squares() -> StageMro {
+        let sample = StructDef::new(
+            "Sample".into(),
+            vec![
+                MroField::new("name", Primary(Str), None, None),
+                MroField::new(
+                    "reads",
+                    Primary(FileType("fastq".into())),
+                    Some("Reads".into()),
+                    Some("reads.fastq".into()),
+                ),
+            ],
+        );
+        StageMro {
+            stage_name: "SUM_SQUARES".into(),
+            adapter_name: "sum_sq".into(),
+            stage_key: "sum_squares".into(),
+            stage_in_out: InAndOut {
+                inputs: vec![
+                    MroField::new("values", Array(Float.into()), None, None),
+                    MroField::new("sample", Primary(Struct(sample)), None, None),
+                ],
+                outputs: vec![MroField::retained("sum", Primary(Float), None, None)],
+            },
+            chunk_in_out: Some(InAndOut {
+                inputs: vec![MroField::new("value", Primary(Float), None, None)],
+                outputs: vec![MroField::new("sum", Primary(Float), None, None)],
+            }),
+            using_attrs: MroUsing {
+                mem_gb: Some(2),
+                volatile: Some(Volatile::Strict),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_compare_generated_mro() {
+        let adapter = [sum_squares()];
+        let mro = MroFile::parse(&make_mro_string("# header", &adapter)).unwrap();
+        assert_eq!(compare_stages(&adapter, &mro.stages), []);
+    }
+
+    #[test]
+    fn test_compare_stages() {
+        let mro = MroFile::parse(indoc!(
+            r#"
+            filetype fastq;
+
+            # Reordered and reformatted, which does not matter.
+            struct Sample(
+                fastq reads "Sample reads" "sample.fastq",
+                string name,
+            )
+
+            stage SUM_SQUARES(
+                in Sample sample,
+                in int[] values,
+                in int offset,
+                out float sum,
+                src comp "sum_sq martian sum_squares",
+            ) split (
+                in float value,
+                out float sum,
+            ) using (
+                mem_gb = 4,
+                volatile = strict,
+            )
+
+            stage OLD_STAGE(
+                src comp "sum_sq martian old_stage",
+            )
+            "#
+        ))
+        .unwrap();
+        let differences: Vec<_> = compare_stages(&[sum_squares()], &mro.stages)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            differences,
+            [
+                "SUM_SQUARES (sum_squares): input `values` has type float[] in the adapter but int[] in the mro",
+                "SUM_SQUARES (sum_squares): the file name of input `sample.reads` is \"reads.fastq\" in the adapter but \"sample.fastq\" in the mro",
+                "SUM_SQUARES (sum_squares): input `offset` (int) is in the mro but not in the adapter",
+                "SUM_SQUARES (sum_squares): output `sum` is retained in the adapter but not in the mro",
+                "SUM_SQUARES (sum_squares): mem_gb is 2 in the adapter but 4 in the mro",
+                "OLD_STAGE (old_stage): the stage is in the mro but not in the adapter",
+            ]
+        );
+
+        let missing = compare_stages(&[sum_squares()], &[]);
+        assert_eq!(missing[0].kind, MroDifferenceKind::MissingStage);
+        let mut main_only = sum_squares();
+        main_only.chunk_in_out = None;
+        main_only.adapter_name = "other".into();
+        assert_eq!(
+            sum_squares()
+                .compare(&main_only)
+                .into_iter()
+                .map(|d| d.kind)
+                .collect::<Vec<_>>(),
+            [
+                MroDifferenceKind::Src {
+                    adapter: "sum_sq martian sum_squares".into(),
+                    mro: "other martian sum_squares".into()
+                },
+                MroDifferenceKind::Split { in_adapter: true },
+            ]
+        );
+    }
+}
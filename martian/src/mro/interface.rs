@@ -0,0 +1,372 @@
+//! A serializable description of the interface of the stages of an adapter,
+//! for tools which need the stages without parsing the mro, like
+//! documentation or UI generators. See [`AdapterInterface`].
+use super::{
+    FiletypeHeader, InAndOut, MartianBlanketType, MartianPrimaryType, MroField, MroUsing, StageMro,
+    StructDef, StructHeader, Volatile,
+};
+use serde::{Deserialize, Serialize};
+
+/// The type of a field, e.g. `{"kind": "array", "items": {"kind": "filetype",
+/// "extension": "bam"}}` for `bam[]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TypeInterface {
+    /// `int`, `float`, `bool`, `string`, `path` or `file`.
+    Primary {
+        name: String,
+    },
+    Filetype {
+        extension: String,
+    },
+    /// A struct declared in [`AdapterInterface::structs`].
+    Struct {
+        name: String,
+    },
+    Array {
+        items: Box<TypeInterface>,
+    },
+    /// `values` is `None` for an untyped `map`.
+    Map {
+        values: Option<Box<TypeInterface>>,
+    },
+}
+
+impl From<&MartianBlanketType> for TypeInterface {
+    fn from(ty: &MartianBlanketType) -> Self {
+        match ty {
+            MartianBlanketType::Primary(primary) => TypeInterface::from(primary),
+            MartianBlanketType::Array(items) => TypeInterface::Array {
+                items: Box::new(TypeInterface::from(&**items)),
+            },
+            MartianBlanketType::TypedMap(values) => TypeInterface::Map {
+                values: Some(Box::new(TypeInterface::from(&**values))),
+            },
+        }
+    }
+}
+
+impl From<&MartianPrimaryType> for TypeInterface {
+    fn from(ty: &MartianPrimaryType) -> Self {
+        match ty {
+            MartianPrimaryType::Map => TypeInterface::Map { values: None },
+            MartianPrimaryType::FileType(extension) => TypeInterface::Filetype {
+                extension: extension.clone(),
+            },
+            MartianPrimaryType::Struct(def) => TypeInterface::Struct {
+                name: def.name.clone(),
+            },
+            primary => TypeInterface::Primary {
+                name: primary.to_string(),
+            },
+        }
+    }
+}
+
+/// An input or output of a stage or a field of a struct.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldInterface {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: TypeInterface,
+    /// The type as written in the mro, e.g. `int[]`, `map<bam>` or the name
+    /// of a struct.
+    pub mro_type: String,
+    pub description: Option<String>,
+    pub mro_filename: Option<String>,
+    pub retain: bool,
+    /// Whether the field is an `Option` in Rust.
+    pub optional: bool,
+}
+
+impl From<&MroField> for FieldInterface {
+    fn from(field: &MroField) -> Self {
+        FieldInterface {
+            name: field.name.clone(),
+            ty: TypeInterface::from(&field.ty),
+            mro_type: field.ty.to_string(),
+            description: field.desc.clone().filter(|desc| !desc.is_empty()),
+            mro_filename: field.mro_filename.clone(),
+            retain: field.retain,
+            optional: field.optional,
+        }
+    }
+}
+
+/// The inputs and outputs of a stage or of its chunks.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParamsInterface {
+    pub inputs: Vec<FieldInterface>,
+    pub outputs: Vec<FieldInterface>,
+}
+
+impl From<&InAndOut> for ParamsInterface {
+    fn from(in_out: &InAndOut) -> Self {
+        ParamsInterface {
+            inputs: in_out.inputs.iter().map(FieldInterface::from).collect(),
+            outputs: in_out.outputs.iter().map(FieldInterface::from).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StructInterface {
+    pub name: String,
+    pub fields: Vec<FieldInterface>,
+}
+
+impl From<&StructDef> for StructInterface {
+    fn from(def: &StructDef) -> Self {
+        StructInterface {
+            name: def.name.clone(),
+            fields: def.fields.iter().map(FieldInterface::from).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StageInterface {
+    /// Name of the stage in the mro, e.g. `SORT_READS`.
+    pub name: String,
+    pub adapter: String,
+    /// Key of the stage in the adapter, e.g. `sort_reads`.
+    pub stage_key: String,
+    #[serde(flatten)]
+    pub params: ParamsInterface,
+    /// The inputs and outputs of the chunks, including the outputs which are
+    /// also stage outputs. `None` for stages without a split.
+    pub chunk: Option<ParamsInterface>,
+    /// `volatile` is written as in the mro, e.g. `"strict"`.
+    #[serde(with = "mro_volatile")]
+    pub using: MroUsing,
+}
+
+/// (De)serialize the `volatile` property of a [`MroUsing`] as in the mro,
+/// rather than with the variant names of [`Volatile`].
+mod mro_volatile {
+    use super::{MroUsing, Volatile};
+    use serde::de::Error as _;
+    use serde::ser::Error as _;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::Value;
+
+    pub(super) fn serialize<S: Serializer>(using: &MroUsing, s: S) -> Result<S::Ok, S::Error> {
+        let mut value = serde_json::to_value(using).map_err(S::Error::custom)?;
+        if let Some(volatile) = &using.volatile {
+            value["volatile"] = volatile.to_string().into();
+        }
+        value.serialize(s)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<MroUsing, D::Error> {
+        let mut value = Value::deserialize(d)?;
+        if let Some(volatile) = value.get_mut("volatile").filter(|v| !v.is_null()) {
+            let mro = volatile.as_str().unwrap_or_default();
+            let parsed: Volatile = mro.parse().map_err(D::Error::custom)?;
+            *volatile = serde_json::to_value(parsed).map_err(D::Error::custom)?;
+        }
+        serde_json::from_value(value).map_err(D::Error::custom)
+    }
+}
+
+impl From<&StageMro> for StageInterface {
+    fn from(stage: &StageMro) -> Self {
+        StageInterface {
+            name: stage.stage_name.clone(),
+            adapter: stage.adapter_name.clone(),
+            stage_key: stage.stage_key.clone(),
+            params: ParamsInterface::from(&stage.stage_in_out),
+            chunk: stage.chunk_in_out.as_ref().map(ParamsInterface::from),
+            using: stage.using_attrs.clone(),
+        }
+    }
+}
+
+/// The interface of all the stages of an adapter, along with the filetypes
+/// and structs they use.
+///
+/// ```rust
+/// # use martian::{AdapterInterface, StageMro};
+/// # let mro_registry: Vec<StageMro> = Vec::new();
+/// // mro_registry is returned by martian_stages!
+/// let json = AdapterInterface::new(&mro_registry).to_json()?;
+/// # Ok::<(), martian::Error>(())
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AdapterInterface {
+    /// Extensions of the filetypes, sorted.
+    pub filetypes: Vec<String>,
+    /// Structs in the order they are declared in the mro, where a struct is
+    /// declared after the structs it contains.
+    pub structs: Vec<StructInterface>,
+    pub stages: Vec<StageInterface>,
+}
+
+impl AdapterInterface {
+    pub fn new(mro_registry: &[StageMro]) -> Self {
+        let mut filetype_header = FiletypeHeader::default();
+        let mut struct_header = StructHeader::default();
+        for stage in mro_registry {
+            filetype_header.add_stage(stage);
+            struct_header.add_stage(stage);
+        }
+        let mut filetypes: Vec<_> = filetype_header.0.into_iter().collect();
+        filetypes.sort();
+        let mut structs: Vec<_> = struct_header.0.into_values().collect();
+        structs.sort_by_key(|(_, index)| *index);
+        AdapterInterface {
+            filetypes,
+            structs: structs
+                .iter()
+                .map(|(def, _)| StructInterface::from(def))
+                .collect(),
+            stages: mro_registry.iter().map(StageInterface::from).collect(),
+        }
+    }
+
+    /// Pretty printed JSON.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mro::{MroFile, Volatile};
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_adapter_interface() {
+        let mro = MroFile::parse(indoc!(
+            r#"
+            filetype fastq;
+            filetype json;
+
+            struct Reads(
+                fastq r1 "Read 1" "r1.fastq",
+            )
+
+            stage CHUNK_READS(
+                in  Reads[] reads,
+                out json    summary "The summary",
+                src comp    "my_adapter martian chunk_reads",
+            ) split (
+                in  int     chunk_id,
+                out int     count,
+            ) using (
+                mem_gb   = 4,
+                volatile = strict,
+            ) retain (
+                summary,
+            )
+            "#
+        ))
+        .unwrap();
+        let interface = AdapterInterface::new(&mro.stages);
+        assert_eq!(interface.filetypes, ["fastq", "json"]);
+        assert_eq!(
+            interface.structs,
+            [StructInterface {
+                name: "Reads".into(),
+                fields: vec![FieldInterface {
+                    name: "r1".into(),
+                    ty: TypeInterface::Filetype {
+                        extension: "fastq".into(),
+                    },
+                    mro_type: "fastq".into(),
+                    description: Some("Read 1".into()),
+                    mro_filename: Some("r1.fastq".into()),
+                    retain: false,
+                    optional: false,
+                }],
+            }]
+        );
+        let [stage] = interface.stages.as_slice() else {
+            panic!("expected one stage, found {:?}", interface.stages);
+        };
+        assert_eq!(
+            stage.params.outputs[0].description.as_deref(),
+            Some("The summary")
+        );
+        assert!(stage.params.outputs[0].retain);
+        assert_eq!(stage.using.volatile, Some(Volatile::Strict));
+
+        let json: serde_json::Value = serde_json::from_str(&interface.to_json().unwrap()).unwrap();
+        assert_eq!(
+            json["stages"][0],
+            serde_json::json!({
+                "name": "CHUNK_READS",
+                "adapter": "my_adapter",
+                "stage_key": "chunk_reads",
+                "inputs": [{
+                    "name": "reads",
+                    "type": {"kind": "array", "items": {"kind": "struct", "name": "Reads"}},
+                    "mro_type": "Reads[]",
+                    "description": null,
+                    "mro_filename": null,
+                    "retain": false,
+                    "optional": false,
+                }],
+                "outputs": [{
+                    "name": "summary",
+                    "type": {"kind": "filetype", "extension": "json"},
+                    "mro_type": "json",
+                    "description": "The summary",
+                    "mro_filename": null,
+                    "retain": true,
+                    "optional": false,
+                }],
+                "chunk": {
+                    "inputs": [{
+                        "name": "chunk_id",
+                        "type": {"kind": "primary", "name": "int"},
+                        "mro_type": "int",
+                        "description": null,
+                        "mro_filename": null,
+                        "retain": false,
+                        "optional": false,
+                    }],
+                    "outputs": [{
+                        "name": "count",
+                        "type": {"kind": "primary", "name": "int"},
+                        "mro_type": "int",
+                        "description": null,
+                        "mro_filename": null,
+                        "retain": false,
+                        "optional": false,
+                    }],
+                },
+                "using": {
+                    "mem_gb": 4,
+                    "threads": null,
+                    "vmem_gb": null,
+                    "special": null,
+                    "volatile": "strict",
+                },
+            })
+        );
+        let parsed: AdapterInterface = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.stages[0], *stage);
+    }
+
+    #[test]
+    fn test_type_interface() {
+        use MartianBlanketType::{Array, Primary, TypedMap};
+        let ty = TypedMap(Box::new(Array(Box::new(Primary(
+            MartianPrimaryType::Float,
+        )))));
+        assert_eq!(
+            serde_json::to_value(TypeInterface::from(&ty)).unwrap(),
+            serde_json::json!({
+                "kind": "map",
+                "values": {"kind": "array", "items": {"kind": "primary", "name": "float"}},
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(TypeInterface::from(&Primary(MartianPrimaryType::Map))).unwrap(),
+            serde_json::json!({"kind": "map", "values": null})
+        );
+    }
+}
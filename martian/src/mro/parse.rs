@@ -0,0 +1,706 @@
+//! Parser for the declarations in an mro file, e.g. the one generated by the
+//! `mro` subcommand of an adapter, so that it can be compared with the stages
+//! of the adapter. See [`MroFile`].
+use super::{
+    InAndOut, MartianBlanketType, MartianPrimaryType, MroField, MroUsing, StageMro, StructDef,
+    MARTIAN_TOKENS,
+};
+use anyhow::{bail, format_err, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::str::FromStr;
+
+/// The filetype, struct and stage declarations of an mro file.
+///
+/// ```rust
+/// use martian::MroFile;
+/// let mro: MroFile = r#"
+///     filetype txt;
+///
+///     stage SUM_SQUARES(
+///         in  float[] values,
+///         out txt     summary,
+///         src comp    "sum_sq martian sum_squares",
+///     ) using (
+///         mem_gb = 4,
+///     )
+/// "#
+/// .parse()?;
+/// assert_eq!(mro.stages[0].stage_key(), "sum_squares");
+/// # Ok::<(), martian::Error>(())
+/// ```
+///
+/// `@include` directives and pipeline declarations are skipped, as are the
+/// stages which are not run by an adapter, i.e. anything but
+/// `src comp "<adapter> martian <stage_key>"`, such as `src py` stages.
+#[derive(Debug, Default)]
+pub struct MroFile {
+    pub filetypes: Vec<String>,
+    pub structs: Vec<StructDef>,
+    pub stages: Vec<StageMro>,
+}
+
+impl MroFile {
+    pub fn parse(mro: &str) -> Result<MroFile> {
+        Parser {
+            tokens: tokenize(mro)?,
+            pos: 0,
+        }
+        .parse_file()
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<MroFile> {
+        let path = path.as_ref();
+        let mro = std::fs::read_to_string(path).with_context(|| path.display().to_string())?;
+        MroFile::parse(&mro).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+}
+
+impl FromStr for MroFile {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        MroFile::parse(s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// Keywords, names, types and numbers.
+    Word(String),
+    Str(String),
+    Punct(char),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "`{word}`"),
+            Token::Str(s) => write!(f, "\"{s}\""),
+            Token::Punct(c) => write!(f, "`{c}`"),
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-'
+}
+
+/// Split `mro` into tokens, along with the line they are on.
+fn tokenize(mro: &str) -> Result<Vec<(usize, Token)>> {
+    let mut tokens = Vec::new();
+    for (i, line) in mro.lines().enumerate() {
+        let line_no = i + 1;
+        let mut chars = line.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            match c {
+                '#' => break,
+                c if c.is_whitespace() => {}
+                '"' => {
+                    let mut s = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '"')) => break,
+                            Some((_, '\\')) => match chars.next() {
+                                Some((_, c)) => s.push(c),
+                                None => bail!("line {line_no}: unterminated string"),
+                            },
+                            Some((_, c)) => s.push(c),
+                            None => bail!("line {line_no}: unterminated string"),
+                        }
+                    }
+                    tokens.push((line_no, Token::Str(s)));
+                }
+                '(' | ')' | ',' | ';' | ':' | '=' | '[' | ']' | '<' | '>' | '@' | '{' | '}' => {
+                    tokens.push((line_no, Token::Punct(c)));
+                }
+                c if is_word_char(c) => {
+                    let mut end = start + c.len_utf8();
+                    while let Some(&(i, c)) = chars.peek() {
+                        if !is_word_char(c) {
+                            break;
+                        }
+                        end = i + c.len_utf8();
+                        chars.next();
+                    }
+                    tokens.push((line_no, Token::Word(line[start..end].to_string())));
+                }
+                c => bail!("line {line_no}: unexpected character `{c}`"),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// A struct or stage before the names of the structs and filetypes it refers
+/// to are resolved. Until then, every type which is not a builtin is parsed
+/// as a `FileType`.
+struct RawStage {
+    mro: StageMro,
+    line: usize,
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or(self.tokens.last())
+            .map_or(0, |(line, _)| *line)
+    }
+
+    fn next(&mut self, expected: &str) -> Result<Token> {
+        match self.tokens.get(self.pos) {
+            Some((_, token)) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => bail!(
+                "line {}: expected {expected}, found the end of the file",
+                self.line()
+            ),
+        }
+    }
+
+    fn unexpected<T>(&self, expected: &str, found: &Token) -> Result<T> {
+        // The token was already consumed.
+        let line = self.tokens[self.pos - 1].0;
+        bail!("line {line}: expected {expected}, found {found}")
+    }
+
+    fn eat_punct(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_punct(&mut self, c: char) -> Result<()> {
+        let expected = format!("`{c}`");
+        match self.next(&expected)? {
+            Token::Punct(p) if p == c => Ok(()),
+            token => self.unexpected(&expected, &token),
+        }
+    }
+
+    fn expect_word(&mut self, expected: &str) -> Result<String> {
+        match self.next(expected)? {
+            Token::Word(word) => Ok(word),
+            token => self.unexpected(expected, &token),
+        }
+    }
+
+    fn eat_str(&mut self) -> Option<String> {
+        match self.peek() {
+            Some(Token::Str(s)) => {
+                let s = s.clone();
+                self.pos += 1;
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse `( item, item, ... )`, with an optional trailing comma.
+    fn parse_list(&mut self, mut item: impl FnMut(&mut Self) -> Result<()>) -> Result<()> {
+        self.expect_punct('(')?;
+        while !self.eat_punct(')') {
+            item(self)?;
+            if !self.eat_punct(',') {
+                return self.expect_punct(')');
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_file(mut self) -> Result<MroFile> {
+        let mut filetypes = Vec::new();
+        let mut structs: Vec<(StructDef, usize)> = Vec::new();
+        let mut stages = Vec::new();
+        while self.peek().is_some() {
+            let line = self.line();
+            match self.next("a declaration")? {
+                Token::Punct('@') => {
+                    let directive = self.expect_word("a directive")?;
+                    if directive != "include" {
+                        bail!("line {line}: unknown directive @{directive}");
+                    }
+                    if self.eat_str().is_none() {
+                        bail!("line {line}: expected the file name after @include");
+                    }
+                }
+                Token::Word(word) if word == "filetype" => {
+                    filetypes.push(self.expect_word("a filetype")?);
+                    self.expect_punct(';')?;
+                }
+                Token::Word(word) if word == "struct" => {
+                    let name = self.expect_word("the name of the struct")?;
+                    let mut fields = Vec::new();
+                    self.parse_list(|p| {
+                        fields.push(p.parse_field()?);
+                        Ok(())
+                    })?;
+                    structs.push((StructDef::new(name, fields), line));
+                }
+                Token::Word(word) if word == "stage" => stages.extend(self.parse_stage(line)?),
+                Token::Word(word) if word == "pipeline" => self.skip_pipeline()?,
+                token => self.unexpected("a filetype, struct or stage declaration", &token)?,
+            }
+        }
+        Resolver::new(filetypes, structs)?.resolve(stages)
+    }
+
+    /// Skip over a pipeline declaration, up to the closing `}` of its body.
+    fn skip_pipeline(&mut self) -> Result<()> {
+        self.expect_word("the name of the pipeline")?;
+        while !self.eat_punct('{') {
+            self.next("`{`")?;
+        }
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next("`}`")? {
+                Token::Punct('{') => depth += 1,
+                Token::Punct('}') => depth -= 1,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_type(&mut self) -> Result<MartianBlanketType> {
+        let name = self.expect_word("a type")?;
+        let mut ty = if name == "map" && self.eat_punct('<') {
+            let inner = self.parse_type()?;
+            self.expect_punct('>')?;
+            MartianBlanketType::TypedMap(Box::new(inner))
+        } else {
+            MartianBlanketType::Primary(name.parse().unwrap_or(MartianPrimaryType::FileType(name)))
+        };
+        while self.eat_punct('[') {
+            self.expect_punct(']')?;
+            ty = MartianBlanketType::Array(Box::new(ty));
+        }
+        Ok(ty)
+    }
+
+    /// `type name ["description"] ["output file name"]`
+    fn parse_field(&mut self) -> Result<MroField> {
+        let ty = self.parse_type()?;
+        let line = self.line();
+        let name = self.expect_word("the name of the field")?;
+        if MARTIAN_TOKENS.contains(&name.as_str()) || name.starts_with("__") {
+            bail!("line {line}: {name} cannot be used as a field name");
+        }
+        let desc = self.eat_str();
+        let mro_filename = self.eat_str();
+        Ok(MroField {
+            name,
+            ty,
+            desc,
+            mro_filename,
+            retain: false,
+            optional: false,
+        })
+    }
+
+    /// The fields of the stage or of its split, and the language and command
+    /// of its `src`.
+    fn parse_params(&mut self, mut src: Option<&mut Option<(String, String)>>) -> Result<InAndOut> {
+        let mut params = InAndOut::default();
+        self.parse_list(|p| {
+            let line = p.line();
+            match p.expect_word("`in`, `out` or `src`")?.as_str() {
+                "in" => params.inputs.push(p.parse_field()?),
+                "out" => params.outputs.push(p.parse_field()?),
+                "src" if src.is_some() => {
+                    let lang = p.expect_word("the language of the stage")?;
+                    let Some(cmd) = p.eat_str() else {
+                        bail!("line {line}: expected the command of the stage");
+                    };
+                    **src.as_mut().unwrap() = Some((lang, cmd));
+                }
+                word => bail!("line {line}: expected `in`, `out` or `src`, found `{word}`"),
+            }
+            Ok(())
+        })?;
+        Ok(params)
+    }
+
+    /// Parse a stage declaration, which is `None` if the stage is not run by
+    /// an adapter.
+    fn parse_stage(&mut self, line: usize) -> Result<Option<RawStage>> {
+        let stage_name = self.expect_word("the name of the stage")?;
+        let mut src = None;
+        let mut stage_in_out = self.parse_params(Some(&mut src))?;
+        let Some((lang, cmd)) = src else {
+            bail!("line {line}: stage {stage_name} has no src");
+        };
+
+        let mut chunk_in_out = None;
+        let mut using_attrs = MroUsing::default();
+        let mut retain = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Word(word)) if word == "split" && chunk_in_out.is_none() => {
+                    self.pos += 1;
+                    // Older mros write `split using (`.
+                    if self.peek() == Some(&Token::Word("using".into())) {
+                        self.pos += 1;
+                    }
+                    chunk_in_out = Some(self.parse_params(None)?);
+                }
+                Some(Token::Word(word)) if word == "using" => {
+                    self.pos += 1;
+                    self.parse_list(|p| {
+                        let line = p.line();
+                        let key = p.expect_word("a property")?;
+                        p.expect_punct('=')?;
+                        let value = match p.next("a value")? {
+                            Token::Word(value) | Token::Str(value) => value,
+                            token => return p.unexpected("a value", &token),
+                        };
+                        using_attrs
+                            .set(&key, &value)
+                            .with_context(|| format!("line {line}"))
+                    })?;
+                }
+                Some(Token::Word(word)) if word == "retain" => {
+                    self.pos += 1;
+                    self.parse_list(|p| {
+                        retain.push((p.line(), p.expect_word("the name of an output")?));
+                        Ok(())
+                    })?;
+                }
+                _ => break,
+            }
+        }
+        for (line, name) in retain {
+            match stage_in_out.outputs.iter_mut().find(|f| f.name == name) {
+                Some(field) => field.retain = true,
+                None => bail!("line {line}: {stage_name} has no output {name} to retain"),
+            }
+        }
+
+        let words: Vec<_> = cmd.split_whitespace().collect();
+        let (adapter_name, stage_key) = match (lang.as_str(), words.as_slice()) {
+            ("comp", [adapter, "martian", stage_key]) => {
+                (adapter.to_string(), stage_key.to_string())
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(RawStage {
+            mro: StageMro {
+                stage_name,
+                adapter_name,
+                stage_key,
+                stage_in_out,
+                chunk_in_out,
+                using_attrs,
+            },
+            line,
+        }))
+    }
+}
+
+/// Replaces the names of structs and filetypes in the parsed types with their
+/// definitions.
+struct Resolver {
+    filetypes: HashSet<String>,
+    raw_structs: HashMap<String, StructDef>,
+    structs: HashMap<String, StructDef>,
+    /// Structs being resolved, to detect recursive definitions.
+    visiting: Vec<String>,
+}
+
+impl Resolver {
+    fn new(filetypes: Vec<String>, structs: Vec<(StructDef, usize)>) -> Result<Self> {
+        let mut raw_structs = HashMap::new();
+        for (def, line) in structs {
+            if raw_structs.contains_key(&def.name) {
+                bail!("line {line}: struct {} is declared twice", def.name);
+            }
+            raw_structs.insert(def.name.clone(), def);
+        }
+        Ok(Resolver {
+            filetypes: filetypes.into_iter().collect(),
+            raw_structs,
+            structs: HashMap::new(),
+            visiting: Vec::new(),
+        })
+    }
+
+    fn resolve(mut self, stages: Vec<RawStage>) -> Result<MroFile> {
+        let mut struct_names: Vec<_> = self.raw_structs.keys().cloned().collect();
+        struct_names.sort();
+        let structs = struct_names
+            .iter()
+            .map(|name| self.resolve_struct(name))
+            .collect::<Result<_>>()?;
+
+        let mut names = HashSet::new();
+        let stages = stages
+            .into_iter()
+            .map(|RawStage { mut mro, line }| {
+                if !names.insert(mro.stage_name.clone()) {
+                    bail!("line {line}: stage {} is declared twice", mro.stage_name);
+                }
+                let context = || format!("line {line}: in stage {}", mro.stage_name);
+                self.resolve_params(&mut mro.stage_in_out)
+                    .with_context(context)?;
+                if let Some(chunk_in_out) = mro.chunk_in_out.as_mut() {
+                    self.resolve_params(chunk_in_out).with_context(context)?;
+                }
+                Ok(mro)
+            })
+            .collect::<Result<_>>()?;
+
+        let mut filetypes: Vec<_> = self.filetypes.into_iter().collect();
+        filetypes.sort();
+        Ok(MroFile {
+            filetypes,
+            structs,
+            stages,
+        })
+    }
+
+    fn resolve_params(&mut self, params: &mut InAndOut) -> Result<()> {
+        for field in params.inputs.iter_mut().chain(params.outputs.iter_mut()) {
+            field.ty = self.resolve_type(&field.ty)?;
+        }
+        Ok(())
+    }
+
+    fn resolve_struct(&mut self, name: &str) -> Result<StructDef> {
+        if let Some(def) = self.structs.get(name) {
+            return Ok(def.clone());
+        }
+        if self.visiting.iter().any(|n| n == name) {
+            bail!("struct {name} contains itself");
+        }
+        self.visiting.push(name.to_string());
+        let mut def = self.raw_structs[name].clone();
+        for field in &mut def.fields {
+            field.ty = self
+                .resolve_type(&field.ty)
+                .with_context(|| format!("in struct {name}"))?;
+        }
+        self.visiting.pop();
+        self.structs.insert(name.to_string(), def.clone());
+        Ok(def)
+    }
+
+    fn resolve_type(&mut self, ty: &MartianBlanketType) -> Result<MartianBlanketType> {
+        Ok(match ty {
+            MartianBlanketType::Primary(MartianPrimaryType::FileType(name)) => {
+                if self.raw_structs.contains_key(name) {
+                    MartianBlanketType::Primary(MartianPrimaryType::Struct(
+                        self.resolve_struct(name)?,
+                    ))
+                } else if self.filetypes.contains(name) {
+                    ty.clone()
+                } else {
+                    return Err(format_err!(
+                        "unknown type {name}, which is not a builtin type, \
+                         a declared filetype or a struct"
+                    ));
+                }
+            }
+            MartianBlanketType::Primary(_) => ty.clone(),
+            MartianBlanketType::Array(inner) => {
+                MartianBlanketType::Array(Box::new(self.resolve_type(inner)?))
+            }
+            MartianBlanketType::TypedMap(inner) => {
+                MartianBlanketType::TypedMap(Box::new(self.resolve_type(inner)?))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mro::Volatile;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use MartianBlanketType::{Array, Primary, TypedMap};
+    use MartianPrimaryType::{FileType, Float, Int, Path, Str, Struct};
+
+    #[test]
+    fn test_parse_mro() -> Result<()> {
+        let mro = MroFile::parse(indoc!(
+            r#"
+            #
+            # Code generated by sum_sq.  DO NOT EDIT.
+            #
+            @include "other.mro"
+
+            filetype fastq.gz;
+            filetype json;
+
+            struct ReadChunk(
+                Sample   sample,
+                fastq.gz r1     "Read 1" "r1.fastq.gz",
+            )
+
+            struct Sample(
+                string name,
+            )
+
+            stage SETUP_CHUNKS(
+                in  ReadChunk[] chunks,
+                in  map<int[]>  counts,
+                out json        summary "The summary",
+                out path        out_dir,
+                src comp        "my_adapter martian setup_chunks",
+            ) split (
+                in  int         chunk_id,
+                out float       value,
+            ) using (
+                mem_gb   = 4,
+                special  = "large",
+                volatile = strict,
+            ) retain (
+                summary,
+            )
+
+            pipeline SETUP(
+                in  ReadChunk[] chunks,
+                out json        summary,
+            )
+            {
+                call SETUP_CHUNKS(
+                    chunks = self.chunks,
+                    counts = {"a": [1]},
+                )
+
+                return (
+                    summary = SETUP_CHUNKS.summary,
+                )
+            }
+            "#
+        ))?;
+
+        assert_eq!(mro.filetypes, ["fastq.gz", "json"]);
+        let sample = StructDef::new(
+            "Sample".into(),
+            vec![MroField::new("name", Primary(Str), None, None)],
+        );
+        let read_chunk = StructDef::new(
+            "ReadChunk".into(),
+            vec![
+                MroField::new("sample", Primary(Struct(sample.clone())), None, None),
+                MroField::new(
+                    "r1",
+                    Primary(FileType("fastq.gz".into())),
+                    Some("Read 1".into()),
+                    Some("r1.fastq.gz".into()),
+                ),
+            ],
+        );
+        assert_eq!(mro.structs, [read_chunk.clone(), sample]);
+
+        let [stage] = mro.stages.as_slice() else {
+            panic!("expected one stage, found {:?}", mro.stages);
+        };
+        assert_eq!(stage.stage_name(), "SETUP_CHUNKS");
+        assert_eq!(stage.adapter_name(), "my_adapter");
+        assert_eq!(stage.stage_key(), "setup_chunks");
+        assert_eq!(
+            stage.stage_in_out.inputs,
+            [
+                MroField::new("chunks", Array(Struct(read_chunk).into()), None, None),
+                MroField::new("counts", TypedMap(Box::new(Array(Int.into()))), None, None),
+            ]
+        );
+        assert_eq!(
+            stage.stage_in_out.outputs,
+            [
+                MroField::retained(
+                    "summary",
+                    Primary(FileType("json".into())),
+                    Some("The summary".into()),
+                    None
+                ),
+                MroField::new("out_dir", Primary(Path), None, None),
+            ]
+        );
+        let chunk = stage.chunk_in_out.as_ref().unwrap();
+        assert_eq!(chunk.outputs[0].ty, Primary(Float));
+        assert_eq!(stage.using_attrs.mem_gb, Some(4));
+        assert_eq!(stage.using_attrs.special.as_deref(), Some("large"));
+        assert_eq!(stage.using_attrs.volatile, Some(Volatile::Strict));
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_non_adapter_stages() -> Result<()> {
+        let mro: MroFile = indoc! {r#"
+            stage PY_STAGE(
+                in  bam   reads,
+                out int   count,
+                src py    "stages/py_stage",
+            ) split (
+                in  int   chunk,
+            )
+
+            stage EXEC_STAGE(
+                in  int   count,
+                src exec  "bin/exec_stage",
+            )
+
+            stage RUST_STAGE(
+                in  int   count,
+                src comp  "my_adapter martian rust_stage",
+            )
+
+            stage OTHER_COMP_STAGE(
+                in  int   count,
+                src comp  "bin/other_stage",
+            )
+        "#}
+        .parse()?;
+        let stages: Vec<_> = mro.stages.iter().map(|s| s.stage_key()).collect();
+        assert_eq!(stages, ["rust_stage"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_mro_errors() {
+        let err = |mro: &str| format!("{:#}", MroFile::parse(mro).unwrap_err());
+        assert_eq!(
+            err("stage A(\n    in int x,\n    src comp \"a martian a\",\n) using (\n    cpus = 2,\n)"),
+            "line 5: Unknown property cpus in the using section"
+        );
+        assert_eq!(
+            err("stage A(\n    in bam x,\n    src comp \"a martian a\",\n)"),
+            "line 1: in stage A: unknown type bam, which is not a builtin type, \
+             a declared filetype or a struct"
+        );
+        assert_eq!(
+            err("stage A(\n    in int x\n    in int y,\n)"),
+            "line 3: expected `)`, found `in`"
+        );
+        assert_eq!(
+            err("pipeline P(\n)\n{\n    call A(\n"),
+            "line 4: expected `}`, found the end of the file"
+        );
+        assert_eq!(
+            err("struct A(\n    A a,\n)"),
+            "in struct A: struct A contains itself"
+        );
+        assert_eq!(
+            err("stage A(\n    in int x,\n"),
+            "line 2: expected `in`, `out` or `src`, found the end of the file"
+        );
+    }
+}
@@ -0,0 +1,569 @@
+//! Declarations of the pipelines which call the stages of an adapter, so that
+//! the bindings between stages are checked against the types of their inputs
+//! and outputs. See [`PipelineBuilder`].
+use super::{
+    AsMartianBlanketType, FiletypeHeader, InAndOut, MartianBlanketType, MartianPrimaryType,
+    MartianStruct, MroDisplay, MroField, MroMaker, StructHeader, TAB_WIDTH_FOR_MRO,
+};
+use anyhow::{bail, ensure, Context, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt::Display;
+
+/// The value bound to an input of a call, or to an output of the pipeline.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Binding {
+    /// An input of the pipeline, `self.<field>`
+    Input(String),
+    /// An output of a call in the pipeline, `<CALL>.<field>`
+    Output {
+        call: String,
+        field: String,
+    },
+    /// A literal value of the given type.
+    Value {
+        value: serde_json::Value,
+        ty: MartianBlanketType,
+    },
+    Null,
+}
+
+impl Binding {
+    /// Bind the input `field` of the pipeline.
+    pub fn input(field: impl ToString) -> Self {
+        Binding::Input(field.to_string())
+    }
+
+    /// Bind the output `field` of the call `call`, which is the name of the
+    /// stage or pipeline called unless the call has an alias.
+    pub fn output(call: impl ToString, field: impl ToString) -> Self {
+        Binding::Output {
+            call: call.to_string(),
+            field: field.to_string(),
+        }
+    }
+
+    /// Bind a literal value, whose mro type is that of `T`.
+    pub fn value<T: Serialize + AsMartianBlanketType>(value: &T) -> Result<Self> {
+        Ok(Binding::Value {
+            value: serde_json::to_value(value)?,
+            ty: T::as_martian_blanket_type(),
+        })
+    }
+}
+
+impl Display for Binding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Binding::Input(field) => write!(f, "self.{field}"),
+            Binding::Output { call, field } => write!(f, "{call}.{field}"),
+            Binding::Value { value, .. } => write!(f, "{value}"),
+            Binding::Null => write!(f, "null"),
+        }
+    }
+}
+
+/// A call to a stage or a pipeline within a [`PipelineBuilder`].
+///
+/// ```rust
+/// # use martian::{Binding, PipelineCall, PipelineMro};
+/// # let sum_squares = PipelineMro::builder("SUM_SQUARES").build()?;
+/// let call = PipelineCall::pipeline(&sum_squares)
+///     .alias("SUM_SQUARES_AGAIN")
+///     .bind("values", Binding::output("SQUARE", "squares"));
+/// # Ok::<(), martian::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct PipelineCall {
+    callee: String,
+    alias: Option<String>,
+    callee_in_out: InAndOut,
+    bindings: Vec<(String, Binding)>,
+}
+
+impl PipelineCall {
+    /// Call the stage `S`.
+    pub fn stage<S: MroMaker>() -> Self {
+        PipelineCall {
+            callee: S::stage_name().to_string(),
+            alias: None,
+            callee_in_out: S::stage_in_and_out(),
+            bindings: Vec::new(),
+        }
+    }
+
+    /// Call another pipeline, once it has been checked.
+    pub fn pipeline(pipeline: &PipelineMro) -> Self {
+        PipelineCall {
+            callee: pipeline.name.clone(),
+            alias: None,
+            callee_in_out: pipeline.in_out.clone(),
+            bindings: Vec::new(),
+        }
+    }
+
+    /// Call the stage or pipeline under a different name, which is needed
+    /// to call it more than once in a pipeline.
+    pub fn alias(self, alias: impl ToString) -> Self {
+        PipelineCall {
+            alias: Some(alias.to_string()),
+            ..self
+        }
+    }
+
+    /// Bind the input `field` of the stage or pipeline called.
+    pub fn bind(mut self, field: impl ToString, binding: Binding) -> Self {
+        self.bindings.push((field.to_string(), binding));
+        self
+    }
+
+    /// The name through which the outputs of this call are bound.
+    pub fn name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.callee)
+    }
+}
+
+/// Builds a pipeline declaration, which calls stages and other pipelines and
+/// binds their inputs to the inputs of the pipeline or the outputs of earlier
+/// calls.
+///
+/// Bindings refer to the fields by name, and [`PipelineBuilder::build`]
+/// checks that the fields exist and that the types of both sides match, so
+/// that a renamed or retyped field in a stage is caught when the mro is
+/// generated rather than when the pipeline runs. Only the checked
+/// [`PipelineMro`] can be written to the mro.
+///
+/// ```ignore
+/// use martian::{Binding, PipelineCall, PipelineMro};
+///
+/// // SumSquares and Report are stages declared with #[make_mro]
+/// let pipeline = PipelineMro::builder("SUM_SQUARES_PIPELINE")
+///     .inputs::<SumSquaresStageInputs>()
+///     .output::<f64>("sum")
+///     .call(PipelineCall::stage::<SumSquares>().bind("values", Binding::input("values")))
+///     .call(PipelineCall::stage::<Report>().bind("sum", Binding::output("SUM_SQUARES", "sum")))
+///     .returns("sum", Binding::output("SUM_SQUARES", "sum"))
+///     .build()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct PipelineBuilder {
+    name: String,
+    in_out: InAndOut,
+    calls: Vec<PipelineCall>,
+    returns: Vec<(String, Binding)>,
+}
+
+impl PipelineBuilder {
+    pub fn new(name: impl ToString) -> Self {
+        PipelineBuilder {
+            name: name.to_string(),
+            in_out: InAndOut::default(),
+            calls: Vec::new(),
+            returns: Vec::new(),
+        }
+    }
+
+    /// Add an input `name` to the pipeline with the mro type of `T`.
+    pub fn input<T: AsMartianBlanketType>(mut self, name: impl ToString) -> Self {
+        let field = MroField::new(name, T::as_martian_blanket_type(), None, None);
+        self.in_out.inputs.push(field);
+        self
+    }
+
+    /// Add an output `name` to the pipeline with the mro type of `T`.
+    pub fn output<T: AsMartianBlanketType>(mut self, name: impl ToString) -> Self {
+        let field = MroField::new(name, T::as_martian_blanket_type(), None, None);
+        self.in_out.outputs.push(field);
+        self
+    }
+
+    /// Add the fields of `T` as inputs of the pipeline, e.g. those of the
+    /// `StageInputs` of its first stage.
+    pub fn inputs<T: MartianStruct>(mut self) -> Self {
+        self.in_out.inputs.extend(T::mro_fields());
+        self
+    }
+
+    /// Add the fields of `T` as outputs of the pipeline.
+    pub fn outputs<T: MartianStruct>(mut self) -> Self {
+        self.in_out.outputs.extend(T::mro_fields());
+        self
+    }
+
+    /// Add a call to the pipeline. The outputs of a call can only be bound
+    /// by the calls which follow it.
+    pub fn call(mut self, call: PipelineCall) -> Self {
+        self.calls.push(call);
+        self
+    }
+
+    /// Bind the output `field` of the pipeline.
+    pub fn returns(mut self, field: impl ToString, binding: Binding) -> Self {
+        self.returns.push((field.to_string(), binding));
+        self
+    }
+
+    /// Check that every input of each call and every output of the pipeline
+    /// is bound exactly once, to a field of the same type.
+    pub fn build(self) -> Result<PipelineMro> {
+        let mut names = HashSet::new();
+        for (i, call) in self.calls.iter().enumerate() {
+            ensure!(
+                names.insert(call.name()),
+                "{} is called twice in pipeline {}, use an alias to tell the calls apart",
+                call.name(),
+                self.name
+            );
+            self.check_bindings(&call.bindings, &call.callee_in_out.inputs, &self.calls[..i])
+                .with_context(|| format!("in call {} of pipeline {}", call.name(), self.name))?;
+        }
+        self.check_bindings(&self.returns, &self.in_out.outputs, &self.calls)
+            .with_context(|| format!("in the return of pipeline {}", self.name))?;
+        let PipelineBuilder {
+            name,
+            in_out,
+            calls,
+            returns,
+        } = self;
+        Ok(PipelineMro {
+            name,
+            in_out,
+            calls,
+            returns,
+        })
+    }
+
+    /// Check `bindings` against `fields`, where outputs may be bound from `calls`.
+    fn check_bindings(
+        &self,
+        bindings: &[(String, Binding)],
+        fields: &[MroField],
+        calls: &[PipelineCall],
+    ) -> Result<()> {
+        let mut bound = HashSet::new();
+        for (name, binding) in bindings {
+            let Some(field) = fields.iter().find(|f| &f.name == name) else {
+                bail!("there is no field `{name}` to bind");
+            };
+            ensure!(bound.insert(name), "`{name}` is bound twice");
+            let ty = match binding {
+                Binding::Input(input) => self
+                    .in_out
+                    .inputs
+                    .iter()
+                    .find(|f| &f.name == input)
+                    .map(MroField::ty)
+                    .with_context(|| {
+                        format!(
+                            "`{name}` is bound to {binding}, which is not an input of the pipeline"
+                        )
+                    })?,
+                Binding::Output { call, field } => calls
+                    .iter()
+                    .find(|c| c.name() == call)
+                    .with_context(|| {
+                        format!("`{name}` is bound to {binding}, but {call} is not called before")
+                    })?
+                    .callee_in_out
+                    .outputs
+                    .iter()
+                    .find(|f| &f.name == field)
+                    .map(MroField::ty)
+                    .with_context(|| {
+                        format!("`{name}` is bound to {binding}, which is not an output of {call}")
+                    })?,
+                Binding::Value { ty, .. } => ty,
+                Binding::Null => continue,
+            };
+            ensure!(
+                assignable(ty, &field.ty),
+                "`{name}` has type {} but is bound to {binding}, which has type {ty}",
+                field.ty
+            );
+        }
+        for field in fields {
+            ensure!(bound.contains(&field.name), "`{}` is not bound", field.name);
+        }
+        Ok(())
+    }
+}
+
+/// A pipeline declaration whose bindings have been checked by
+/// [`PipelineBuilder::build`], ready to be written to the mro.
+#[derive(Debug, Clone)]
+pub struct PipelineMro {
+    name: String,
+    in_out: InAndOut,
+    calls: Vec<PipelineCall>,
+    returns: Vec<(String, Binding)>,
+}
+
+impl PipelineMro {
+    /// Start declaring the pipeline `name`, see [`PipelineBuilder`].
+    pub fn builder(name: impl ToString) -> PipelineBuilder {
+        PipelineBuilder::new(name)
+    }
+
+    /// Name of the pipeline in the mro.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn iter_mro_fields(&self) -> impl Iterator<Item = &MroField> {
+        self.in_out.iter_mro_fields()
+    }
+}
+
+/// Whether a value of type `from` can be bound to a field of type `to`. Ints
+/// are converted to floats, as in martian.
+fn assignable(from: &MartianBlanketType, to: &MartianBlanketType) -> bool {
+    use MartianBlanketType::{Array, Primary, TypedMap};
+    match (from, to) {
+        (Primary(MartianPrimaryType::Int), Primary(MartianPrimaryType::Float)) => true,
+        (Array(from), Array(to)) | (TypedMap(from), TypedMap(to)) => assignable(from, to),
+        (from, to) => from == to,
+    }
+}
+
+/// Write `(name = binding, ...)` with the names aligned, one per line.
+fn fmt_bindings(
+    f: &mut std::fmt::Formatter<'_>,
+    bindings: &[(String, Binding)],
+    indent: usize,
+) -> std::fmt::Result {
+    if bindings.is_empty() {
+        return writeln!(f, "()");
+    }
+    writeln!(f, "(")?;
+    let width = bindings.iter().map(|(name, _)| name.len()).max().unwrap();
+    for (name, binding) in bindings {
+        writeln!(
+            f,
+            "{blank:indent$}{name:<width$} = {binding},",
+            blank = "",
+            indent = indent + TAB_WIDTH_FOR_MRO
+        )?;
+    }
+    writeln!(f, "{blank:indent$})", blank = "")
+}
+
+impl MroDisplay for PipelineMro {
+    fn min_width(&self) -> usize {
+        0
+    }
+}
+
+impl Display for PipelineMro {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "pipeline {}(", self.name)?;
+        write!(f, "{}", self.in_out)?;
+        writeln!(f, ")")?;
+        writeln!(f, "{{")?;
+        for call in &self.calls {
+            match call.alias {
+                Some(ref alias) => write!(
+                    f,
+                    "{blank:TAB_WIDTH_FOR_MRO$}call {} as {alias}",
+                    call.callee,
+                    blank = ""
+                )?,
+                None => write!(
+                    f,
+                    "{blank:TAB_WIDTH_FOR_MRO$}call {}",
+                    call.callee,
+                    blank = ""
+                )?,
+            }
+            fmt_bindings(f, &call.bindings, TAB_WIDTH_FOR_MRO)?;
+            writeln!(f)?;
+        }
+        write!(f, "{blank:TAB_WIDTH_FOR_MRO$}return ", blank = "")?;
+        fmt_bindings(f, &self.returns, TAB_WIDTH_FOR_MRO)?;
+        writeln!(f, "}}")
+    }
+}
+
+impl FiletypeHeader {
+    /// Add the filetypes of the inputs and outputs of a pipeline.
+    pub fn add_pipeline(&mut self, pipeline: &PipelineMro) {
+        for field in pipeline.iter_mro_fields() {
+            self.add_mro_field(field);
+        }
+    }
+}
+
+impl StructHeader {
+    /// Add the structs of the inputs and outputs of a pipeline.
+    pub fn add_pipeline(&mut self, pipeline: &PipelineMro) {
+        for field in pipeline.iter_mro_fields() {
+            self.add_mro_field(field);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MartianPrimaryType, MroUsing};
+
+    struct Square;
+    impl MroMaker for Square {
+        fn stage_name() -> &'static str {
+            "SQUARE"
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut {
+                inputs: vec![MroField::new(
+                    "values",
+                    MartianBlanketType::Array(MartianPrimaryType::Float.into()),
+                    None,
+                    None,
+                )],
+                outputs: vec![MroField::new(
+                    "squares",
+                    MartianBlanketType::Array(MartianPrimaryType::Float.into()),
+                    None,
+                    None,
+                )],
+            }
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    struct Sum;
+    impl MroMaker for Sum {
+        fn stage_name() -> &'static str {
+            "SUM"
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut {
+                inputs: vec![
+                    MroField::new(
+                        "values",
+                        MartianBlanketType::Array(MartianPrimaryType::Float.into()),
+                        None,
+                        None,
+                    ),
+                    MroField::new("scale", MartianPrimaryType::Float.into(), None, None),
+                ],
+                outputs: vec![MroField::new(
+                    "sum",
+                    MartianPrimaryType::Float.into(),
+                    None,
+                    None,
+                )],
+            }
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    fn pipeline() -> PipelineBuilder {
+        PipelineMro::builder("SUM_SQUARES")
+            .input::<Vec<i32>>("values")
+            .output::<f64>("sum")
+            .call(PipelineCall::stage::<Square>().bind("values", Binding::input("values")))
+            .call(
+                PipelineCall::stage::<Sum>()
+                    .alias("SUM_SQUARES_SCALED")
+                    .bind("values", Binding::output("SQUARE", "squares"))
+                    .bind("scale", Binding::value(&2).unwrap()),
+            )
+            .returns("sum", Binding::output("SUM_SQUARES_SCALED", "sum"))
+    }
+
+    #[test]
+    fn test_pipeline_mro() {
+        let pipeline = pipeline().build().unwrap();
+        assert_eq!(
+            pipeline.to_string(),
+            "pipeline SUM_SQUARES(
+    in  int[] values,
+    out float sum,
+)
+{
+    call SQUARE(
+        values = self.values,
+    )
+
+    call SUM as SUM_SQUARES_SCALED(
+        values = SQUARE.squares,
+        scale  = 2,
+    )
+
+    return (
+        sum = SUM_SQUARES_SCALED.sum,
+    )
+}
+"
+        );
+        let nested = PipelineMro::builder("OUTER")
+            .call(PipelineCall::pipeline(&pipeline).bind("values", Binding::Null))
+            .build()
+            .unwrap();
+        assert_eq!(
+            nested.to_string(),
+            "pipeline OUTER(
+)
+{
+    call SUM_SQUARES(
+        values = null,
+    )
+
+    return ()
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_pipeline_mro_errors() {
+        let err = |pipeline: PipelineBuilder| format!("{:#}", pipeline.build().unwrap_err());
+        assert_eq!(
+            err(pipeline().call(PipelineCall::stage::<Square>().bind("values", Binding::Null))),
+            "SQUARE is called twice in pipeline SUM_SQUARES, use an alias to tell the calls apart"
+        );
+        assert_eq!(
+            err(PipelineMro::builder("P")
+                .input::<Vec<String>>("values")
+                .call(PipelineCall::stage::<Square>().bind("values", Binding::input("values")))),
+            "in call SQUARE of pipeline P: `values` has type float[] but is bound to \
+             self.values, which has type string[]"
+        );
+        assert_eq!(
+            err(PipelineMro::builder("P")
+                .call(PipelineCall::stage::<Square>().bind("value", Binding::Null))),
+            "in call SQUARE of pipeline P: there is no field `value` to bind"
+        );
+        assert_eq!(
+            err(PipelineMro::builder("P").call(PipelineCall::stage::<Square>())),
+            "in call SQUARE of pipeline P: `values` is not bound"
+        );
+        assert_eq!(
+            err(PipelineMro::builder("P").call(
+                PipelineCall::stage::<Square>().bind("values", Binding::output("SUM", "sum"))
+            )),
+            "in call SQUARE of pipeline P: `values` is bound to SUM.sum, but SUM is not called before"
+        );
+        assert_eq!(
+            err(pipeline().returns("sum", Binding::output("SQUARE", "sum"))),
+            "in the return of pipeline SUM_SQUARES: `sum` is bound twice"
+        );
+        assert_eq!(
+            err(PipelineMro::builder("P")
+                .output::<f64>("sum")
+                .call(PipelineCall::stage::<Square>().bind("values", Binding::Null))
+                .returns("sum", Binding::output("SQUARE", "sum"))),
+            "in the return of pipeline P: `sum` is bound to SQUARE.sum, which is not an output of SQUARE"
+        );
+    }
+}
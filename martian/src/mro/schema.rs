@@ -0,0 +1,196 @@
+//! JSON Schema for the inputs and outputs of a stage, e.g. to validate the
+//! arguments of an invocation before starting `mrp`.
+//!
+//! The mro types map to schema types as follows:
+//! - `int`, `float`, `bool` and `string` to `integer`, `number`, `boolean`
+//!   and `string`.
+//! - `path`, `file` and filetypes to `string`.
+//! - `T[]` to an `array` with items `T`.
+//! - `map<T>` to an `object` with `additionalProperties` `T`, and `map` to
+//!   any `object`.
+//! - Structs to an `object` in `$defs`, with a property for each field.
+//!
+//! Every field is required unless it is an `Option` in Rust, in which case it
+//! may also be null. Doc comments of the fields are their descriptions.
+use super::{MartianBlanketType, MartianPrimaryType, MroField, StageMro, StructDef};
+use serde_json::{json, Map, Value};
+
+const DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// The schema of an object with the given `fields`, e.g. the `mro_fields()`
+/// of a `MartianStruct`.
+pub fn json_schema(title: Option<&str>, fields: &[MroField]) -> Value {
+    let mut defs = Map::new();
+    let mut schema = Map::new();
+    schema.insert("$schema".into(), DIALECT.into());
+    if let Some(title) = title {
+        schema.insert("title".into(), title.into());
+    }
+    schema.extend(object_schema(fields, &mut defs));
+    if !defs.is_empty() {
+        schema.insert("$defs".into(), defs.into());
+    }
+    schema.into()
+}
+
+impl StageMro {
+    /// The schema of the arguments of the stage, see [`json_schema`].
+    pub fn inputs_json_schema(&self) -> Value {
+        json_schema(
+            Some(&format!("{} inputs", self.stage_name)),
+            &self.stage_in_out.inputs,
+        )
+    }
+
+    /// The schema of the outputs of the stage, see [`json_schema`].
+    pub fn outputs_json_schema(&self) -> Value {
+        json_schema(
+            Some(&format!("{} outputs", self.stage_name)),
+            &self.stage_in_out.outputs,
+        )
+    }
+}
+
+fn object_schema(fields: &[MroField], defs: &mut Map<String, Value>) -> Map<String, Value> {
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    for field in fields {
+        properties.insert(field.name.clone(), field_schema(field, defs));
+        if !field.optional {
+            required.push(Value::from(field.name.clone()));
+        }
+    }
+    let mut schema = Map::new();
+    schema.insert("type".into(), "object".into());
+    schema.insert("properties".into(), properties.into());
+    schema.insert("required".into(), required.into());
+    schema
+}
+
+fn field_schema(field: &MroField, defs: &mut Map<String, Value>) -> Value {
+    let schema = type_schema(&field.ty, defs);
+    let mut schema = if field.optional {
+        let mut nullable = Map::new();
+        nullable.insert("anyOf".into(), json!([schema, {"type": "null"}]));
+        nullable
+    } else {
+        match schema {
+            Value::Object(schema) => schema,
+            _ => unreachable!("type schemas are objects"),
+        }
+    };
+    if let Some(desc) = field.desc.as_ref().filter(|desc| !desc.is_empty()) {
+        schema.insert("description".into(), desc.as_str().into());
+    }
+    schema.into()
+}
+
+fn type_schema(ty: &MartianBlanketType, defs: &mut Map<String, Value>) -> Value {
+    match ty {
+        MartianBlanketType::Primary(primary) => primary_schema(primary, defs),
+        MartianBlanketType::Array(items) => {
+            json!({"type": "array", "items": type_schema(items, defs)})
+        }
+        MartianBlanketType::TypedMap(values) => match **values {
+            // Printed as an untyped map in the mro, see MartianBlanketType.
+            MartianBlanketType::TypedMap(_)
+            | MartianBlanketType::Primary(MartianPrimaryType::Map) => json!({"type": "object"}),
+            _ => json!({"type": "object", "additionalProperties": type_schema(values, defs)}),
+        },
+    }
+}
+
+fn primary_schema(ty: &MartianPrimaryType, defs: &mut Map<String, Value>) -> Value {
+    match ty {
+        MartianPrimaryType::Int => json!({"type": "integer"}),
+        MartianPrimaryType::Float => json!({"type": "number"}),
+        MartianPrimaryType::Bool => json!({"type": "boolean"}),
+        MartianPrimaryType::Str
+        | MartianPrimaryType::Path
+        | MartianPrimaryType::File
+        | MartianPrimaryType::FileType(_) => json!({"type": "string"}),
+        MartianPrimaryType::Map => json!({"type": "object"}),
+        MartianPrimaryType::Struct(def) => struct_schema(def, defs),
+    }
+}
+
+fn struct_schema(def: &StructDef, defs: &mut Map<String, Value>) -> Value {
+    if !defs.contains_key(&def.name) {
+        let schema = object_schema(&def.fields, defs);
+        defs.insert(def.name.clone(), schema.into());
+    }
+    json!({"$ref": format!("#/$defs/{}", def.name)})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mro::MroFile;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_json_schema() {
+        let mro = MroFile::parse(indoc!(
+            r#"
+            filetype bam;
+
+            struct Sample(
+                string name,
+                bam    reads,
+            )
+
+            stage ALIGN(
+                in  Sample[]   samples,
+                in  map<float> weights "Weight of each sample",
+                in  map        options,
+                in  int        threads,
+                out bam        merged,
+                src comp       "adapter martian align",
+            )
+            "#
+        ))
+        .unwrap();
+        let mut stage = mro.stages.into_iter().next().unwrap();
+        stage.stage_in_out.inputs[3] = stage.stage_in_out.inputs[3].clone().optional(true);
+        assert_eq!(
+            stage.inputs_json_schema(),
+            json!({
+                "$schema": DIALECT,
+                "title": "ALIGN inputs",
+                "type": "object",
+                "properties": {
+                    "samples": {"type": "array", "items": {"$ref": "#/$defs/Sample"}},
+                    "weights": {
+                        "type": "object",
+                        "additionalProperties": {"type": "number"},
+                        "description": "Weight of each sample",
+                    },
+                    "options": {"type": "object"},
+                    "threads": {"anyOf": [{"type": "integer"}, {"type": "null"}]},
+                },
+                "required": ["samples", "weights", "options"],
+                "$defs": {
+                    "Sample": {
+                        "type": "object",
+                        "properties": {
+                            "name": {"type": "string"},
+                            "reads": {"type": "string"},
+                        },
+                        "required": ["name", "reads"],
+                    },
+                },
+            })
+        );
+        assert_eq!(
+            stage.outputs_json_schema(),
+            json!({
+                "$schema": DIALECT,
+                "title": "ALIGN outputs",
+                "type": "object",
+                "properties": {"merged": {"type": "string"}},
+                "required": ["merged"],
+            })
+        );
+    }
+}
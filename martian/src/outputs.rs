@@ -0,0 +1,312 @@
+//! Check the files declared in the outputs of a stage before writing `_outs`.
+//!
+//! Every non-null output of type `path`, `file` or a filetype must exist and
+//! be inside the files directory of the stage, and filetype outputs must have
+//! the expected extension. Otherwise the mistake only surfaces in the stage
+//! which consumes the output. Since joins commonly pass along the files
+//! written by the chunks, the outputs of a join may also be the file outputs
+//! of its chunks, or inside them if they are directories.
+use crate::mro::{MartianBlanketType, MartianPrimaryType, MroField};
+use crate::JsonDict;
+use anyhow::{bail, Result};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// Check the file outputs in `outs`, which are described by `fields`. The
+/// files must be under `root`.
+pub(crate) fn check_outputs(fields: &[MroField], outs: &JsonDict, root: &Path) -> Result<()> {
+    check_outputs_in(fields, outs, &[canonical_path(root)])
+}
+
+/// Check the file outputs of a join, which must be under `root`, or be (or
+/// be inside) one of the file outputs of the chunks, `chunk_outs`, which are
+/// described by `chunk_fields`.
+pub(crate) fn check_join_outputs(
+    fields: &[MroField],
+    outs: &JsonDict,
+    root: &Path,
+    chunk_fields: &[MroField],
+    chunk_outs: &[JsonDict],
+) -> Result<()> {
+    let mut roots = vec![canonical_path(root)];
+    for chunk in chunk_outs {
+        roots.extend(
+            file_values(chunk_fields, chunk)
+                .into_iter()
+                .filter_map(|file| {
+                    file.value
+                        .as_str()
+                        .map(|path| canonical_path(path.as_ref()))
+                }),
+        );
+    }
+    check_outputs_in(fields, outs, &roots)
+}
+
+/// Check the file outputs in `outs`, which must be under one of `roots`.
+/// The first root is the files directory of the stage.
+fn check_outputs_in(fields: &[MroField], outs: &JsonDict, roots: &[PathBuf]) -> Result<()> {
+    let mut problems = Vec::new();
+    for file in file_values(fields, outs) {
+        let Some(path) = file.value.as_str() else {
+            problems.push((file.field, format!("expected a path, found {}", file.value)));
+            continue;
+        };
+        if let Some(problem) = check_path(Path::new(path), file.extension.as_deref(), roots) {
+            problems.push((file.field, problem));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let mut msg = String::from("invalid stage outputs:");
+    for (field, problem) in problems {
+        msg.push_str(&format!("\n  - {field}: {problem}"));
+    }
+    bail!(msg)
+}
+
+/// A non-null value of type `path`, `file` or a filetype in the inputs or
+/// outputs of a stage.
+pub(crate) struct FileValue<'a> {
+    /// Name of the field, e.g. `reads[1]` for an element of an array, or
+    /// `config.reference` for a field of a struct.
+    pub field: String,
+    pub value: &'a Value,
+    /// The extension of filetypes.
+    pub extension: Option<String>,
+}
+
+/// The file values in `obj`, which is described by `fields`.
+pub(crate) fn file_values<'a>(fields: &[MroField], obj: &'a JsonDict) -> Vec<FileValue<'a>> {
+    let mut files = Vec::new();
+    collect_fields(fields, obj, "", &mut files);
+    files
+}
+
+fn collect_fields<'a>(
+    fields: &[MroField],
+    obj: &'a JsonDict,
+    prefix: &str,
+    files: &mut Vec<FileValue<'a>>,
+) {
+    for field in fields {
+        if let Some(value) = obj.get(field.name()) {
+            let name = format!("{prefix}{}", field.name());
+            collect_value(field.ty(), value, name, files);
+        }
+    }
+}
+
+fn collect_value<'a>(
+    ty: &MartianBlanketType,
+    value: &'a Value,
+    name: String,
+    files: &mut Vec<FileValue<'a>>,
+) {
+    if value.is_null() {
+        return;
+    }
+    match ty {
+        MartianBlanketType::Primary(primary) => collect_primary(primary, value, name, files),
+        MartianBlanketType::Array(inner) => {
+            if let Some(values) = value.as_array() {
+                for (i, value) in values.iter().enumerate() {
+                    collect_value(inner, value, format!("{name}[{i}]"), files);
+                }
+            }
+        }
+        MartianBlanketType::TypedMap(inner) => {
+            if let Some(values) = value.as_object() {
+                for (key, value) in values {
+                    collect_value(inner, value, format!("{name}[{key}]"), files);
+                }
+            }
+        }
+    }
+}
+
+fn collect_primary<'a>(
+    ty: &MartianPrimaryType,
+    value: &'a Value,
+    name: String,
+    files: &mut Vec<FileValue<'a>>,
+) {
+    let extension = match ty {
+        MartianPrimaryType::Path | MartianPrimaryType::File => None,
+        MartianPrimaryType::FileType(ext) => Some(ext.clone()),
+        MartianPrimaryType::Struct(def) => {
+            if let Some(obj) = value.as_object() {
+                collect_fields(def.fields(), obj, &format!("{name}."), files);
+            }
+            return;
+        }
+        _ => return,
+    };
+    files.push(FileValue {
+        field: name,
+        value,
+        extension,
+    });
+}
+
+fn check_path(path: &Path, extension: Option<&str>, roots: &[PathBuf]) -> Option<String> {
+    if !path.exists() {
+        return Some(format!("{} does not exist", path.display()));
+    }
+    if let Some(ext) = extension {
+        let has_extension = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with(&format!(".{ext}")));
+        if !has_extension {
+            return Some(format!(
+                "{} does not have the extension .{ext}",
+                path.display()
+            ));
+        }
+    }
+    let canonical = canonical_path(path);
+    if !roots.iter().any(|root| canonical.starts_with(root)) {
+        let chunks = if roots.len() > 1 {
+            " or the file outputs of the chunks"
+        } else {
+            ""
+        };
+        return Some(format!(
+            "{} is not inside {}{chunks}",
+            path.display(),
+            roots[0].display()
+        ));
+    }
+    None
+}
+
+/// Resolve the parent directory of `path`, but not a symlink at `path`
+/// itself, since stages may link to files elsewhere from their files
+/// directory.
+fn canonical_path(path: &Path) -> PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => parent
+            .canonicalize()
+            .map(|parent| parent.join(name))
+            .unwrap_or(path),
+        _ => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mro::StructDef;
+    use serde_json::json;
+    use MartianBlanketType::{Array, Primary, TypedMap};
+    use MartianPrimaryType::{FileType, Int, Path as PathType, Struct};
+
+    #[test]
+    fn test_check_outputs() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let files_dir = tmp.path().join("files");
+        std::fs::create_dir(&files_dir)?;
+        let txt = files_dir.join("out.txt");
+        let json = files_dir.join("out.json");
+        let outside = tmp.path().join("outside.txt");
+        for path in [&txt, &json, &outside] {
+            std::fs::write(path, "")?;
+        }
+        std::os::unix::fs::symlink(&outside, files_dir.join("link.txt"))?;
+
+        let fields = vec![
+            MroField::new("count", Primary(Int), None, None),
+            MroField::new("txt", Primary(FileType("txt".into())), None, None),
+            MroField::new("missing", Primary(FileType("txt".into())), None, None),
+            MroField::new("unset", Primary(FileType("txt".into())), None, None),
+            MroField::new("dir", Primary(PathType), None, None),
+            MroField::new("txts", Array(FileType("txt".into()).into()), None, None),
+            MroField::new("by_name", TypedMap(PathType.into()), None, None),
+            MroField::new(
+                "nested",
+                Primary(Struct(StructDef::new(
+                    "Nested".into(),
+                    vec![MroField::new("file", Primary(PathType), None, None)],
+                ))),
+                None,
+                None,
+            ),
+        ];
+        let outs = json!({
+            "count": 1,
+            "txt": txt,
+            "missing": files_dir.join("missing.txt"),
+            "unset": null,
+            "dir": tmp.path(),
+            "txts": [txt, json, files_dir.join("link.txt")],
+            "by_name": {"a": txt, "b": outside},
+            "nested": {"file": files_dir.join("..").join("files").join("out.json")},
+        });
+        let err = check_outputs(&fields, outs.as_object().unwrap(), &files_dir).unwrap_err();
+        let msg = err.to_string();
+        let problems: Vec<_> = msg.lines().skip(1).collect();
+        assert_eq!(problems.len(), 4, "{msg}");
+        assert!(
+            problems[0].starts_with("  - missing: ") && problems[0].ends_with("does not exist")
+        );
+        assert!(problems[1].starts_with("  - dir: ") && problems[1].contains("not inside"));
+        assert!(problems[2].starts_with("  - txts[1]: ") && problems[2].ends_with(".txt"));
+        assert!(problems[3].starts_with("  - by_name[b]: ") && problems[3].contains("not inside"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_join_outputs() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let join_dir = tmp.path().join("join/files");
+        let chunk_dir = tmp.path().join("chnk0/files");
+        let chunk_subdir = chunk_dir.join("sub");
+        std::fs::create_dir_all(&join_dir)?;
+        std::fs::create_dir_all(&chunk_subdir)?;
+        let joined = join_dir.join("joined.txt");
+        let chunk_txt = chunk_dir.join("chunk.txt");
+        let chunk_other = chunk_dir.join("other.txt");
+        let chunk_sub_txt = chunk_subdir.join("sub.txt");
+        for path in [&joined, &chunk_txt, &chunk_other, &chunk_sub_txt] {
+            std::fs::write(path, "")?;
+        }
+
+        let chunk_fields = vec![
+            MroField::new("txt", Primary(FileType("txt".into())), None, None),
+            MroField::new("dir", Primary(PathType), None, None),
+        ];
+        let chunk_outs = json!([{"txt": chunk_txt, "dir": chunk_subdir}]);
+        let chunk_outs: Vec<JsonDict> = serde_json::from_value(chunk_outs)?;
+        let fields = vec![MroField::new(
+            "txts",
+            Array(FileType("txt".into()).into()),
+            None,
+            None,
+        )];
+        let check = |txts: Vec<&PathBuf>| {
+            let outs = json!({ "txts": txts });
+            check_join_outputs(
+                &fields,
+                outs.as_object().unwrap(),
+                &join_dir,
+                &chunk_fields,
+                &chunk_outs,
+            )
+        };
+
+        check(vec![&joined, &chunk_txt, &chunk_sub_txt])?;
+        // Other files of the chunks were not passed to the join.
+        let msg = check(vec![&joined, &chunk_other]).unwrap_err().to_string();
+        let problems: Vec<_> = msg.lines().skip(1).collect();
+        assert_eq!(problems.len(), 1, "{msg}");
+        assert!(
+            problems[0].starts_with("  - txts[1]: ")
+                && problems[0].ends_with("or the file outputs of the chunks"),
+            "{msg}"
+        );
+        Ok(())
+    }
+}
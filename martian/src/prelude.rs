@@ -6,10 +6,14 @@
 //! ```rust
 //! use martian::prelude::*;
 //! ```
+pub use crate::alarm::{Alarm, AlarmSeverity};
+#[cfg(feature = "tokio")]
+pub use crate::runtime::{MartianMainAsync, MartianStageAsync};
 pub use crate::stage::{
     MartianFileType, MartianMain, MartianMakePath, MartianRover, MartianStage, MartianVoid,
     RawMartianStage, Resource, StageDef,
 };
-pub use crate::{martian_make_mro, Error, MartianAdapter};
+pub use crate::validate::ValidationErrors;
+pub use crate::{martian_make_mro, Error, MartianAdapter, MartianCli};
 pub use log::LevelFilter;
 pub use martian_stages;
@@ -0,0 +1,176 @@
+//! Profiling of stages requested with `mrp --profile=<mode>`.
+//!
+//! - `cpu`: requires the `profiling` feature. The stage is sampled 99 times a
+//!   second and the call stacks are written to `_profile.out` in the folded
+//!   stacks format, which can be rendered with flamegraph tools such as
+//!   [inferno](https://github.com/jonhoo/inferno).
+//! - `mem`: requires the `tracking-allocator` feature, with
+//!   [`TrackingAllocator`](crate::alloc::TrackingAllocator) installed as the
+//!   global allocator. A sampled heap profile is written to `_profile.out`,
+//!   in the same format, with the number of bytes allocated from each stack.
+//! - `perf` and `pyspy`: handled entirely by `mrp`, which wraps the adapter.
+//! - `line`: not supported for rust stages.
+
+use crate::metadata::{Metadata, ProfileMode};
+use anyhow::Result;
+#[cfg(any(feature = "profiling", feature = "tracking-allocator"))]
+use log::info;
+use log::warn;
+use std::fmt;
+
+#[cfg(any(feature = "profiling", feature = "tracking-allocator"))]
+const PROFILE_FN: &str = "profile.out";
+
+/// Uninhabited when martian is built without any of the profiling features.
+pub(crate) enum StageProfiler {
+    #[cfg(feature = "profiling")]
+    Cpu(pprof::ProfilerGuard<'static>),
+    #[cfg(feature = "tracking-allocator")]
+    Mem,
+}
+
+#[cfg(not(any(feature = "profiling", feature = "tracking-allocator")))]
+impl fmt::Debug for StageProfiler {
+    fn fmt(&self, _: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {}
+    }
+}
+
+#[cfg(any(feature = "profiling", feature = "tracking-allocator"))]
+impl fmt::Debug for StageProfiler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            #[cfg(feature = "profiling")]
+            StageProfiler::Cpu(_) => f.write_str("StageProfiler::Cpu"),
+            #[cfg(feature = "tracking-allocator")]
+            StageProfiler::Mem => f.write_str("StageProfiler::Mem"),
+        }
+    }
+}
+
+impl StageProfiler {
+    /// Start profiling according to `mode`, if it is supported.
+    pub(crate) fn start(mode: ProfileMode) -> Option<StageProfiler> {
+        match mode {
+            ProfileMode::Disable | ProfileMode::Perf | ProfileMode::Pyspy => None,
+            ProfileMode::Cpu => Self::start_cpu(),
+            ProfileMode::Mem => Self::start_mem(),
+            ProfileMode::Line => {
+                warn!("line profiling is not supported for rust stages");
+                None
+            }
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    fn start_cpu() -> Option<StageProfiler> {
+        match pprof::ProfilerGuardBuilder::default().frequency(99).build() {
+            Ok(guard) => Some(StageProfiler::Cpu(guard)),
+            Err(e) => {
+                warn!("failed to start the cpu profiler: {e}");
+                None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    fn start_cpu() -> Option<StageProfiler> {
+        warn!("cpu profiling requires building martian with the `profiling` feature");
+        None
+    }
+
+    #[cfg(feature = "tracking-allocator")]
+    fn start_mem() -> Option<StageProfiler> {
+        if crate::alloc::is_installed() {
+            crate::alloc::start_heap_profile();
+            Some(StageProfiler::Mem)
+        } else {
+            warn!("memory profiling requires martian::alloc::TrackingAllocator to be the global allocator");
+            None
+        }
+    }
+
+    #[cfg(not(feature = "tracking-allocator"))]
+    fn start_mem() -> Option<StageProfiler> {
+        warn!("memory profiling requires building martian with the `tracking-allocator` feature");
+        None
+    }
+
+    /// Stop profiling and write the profile into the metadata directory.
+    #[cfg(not(any(feature = "profiling", feature = "tracking-allocator")))]
+    pub(crate) fn finish(self, _md: &mut Metadata) -> Result<()> {
+        match self {}
+    }
+
+    /// Stop profiling and write the profile into the metadata directory.
+    #[cfg(any(feature = "profiling", feature = "tracking-allocator"))]
+    pub(crate) fn finish(self, md: &mut Metadata) -> Result<()> {
+        match self {
+            #[cfg(feature = "profiling")]
+            StageProfiler::Cpu(guard) => {
+                let report = guard.report().build()?;
+                drop(guard);
+                let mut folded = std::collections::BTreeMap::<String, isize>::new();
+                for (frames, count) in report.data {
+                    let mut stack = frames.thread_name_or_id();
+                    // Root of the call stack first.
+                    for frame in frames.frames.iter().rev() {
+                        for symbol in frame.iter().rev() {
+                            stack.push(';');
+                            stack.push_str(&symbol.name());
+                        }
+                    }
+                    *folded.entry(stack).or_default() += count;
+                }
+                let mut out = String::new();
+                use std::fmt::Write;
+                for (stack, count) in folded {
+                    writeln!(&mut out, "{stack} {count}").unwrap();
+                }
+                md.write_raw(PROFILE_FN, &out)?;
+                info!(
+                    "wrote cpu profile to {}",
+                    md.make_path(PROFILE_FN).display()
+                );
+            }
+            #[cfg(feature = "tracking-allocator")]
+            StageProfiler::Mem => {
+                let out = crate::alloc::finish_heap_profile();
+                md.write_raw(PROFILE_FN, &out)?;
+                info!(
+                    "wrote heap profile to {} (peak heap usage {} bytes)",
+                    md.make_path(PROFILE_FN).display(),
+                    crate::alloc::peak_bytes()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_profile() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let mut md = Metadata::new(vec![
+            "sum_squares".to_string(),
+            "main".to_string(),
+            dir.path().to_str().unwrap().to_string(),
+            path("files"),
+            path("run"),
+        ])?;
+        let profiler = StageProfiler::start(ProfileMode::Cpu).unwrap();
+        let start = std::time::Instant::now();
+        let mut x = 0u64;
+        while start.elapsed() < std::time::Duration::from_millis(200) {
+            x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(7));
+        }
+        profiler.finish(&mut md)?;
+        assert!(std::fs::metadata(md.make_path(PROFILE_FN))?.len() > 0);
+        Ok(())
+    }
+}
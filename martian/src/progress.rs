@@ -0,0 +1,237 @@
+//! Progress reports from long running stages.
+//!
+//! [`MartianRover::progress()`] writes the fraction of the work done and a
+//! message to the `_progress` file of the chunk, which `mrp` shows while the
+//! stage runs:
+//! ```rust
+//! # use martian::{MartianRover, Resource};
+//! # let rover = MartianRover::new("/tmp", Resource::with_mem_gb(1).threads(1).vmem_gb(2));
+//! let total = 1000;
+//! for i in 0..total {
+//!     rover.progress(i as f64 / total as f64, &format!("processed {i} of {total} barcodes"))?;
+//! }
+//! # Ok::<(), martian::Error>(())
+//! ```
+//! Reports are throttled, so they can be made as often as convenient.
+//! Outside of `mrp`, e.g. in `test_run()`, they are printed to stdout.
+//!
+//! A [`ProgressReader`] reports how much of a file has been read. The lazy
+//! readers of `martian-filetypes` use it to report progress automatically.
+//!
+//! [`MartianRover::progress()`]: crate::MartianRover::progress
+use crate::metadata::{write_journal, Metadata};
+use anyhow::Result;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Minimum time between two progress reports which are written.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reports the progress of a stage. Cloning the reporter is cheap, so it can
+/// be handed to worker threads.
+#[derive(Debug, Clone)]
+pub struct ProgressReporter {
+    sink: Arc<Sink>,
+    last_written: Arc<Mutex<Option<Instant>>>,
+    interval: Duration,
+}
+
+#[derive(Debug)]
+enum Sink {
+    /// The `_progress` file, and the journal entry which tells `mrp` that
+    /// it was updated.
+    Metadata {
+        path: PathBuf,
+        journal: String,
+    },
+    Stdout,
+}
+
+impl ProgressReporter {
+    pub(crate) fn for_metadata(md: &Metadata) -> Self {
+        ProgressReporter::new(Sink::Metadata {
+            path: md.make_path("progress"),
+            journal: md.journal_path("progress"),
+        })
+    }
+
+    pub(crate) fn stdout() -> Self {
+        ProgressReporter::new(Sink::Stdout)
+    }
+
+    fn new(sink: Sink) -> Self {
+        ProgressReporter {
+            sink: Arc::new(sink),
+            last_written: Arc::new(Mutex::new(None)),
+            interval: PROGRESS_INTERVAL,
+        }
+    }
+
+    /// Report that `fraction` (between 0 and 1) of the work is done. Reports
+    /// made less than a few seconds after the last written one are dropped,
+    /// unless the work is done.
+    pub fn report(&self, fraction: f64, message: &str) -> Result<()> {
+        let fraction = if fraction.is_nan() {
+            0.0
+        } else {
+            fraction.clamp(0.0, 1.0)
+        };
+        let mut last_written = self
+            .last_written
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if fraction < 1.0 && last_written.is_some_and(|t| now.duration_since(t) < self.interval) {
+            return Ok(());
+        }
+        *last_written = Some(now);
+
+        let line = if message.is_empty() {
+            format!("{:.0}%", fraction * 100.0)
+        } else {
+            format!("{:.0}% {message}", fraction * 100.0)
+        };
+        match &*self.sink {
+            Sink::Metadata { path, journal } => {
+                let mut f = File::create(path)?;
+                writeln!(f, "{line}")?;
+                drop(f);
+                write_journal(journal)
+            }
+            Sink::Stdout => {
+                println!("{line}");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Reports how much of the underlying reader has been read, out of `total`
+/// bytes.
+/// ```rust
+/// # use martian::{MartianRover, Resource};
+/// # use martian::progress::ProgressReader;
+/// # use std::io::Read;
+/// # let rover = MartianRover::new("/tmp", Resource::with_mem_gb(1).threads(1).vmem_gb(2));
+/// let data = vec![0u8; 1 << 20];
+/// let mut reader = ProgressReader::new(&data[..], data.len() as u64, &rover, "reading data");
+/// let mut buf = Vec::new();
+/// reader.read_to_end(&mut buf)?;
+/// # Ok::<(), martian::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct ProgressReader<R> {
+    inner: R,
+    reporter: ProgressReporter,
+    message: String,
+    read: u64,
+    total: u64,
+    done: bool,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(
+        inner: R,
+        total: u64,
+        rover: &crate::MartianRover,
+        message: impl Into<String>,
+    ) -> Self {
+        ProgressReader::with_reporter(inner, total, rover.progress_reporter().clone(), message)
+    }
+
+    pub fn with_reporter(
+        inner: R,
+        total: u64,
+        reporter: ProgressReporter,
+        message: impl Into<String>,
+    ) -> Self {
+        ProgressReader {
+            inner,
+            reporter,
+            message: message.into(),
+            read: 0,
+            total,
+            done: false,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.done {
+            return Ok(n);
+        }
+        let fraction = if n == 0 || self.total == 0 {
+            1.0
+        } else {
+            self.read as f64 / self.total as f64
+        };
+        self.done = fraction >= 1.0;
+        // Failing to report progress should not fail the read.
+        if let Err(e) = self.reporter.report(fraction, &self.message) {
+            log::warn!("failed to report progress: {e:#}");
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reporter(dir: &std::path::Path, interval: Duration) -> ProgressReporter {
+        ProgressReporter {
+            interval,
+            ..ProgressReporter::new(Sink::Metadata {
+                path: dir.join("_progress"),
+                journal: dir.join("run.progress").to_string_lossy().into_owned(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_progress_reporter() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let progress = reporter(tmp.path(), Duration::from_secs(3600));
+        let read = || std::fs::read_to_string(tmp.path().join("_progress")).unwrap();
+
+        progress.report(0.25, "step 1")?;
+        assert_eq!(read(), "25% step 1\n");
+        assert!(tmp.path().join("run.progress").exists());
+        // Throttled
+        progress.report(0.5, "step 2")?;
+        assert_eq!(read(), "25% step 1\n");
+        // Always written when done.
+        progress.clone().report(1.5, "")?;
+        assert_eq!(read(), "100%\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress_reader() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let data = vec![1u8; 1000];
+        let mut reader = ProgressReader::with_reporter(
+            &data[..],
+            data.len() as u64,
+            reporter(tmp.path(), Duration::ZERO),
+            "reading",
+        );
+        let mut buf = [0u8; 300];
+        reader.read_exact(&mut buf)?;
+        let progress = tmp.path().join("_progress");
+        assert_eq!(std::fs::read_to_string(&progress)?, "30% reading\n");
+        io::copy(&mut reader, &mut io::sink())?;
+        assert_eq!(std::fs::read_to_string(&progress)?, "100% reading\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+//! Provenance of the outputs of a stage, as content hashes.
+//!
+//! Enabled with [`MartianAdapter::provenance()`]. The adapter then hashes
+//! the `path`, `file` and filetype inputs of the split, chunk or join before
+//! running it, and its file outputs afterwards, and writes them to
+//! `_provenance` along with the version of the pipeline and how the adapter
+//! was built:
+//! ```json
+//! {
+//!     "stage": "sort_reads",
+//!     "phase": "main",
+//!     "version": { "martian": "v4.0.11", "pipelines": "7.2.0" },
+//!     "build_info": { "crate_name": "my_adapter", "git_commit": "8f3a2c1e4b...", ... },
+//!     "inputs": [
+//!         { "field": "reads[0]", "path": "/data/reads_0.fastq", "sha256": "9f86d08...", "bytes": 2351 }
+//!     ],
+//!     "outputs": [
+//!         { "field": "sorted", "path": ".../files/sorted.bam", "sha256": "60303ae...", "bytes": 1892 }
+//!     ]
+//! }
+//! ```
+//! Directories are hashed from the relative paths and hashes of the files
+//! they contain. Symlinks to directories inside a directory are not followed,
+//! which could loop forever, and are hashed from the path they point to.
+//! Inputs which do not exist are recorded without a hash.
+//!
+//! [`MartianAdapter::provenance()`]: crate::MartianAdapter::provenance
+use crate::build::BuildInfo;
+use crate::metadata::{StagePhase, Version};
+use crate::mro::MroField;
+use crate::outputs::file_values;
+use crate::JsonDict;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Inputs and outputs of one split, chunk or join.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Provenance {
+    pub stage: String,
+    pub phase: StagePhase,
+    pub version: Version,
+    pub build_info: Option<BuildInfo>,
+    pub inputs: Vec<FileHash>,
+    pub outputs: Vec<FileHash>,
+}
+
+/// Hash of a file or directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileHash {
+    /// Name of the field, e.g. `reads[1]`.
+    pub field: String,
+    pub path: PathBuf,
+    /// `None` if the path does not exist.
+    pub sha256: Option<String>,
+    /// Total size of the files.
+    pub bytes: u64,
+}
+
+/// Hash the files referenced by `obj`, which is described by `fields`.
+pub(crate) fn hash_files(fields: &[MroField], obj: &JsonDict) -> Result<Vec<FileHash>> {
+    file_values(fields, obj)
+        .into_iter()
+        .filter_map(|file| Some((file.field, file.value.as_str()?)))
+        .map(|(field, path)| {
+            let path = PathBuf::from(path);
+            let (sha256, bytes) = match hash_path(&path)
+                .with_context(|| format!("Failed to hash {field}: {}", path.display()))?
+            {
+                Some((sha256, bytes)) => (Some(sha256), bytes),
+                None => (None, 0),
+            };
+            Ok(FileHash {
+                field,
+                path,
+                sha256,
+                bytes,
+            })
+        })
+        .collect()
+}
+
+/// Hex encoded sha256 and size of the file or directory at `path`, if it
+/// exists.
+fn hash_path(path: &Path) -> Result<Option<(String, u64)>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    if !path.is_dir() {
+        let mut hasher = Sha256::new();
+        let bytes = std::io::copy(&mut File::open(path)?, &mut hasher)?;
+        return Ok(Some((hex(&hasher.finalize()), bytes)));
+    }
+    let mut entries = Vec::new();
+    collect_dir(path, path, &mut entries)?;
+    entries.sort();
+    let mut hasher = Sha256::new();
+    let mut total = 0;
+    for (relative, sha256, bytes) in entries {
+        hasher.update(format!("{}\0{sha256}\n", relative.display()));
+        total += bytes;
+    }
+    Ok(Some((hex(&hasher.finalize()), total)))
+}
+
+fn collect_dir(root: &Path, dir: &Path, entries: &mut Vec<(PathBuf, String, u64)>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+        if file_type.is_dir() {
+            collect_dir(root, &path, entries)?;
+        } else if file_type.is_symlink() && path.is_dir() {
+            let target = std::fs::read_link(&path)?;
+            let sha256 = hex(&Sha256::digest(target.as_os_str().as_bytes()));
+            entries.push((relative, sha256, 0));
+        } else if let Some((sha256, bytes)) = hash_path(&path)? {
+            entries.push((relative, sha256, bytes));
+        }
+    }
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mro::{MartianBlanketType, MartianPrimaryType};
+    use serde_json::json;
+
+    // sha256 of "hello\n"
+    const HELLO_SHA256: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    #[test]
+    fn test_hash_files() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let hello = tmp.path().join("hello.txt");
+        std::fs::write(&hello, "hello\n")?;
+        let dir = tmp.path().join("dir");
+        std::fs::create_dir_all(dir.join("nested"))?;
+        std::fs::write(dir.join("nested/a.txt"), "hello\n")?;
+        std::fs::write(dir.join("b.txt"), "")?;
+
+        let fields = vec![
+            MroField::new(
+                "txts",
+                MartianBlanketType::Array(MartianPrimaryType::FileType("txt".into()).into()),
+                None,
+                None,
+            ),
+            MroField::new(
+                "reference",
+                MartianBlanketType::Primary(MartianPrimaryType::Path),
+                None,
+                None,
+            ),
+            MroField::new(
+                "count",
+                MartianBlanketType::Primary(MartianPrimaryType::Int),
+                None,
+                None,
+            ),
+        ];
+        let obj = json!({
+            "txts": [hello, tmp.path().join("missing.txt")],
+            "reference": dir,
+            "count": 3,
+        });
+        let hashes = hash_files(&fields, obj.as_object().unwrap())?;
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(hashes[0].field, "txts[0]");
+        assert_eq!(hashes[0].sha256.as_deref(), Some(HELLO_SHA256));
+        assert_eq!(hashes[0].bytes, 6);
+        assert_eq!(hashes[1].sha256, None);
+        assert_eq!(hashes[2].field, "reference");
+        assert_eq!(hashes[2].bytes, 6);
+
+        // The hash of a directory depends on the content and names of its
+        // files.
+        let dir_hash = hashes[2].sha256.clone();
+        std::fs::write(dir.join("b.txt"), "changed")?;
+        let hashes = hash_files(&fields, obj.as_object().unwrap())?;
+        assert_ne!(hashes[2].sha256, dir_hash);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_symlink_loop() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path().join("reference");
+        std::fs::create_dir_all(dir.join("genes"))?;
+        std::fs::write(dir.join("genes/genes.gtf"), "hello\n")?;
+        std::os::unix::fs::symlink("..", dir.join("genes/parent"))?;
+        std::os::unix::fs::symlink("genes/genes.gtf", dir.join("genes.gtf"))?;
+
+        let (dir_hash, bytes) = hash_path(&dir)?.unwrap();
+        // The file is hashed through the symlink, but not the directory.
+        assert_eq!(bytes, 12);
+
+        std::fs::remove_file(dir.join("genes/parent"))?;
+        std::os::unix::fs::symlink("../..", dir.join("genes/parent"))?;
+        assert_ne!(hash_path(&dir)?.unwrap().0, dir_hash);
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+//! Async stages, run on a tokio runtime managed by the adapter.
+//!
+//! Enabled with the `tokio` feature. [`MartianMainAsync`] and
+//! [`MartianStageAsync`] mirror [`MartianMain`] and [`MartianStage`], except
+//! that `split`, `main` and `join` are `async fn`s. Annotating the impl with
+//! `#[make_mro]` also implements the synchronous trait, which blocks on the
+//! future using a multi-threaded runtime shared by all the stages in the
+//! process. The runtime is built the first time it is needed, with one worker
+//! thread per thread allotted to the stage, so async stages work with
+//! `martian_stages!`, [`RawMartianStage`] and `test_run` like any other stage.
+//! ```ignore
+//! use martian::prelude::*;
+//! use martian_derive::{make_mro, MartianStruct};
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize, MartianStruct)]
+//! pub struct FetchStageInputs {
+//!     url: String,
+//! }
+//! #[derive(Serialize, Deserialize, MartianStruct)]
+//! pub struct FetchStageOutputs {
+//!     length: usize,
+//! }
+//! pub struct Fetch;
+//!
+//! #[make_mro]
+//! impl MartianMainAsync for Fetch {
+//!     type StageInputs = FetchStageInputs;
+//!     type StageOutputs = FetchStageOutputs;
+//!
+//!     async fn main(
+//!         &self,
+//!         args: Self::StageInputs,
+//!         _rover: MartianRover,
+//!     ) -> Result<Self::StageOutputs, Error> {
+//!         Ok(FetchStageOutputs { length: args.url.len() })
+//!     }
+//! }
+//!
+//! let outs = Fetch.test_run_tmpdir(FetchStageInputs { url: "local".into() }).unwrap();
+//! assert_eq!(outs.length, 5);
+//! ```
+//!
+//! Blocking on the runtime from a thread which is already driving an async
+//! task panics, so `test_run` should not be called from `#[tokio::test]`.
+//!
+//! [`MartianMain`]: crate::MartianMain
+//! [`MartianStage`]: crate::MartianStage
+//! [`RawMartianStage`]: crate::RawMartianStage
+use crate::mro::{MartianStruct, MroMaker};
+use crate::stage::{MartianRover, StageDef};
+use crate::{Error, ValidationErrors};
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::OnceLock;
+use tokio::runtime::{Builder, Runtime};
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// The runtime shared by the async stages in this process. It is built on
+/// first use, with `threads` worker threads.
+pub fn runtime(threads: usize) -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        let threads = threads.max(1);
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(threads)
+            .thread_name(crate::usage::TOKIO_THREAD_NAME)
+            .enable_all()
+            .build()
+            .expect("Failed to build the tokio runtime");
+        info!("started the tokio runtime with {threads} worker threads");
+        runtime
+    })
+}
+
+/// Run `future` to completion on the [`runtime()`].
+pub fn block_on<F: Future>(threads: usize, future: F) -> F::Output {
+    runtime(threads).block_on(future)
+}
+
+/// Async version of [`MartianMain`](crate::MartianMain).
+///
+/// Implementations can use `async fn main(..)`.
+pub trait MartianMainAsync: MroMaker {
+    type StageInputs: DeserializeOwned + MartianStruct;
+    type StageOutputs: Serialize + DeserializeOwned + MartianStruct;
+
+    fn main(
+        &self,
+        args: Self::StageInputs,
+        rover: MartianRover,
+    ) -> impl Future<Output = Result<Self::StageOutputs, Error>>;
+
+    /// See [`MartianMain::validate()`](crate::MartianMain::validate).
+    fn validate(&self, args: &Self::StageInputs) -> Result<(), ValidationErrors> {
+        MartianStruct::validate(args)
+    }
+}
+
+/// Async version of [`MartianStage`](crate::MartianStage).
+///
+/// Implementations can use `async fn` for `split`, `main` and `join`.
+pub trait MartianStageAsync: MroMaker {
+    type StageInputs: DeserializeOwned + MartianStruct;
+    type StageOutputs: Serialize + MartianStruct;
+    type ChunkInputs: Serialize + DeserializeOwned + MartianStruct;
+    type ChunkOutputs: Serialize + DeserializeOwned + MartianStruct;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        rover: MartianRover,
+    ) -> impl Future<Output = Result<StageDef<Self::ChunkInputs>, Error>>;
+
+    fn main(
+        &self,
+        args: Self::StageInputs,
+        chunk_args: Self::ChunkInputs,
+        rover: MartianRover,
+    ) -> impl Future<Output = Result<Self::ChunkOutputs, Error>>;
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        chunk_defs: Vec<Self::ChunkInputs>,
+        chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> impl Future<Output = Result<Self::StageOutputs, Error>>;
+
+    /// See [`MartianStage::validate()`](crate::MartianStage::validate).
+    fn validate(&self, args: &Self::StageInputs) -> Result<(), ValidationErrors> {
+        MartianStruct::validate(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_on() {
+        let threads = block_on(2, async {
+            tokio::task::spawn(async { std::thread::current().name().map(String::from) })
+                .await
+                .unwrap()
+        });
+        assert_eq!(threads.as_deref(), Some("martian-tokio"));
+        assert_eq!(runtime(8).metrics().num_workers(), 2);
+    }
+}
@@ -1,7 +1,12 @@
-use crate::metadata::{Metadata, Version};
+use crate::alarm::{Alarm, AlarmLog, AlarmSeverity};
+use crate::checkpoint::CheckpointStore;
+use crate::disk::DiskUsage;
+use crate::metadata::{JsonDict, Metadata, StagePhase, Version};
 use crate::mro::{MartianStruct, MroMaker};
+use crate::outputs::{check_join_outputs, check_outputs};
+use crate::progress::ProgressReporter;
 use crate::utils::obj_encode;
-use crate::{Error, SharedFile};
+use crate::{CancellationToken, Error, ValidationErrors};
 use log::warn;
 #[cfg(feature = "rayon")]
 use rayon::prelude::*;
@@ -425,18 +430,34 @@ pub struct MartianRover {
     threads: usize,
     vmem_gb: usize,
     version: Version,
-    alarm_file: Option<SharedFile>,
+    alarms: Option<AlarmLog>,
+    cancellation: CancellationToken,
+    checkpoint: CheckpointStore,
+    resumed: bool,
+    progress: ProgressReporter,
 }
 
 impl From<&Metadata> for MartianRover {
     fn from(md: &Metadata) -> MartianRover {
+        let checkpoint = CheckpointStore::new(&md.files_path);
+        let resumed = checkpoint.exists();
+        if resumed {
+            log::info!(
+                "resuming from the checkpoint saved at {}",
+                checkpoint.saved_at().as_deref().unwrap_or("unknown time")
+            );
+        }
         MartianRover {
             files_path: PathBuf::from(&md.files_path),
             mem_gb: md.jobinfo.mem_gb,
             threads: md.jobinfo.threads,
             vmem_gb: md.jobinfo.vmem_gb,
             version: md.jobinfo.version.clone(),
-            alarm_file: Some(md.alarm_file().clone()),
+            alarms: Some(md.alarms().clone()),
+            cancellation: md.cancellation_token().clone(),
+            checkpoint,
+            resumed,
+            progress: ProgressReporter::for_metadata(md),
         }
     }
 }
@@ -457,13 +478,19 @@ impl MartianRover {
         assert!(resource.threads.unwrap() >= 0);
         assert!(resource.vmem_gb.is_some());
         assert!(resource.vmem_gb.unwrap() >= 0);
+        let checkpoint = CheckpointStore::new(files_path);
+        let resumed = checkpoint.exists();
         MartianRover {
             files_path: PathBuf::from(files_path),
             mem_gb: resource.mem_gb.unwrap() as usize,
             threads: resource.threads.unwrap() as usize,
             vmem_gb: resource.vmem_gb.unwrap() as usize,
             version: Version::default(),
-            alarm_file: None,
+            alarms: None,
+            cancellation: CancellationToken::new(),
+            checkpoint,
+            resumed,
+            progress: ProgressReporter::stdout(),
         }
     }
     ///
@@ -495,6 +522,8 @@ impl MartianRover {
     pub fn get_mem_gb(&self) -> usize {
         self.mem_gb
     }
+    /// Number of threads allotted to the stage. When the `rayon` feature is
+    /// enabled, the adapter sizes the global rayon thread pool to match.
     pub fn get_threads(&self) -> usize {
         self.threads
     }
@@ -511,17 +540,95 @@ impl MartianRover {
         self.version.pipelines.clone()
     }
 
-    /// Add a message to the martian alarm system.
+    /// Token which is cancelled when the job manager asks the stage to
+    /// terminate, e.g. because it exceeded its walltime.
+    ///
+    /// Long running loops should poll it and exit early:
+    /// ```rust
+    /// # use martian::{Error, MartianRover, Resource};
+    /// # fn process(_: usize) {}
+    /// # fn run(rover: MartianRover) -> Result<(), Error> {
+    /// for item in 0..1000 {
+    ///     rover.cancellation_token().check()?;
+    ///     process(item);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// Shorthand for `self.cancellation_token().is_cancelled()`.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    /// Store for saving the progress of the chunk, so that it can resume
+    /// after being preempted. See [`checkpoint`](crate::checkpoint).
+    pub fn checkpoint(&self) -> &CheckpointStore {
+        &self.checkpoint
+    }
+
+    /// Whether a checkpoint was saved by a previous run of this chunk.
+    pub fn is_resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// The state saved by a previous run of this chunk, if it was restarted
+    /// after saving a checkpoint.
+    pub fn resume<T: DeserializeOwned>(&self) -> Result<Option<T>, Error> {
+        if !self.resumed {
+            return Ok(None);
+        }
+        self.checkpoint.load()
+    }
+
+    /// Report that `fraction` (between 0 and 1) of the work of the stage is
+    /// done, along with a message shown by `mrp`. Reports are throttled, so
+    /// this can be called as often as convenient. If this rover was not
+    /// initialized with metadata, such as in test mode, print the progress
+    /// to stdout instead. See [`progress`](crate::progress).
+    pub fn progress(&self, fraction: f64, message: &str) -> Result<(), Error> {
+        self.progress.report(fraction, message)
+    }
+
+    /// The reporter behind [`progress()`](Self::progress), which can be
+    /// handed to worker threads or wrapped around a reader with
+    /// [`ProgressReader`](crate::progress::ProgressReader).
+    pub fn progress_reporter(&self) -> &ProgressReporter {
+        &self.progress
+    }
+
+    /// Add a message to the martian alarm system, as a warning keyed by the
+    /// message itself, so that repeats of the same message are aggregated.
+    /// The message is written to `_alarm` as is.
     /// If this rover was not initialized with metadata, such as in test mode,
     /// log at warning level instead.
     pub fn alarm(&self, message: &str) -> Result<(), Error> {
-        if let Some(f) = &self.alarm_file {
-            f.appendln(message, true)
+        if let Some(alarms) = &self.alarms {
+            alarms.raise_message(message)
         } else {
             warn!("{message}");
             Ok(())
         }
     }
+
+    /// Raise a structured alarm. See [`alarm`](crate::alarm) for how repeated
+    /// alarms are aggregated. If this rover was not initialized with
+    /// metadata, log at the level matching the severity instead.
+    pub fn raise_alarm(&self, alarm: Alarm) -> Result<(), Error> {
+        if let Some(alarms) = &self.alarms {
+            alarms.raise(alarm)
+        } else {
+            match alarm.severity {
+                AlarmSeverity::Info => log::info!("{alarm}"),
+                AlarmSeverity::Warning => warn!("{alarm}"),
+                AlarmSeverity::Error => log::error!("{alarm}"),
+            }
+            Ok(())
+        }
+    }
 }
 
 /// Two different kinds of marian stages. `MainOnly` stages only have a
@@ -531,7 +638,7 @@ impl MartianRover {
 /// which returns `StageKind::WithSplit`. `MartianMain` trait overrides this to return
 /// `StageKind::MainOnly`. This enum exists so that one can determine whether a stage object
 /// implements `MartianMain` or `MartianStage`
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum StageKind {
     /// Stage with only a `main()` function
     MainOnly,
@@ -551,6 +658,13 @@ pub trait MartianMain: MroMaker {
         args: Self::StageInputs,
         rover: MartianRover,
     ) -> Result<Self::StageOutputs, Error>;
+
+    /// Check the stage inputs before `main()` runs. Errors are reported to
+    /// martian as an assert. The default implementation checks the
+    /// `#[mro_validate(..)]` attributes of the `StageInputs`.
+    fn validate(&self, args: &Self::StageInputs) -> Result<(), ValidationErrors> {
+        MartianStruct::validate(args)
+    }
 }
 
 fn split_prelude(
@@ -598,6 +712,13 @@ pub trait MartianStage: MroMaker {
         rover: MartianRover,
     ) -> Result<Self::StageOutputs, Error>;
 
+    /// Check the stage inputs before `split()` runs. Errors are reported to
+    /// martian as an assert. The default implementation checks the
+    /// `#[mro_validate(..)]` attributes of the `StageInputs`.
+    fn validate(&self, args: &Self::StageInputs) -> Result<(), ValidationErrors> {
+        MartianStruct::validate(args)
+    }
+
     /// In-process stage runner, useful for writing unit tests that exercise one of more stages purely from Rust.
     /// Executes stage with arguments `args` in directory `run_directory`. The defaul implementation executes split
     /// to get the stage definition (chunks), executes each chunk one after another and finally calls the join function.
@@ -613,6 +734,7 @@ pub trait MartianStage: MroMaker {
         Self::ChunkOutputs: Send + Sync,
     {
         let run_directory = run_directory.as_ref();
+        self.validate(&args)?;
 
         let rover = split_prelude(run_directory, Self::stage_name(), "split")?;
         println!(" > [split ] running");
@@ -687,6 +809,30 @@ pub trait MartianStage: MroMaker {
         let tmp_dir = tempfile::tempdir()?;
         self.test_run(&tmp_dir, args)
     }
+
+    /// Same as `test_run()`, and also return the disk usage of the files
+    /// left in `run_directory` by the split, chunks and join, as reported
+    /// in `_disk_usage` by the adapter.
+    fn test_run_with_disk_usage(
+        &self,
+        run_directory: impl AsRef<Path> + Send + Sync,
+        args: Self::StageInputs,
+    ) -> Result<(Self::StageOutputs, DiskUsage), Error>
+    where
+        Self: Sync,
+        Self::ChunkInputs: Clone + Send + Sync,
+        Self::StageInputs: Clone + Send + Sync,
+        Self::ChunkOutputs: Send + Sync,
+    {
+        let outs = self.test_run(&run_directory, args)?;
+        let usage = DiskUsage::measure(run_directory)?;
+        println!(
+            " > [stage ] wrote {} bytes in {} files",
+            usage.total_bytes,
+            usage.files.len()
+        );
+        Ok((outs, usage))
+    }
     fn stage_kind() -> StageKind {
         StageKind::WithSplit
     }
@@ -698,6 +844,21 @@ pub trait RawMartianStage {
     fn split(&self, metadata: &mut Metadata) -> Result<(), Error>;
     fn main(&self, metadata: &mut Metadata) -> Result<(), Error>;
     fn join(&self, metadata: &mut Metadata) -> Result<(), Error>;
+    /// Check the stage inputs without running the stage.
+    fn preflight(&self, metadata: &mut Metadata) -> Result<(), Error> {
+        self.check(metadata)?;
+        metadata.complete_with(OUTS_FN, &JsonDict::new())
+    }
+    /// Load the inputs of the phase of `metadata` from its metadata directory,
+    /// and validate them, without running the stage or writing any files.
+    /// Fails by default, as there is nothing to check the inputs against.
+    fn check(&self, metadata: &Metadata) -> Result<(), Error> {
+        anyhow::bail!(
+            "Checking the inputs of the {} phase is not supported by stage {}",
+            metadata.phase,
+            metadata.stage_name
+        )
+    }
 }
 
 impl<T> MartianStage for T
@@ -722,6 +883,10 @@ where
         <T as MartianMain>::main(self, args, rover)
     }
 
+    fn validate(&self, args: &Self::StageInputs) -> Result<(), ValidationErrors> {
+        <T as MartianMain>::validate(self, args)
+    }
+
     fn join(
         &self,
         _: Self::StageInputs,
@@ -737,6 +902,7 @@ where
         run_directory: impl AsRef<Path>,
         args: Self::StageInputs,
     ) -> Result<Self::StageOutputs, Error> {
+        self.validate(&args)?;
         // Use default resource for main
         let rover = split_prelude(run_directory.as_ref(), Self::stage_name(), "main")?;
         println!(" > [chunk] running");
@@ -769,31 +935,76 @@ where
 {
     fn split(&self, md: &mut Metadata) -> Result<(), Error> {
         let args: <T as MartianStage>::StageInputs = md.decode(ARGS_FN)?;
+        MartianStage::validate(self, &args)?;
+        let inputs = md.hash_inputs(&<T as MartianStage>::StageInputs::mro_fields())?;
         let rover: MartianRover = MartianRover::from(&*md);
         let stage_defs = MartianStage::split(self, args, rover)?;
         let stage_def_obj = obj_encode(&stage_defs)?;
+        md.write_provenance(inputs, &[], &stage_def_obj)?;
         md.complete_with("stage_defs", &stage_def_obj)
     }
 
     fn main(&self, md: &mut Metadata) -> Result<(), Error> {
         let args: <T as MartianStage>::StageInputs = md.decode(ARGS_FN)?;
         let chunk_args: <T as MartianStage>::ChunkInputs = md.decode(ARGS_FN)?;
+        // Stages with a split were validated before the split.
+        if matches!(T::stage_kind(), StageKind::MainOnly) {
+            MartianStage::validate(self, &args)?;
+        }
+        let inputs = md.hash_inputs(
+            &[
+                <T as MartianStage>::StageInputs::mro_fields(),
+                <T as MartianStage>::ChunkInputs::mro_fields(),
+            ]
+            .concat(),
+        )?;
         let rover = MartianRover::from(&*md);
         let outs = MartianStage::main(self, args, chunk_args, rover)?;
         let outs_obj = obj_encode(&outs)?;
+        let out_fields = <T as MartianStage>::ChunkOutputs::mro_fields();
+        check_outputs(&out_fields, &outs_obj, Path::new(&md.files_path))?;
+        md.write_provenance(inputs, &out_fields, &outs_obj)?;
         md.complete_with(OUTS_FN, &outs_obj)
     }
 
     fn join(&self, md: &mut Metadata) -> Result<(), Error> {
         let args: <T as MartianStage>::StageInputs = md.decode(ARGS_FN)?;
+        let inputs = md.hash_inputs(&<T as MartianStage>::StageInputs::mro_fields())?;
         let rover = MartianRover::from(&*md);
         // let outs = md.read_json_obj("outs")?;
         let chunk_defs: Vec<<T as MartianStage>::ChunkInputs> = md.decode("chunk_defs")?;
         let chunk_outs: Vec<<T as MartianStage>::ChunkOutputs> = md.decode("chunk_outs")?;
         let outs = MartianStage::join(self, args, chunk_defs, chunk_outs, rover)?;
         let outs_obj = obj_encode(&outs)?;
+        // The join may pass along the files of the chunks.
+        let chunk_outs_obj: Vec<JsonDict> = md.decode("chunk_outs")?;
+        let out_fields = <T as MartianStage>::StageOutputs::mro_fields();
+        check_join_outputs(
+            &out_fields,
+            &outs_obj,
+            Path::new(&md.files_path),
+            &<T as MartianStage>::ChunkOutputs::mro_fields(),
+            &chunk_outs_obj,
+        )?;
+        md.write_provenance(inputs, &out_fields, &outs_obj)?;
         md.complete_with(OUTS_FN, &outs_obj)
     }
+
+    fn check(&self, md: &Metadata) -> Result<(), Error> {
+        let args: <T as MartianStage>::StageInputs = md.decode(ARGS_FN)?;
+        MartianStage::validate(self, &args)?;
+        match md.phase {
+            StagePhase::Split | StagePhase::Preflight => {}
+            StagePhase::Main | StagePhase::Chunk => {
+                let _: <T as MartianStage>::ChunkInputs = md.decode(ARGS_FN)?;
+            }
+            StagePhase::Join => {
+                let _: Vec<<T as MartianStage>::ChunkInputs> = md.decode("chunk_defs")?;
+                let _: Vec<<T as MartianStage>::ChunkOutputs> = md.decode("chunk_outs")?;
+            }
+        }
+        Ok(())
+    }
 }
 
 // Prep a path for a test run of a stage.
@@ -808,7 +1019,7 @@ fn _prep_path(path: &Path, subdir: &str) -> Result<PathBuf, Error> {
     Ok(sub_path)
 }
 
-fn fill_defaults(mut resource: Resource) -> Resource {
+pub(crate) fn fill_defaults(mut resource: Resource) -> Resource {
     if resource.mem_gb.is_none() {
         resource.mem_gb.replace(1);
     }
@@ -826,7 +1037,12 @@ fn fill_defaults(mut resource: Resource) -> Resource {
 
 #[cfg(test)]
 mod test {
-    use crate::{Resource, StageDef};
+    use crate::prelude::*;
+    use crate::{
+        InAndOut, MartianBlanketType, MartianPrimaryType, MartianStruct, Metadata, MroField,
+        MroMaker, MroUsing,
+    };
+    use serde::{Deserialize, Serialize};
 
     #[test]
     fn test_stage_def_extend() {
@@ -837,4 +1053,133 @@ mod test {
             .collect::<StageDef<usize>>()
             .join_resource(Resource::default());
     }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    struct Count {
+        count: i64,
+    }
+
+    impl MartianStruct for Count {
+        fn mro_fields() -> Vec<MroField> {
+            vec![MroField::new(
+                "count",
+                MartianBlanketType::Primary(MartianPrimaryType::Int),
+                None,
+                None,
+            )]
+        }
+
+        fn validate(&self) -> Result<(), ValidationErrors> {
+            let mut errors = ValidationErrors::new();
+            if self.count < 0 {
+                errors.add("count", "must not be negative");
+            }
+            errors.into_result()
+        }
+    }
+
+    struct Double;
+
+    impl MroMaker for Double {
+        fn stage_name() -> &'static str {
+            "DOUBLE"
+        }
+        fn stage_in_and_out() -> InAndOut {
+            InAndOut {
+                inputs: Count::mro_fields(),
+                outputs: Count::mro_fields(),
+            }
+        }
+        fn chunk_in_and_out() -> Option<InAndOut> {
+            None
+        }
+        fn using_attributes() -> MroUsing {
+            MroUsing::default()
+        }
+    }
+
+    impl MartianStage for Double {
+        type StageInputs = Count;
+        type StageOutputs = Count;
+        type ChunkInputs = Count;
+        type ChunkOutputs = Count;
+
+        fn split(&self, args: Count, _: MartianRover) -> Result<StageDef<Count>, Error> {
+            Ok(StageDef::from_iter([args]))
+        }
+        fn main(&self, _: Count, chunk: Count, _: MartianRover) -> Result<Count, Error> {
+            unreachable!("{}", chunk.count)
+        }
+        fn join(
+            &self,
+            _: Count,
+            _: Vec<Count>,
+            _: Vec<Count>,
+            _: MartianRover,
+        ) -> Result<Count, Error> {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_check() -> Result<(), Error> {
+        let tmp = tempfile::tempdir()?;
+        let check = |phase: &str| {
+            let md = Metadata::new(
+                ["double", phase, tmp.path().to_str().unwrap(), "", ""]
+                    .map(String::from)
+                    .to_vec(),
+            )?;
+            RawMartianStage::check(&Double, &md)
+        };
+        let write = |name: &str, json: &str| std::fs::write(tmp.path().join(name), json);
+
+        write("_args", r#"{"count": 2}"#)?;
+        check("split")?;
+        check("main")?;
+        // The join also needs the chunk definitions and outputs.
+        assert!(check("join").is_err());
+        write("_chunk_defs", r#"[{"count": 2}]"#)?;
+        write("_chunk_outs", r#"[{"count": "four"}]"#)?;
+        let err = format!("{:#}", check("join").unwrap_err());
+        assert!(err.contains("_chunk_outs"), "{err}");
+        write("_chunk_outs", r#"[{"count": 4}]"#)?;
+        check("join")?;
+
+        write("_args", r#"{"count": -2}"#)?;
+        assert!(check("split")
+            .unwrap_err()
+            .downcast_ref::<ValidationErrors>()
+            .is_some());
+        assert!(check("merge").is_err());
+        Ok(())
+    }
+
+    struct HandWritten;
+
+    impl RawMartianStage for HandWritten {
+        fn split(&self, _: &mut Metadata) -> Result<(), Error> {
+            unimplemented!()
+        }
+        fn main(&self, _: &mut Metadata) -> Result<(), Error> {
+            unimplemented!()
+        }
+        fn join(&self, _: &mut Metadata) -> Result<(), Error> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_check_unsupported() -> Result<(), Error> {
+        let md = Metadata::new(
+            ["hand_written", "main", "", "", ""]
+                .map(String::from)
+                .to_vec(),
+        )?;
+        assert_eq!(
+            HandWritten.check(&md).unwrap_err().to_string(),
+            "Checking the inputs of the main phase is not supported by stage hand_written"
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,322 @@
+//! Measure the resources actually consumed by a stage.
+//!
+//! The adapter records the usage of every split/main/join in the `_jobinfo`
+//! under `rust.usage`, so that the `Resource` requests of the stage can be
+//! tuned against reality:
+//! ```json
+//! "rust": {
+//!     "binpath": "/path/to/adapter",
+//!     "version": "1.75.0",
+//!     "usage": {
+//!         "wall_time_secs": 12.5,
+//!         "user_cpu_secs": 40.1,
+//!         "system_cpu_secs": 0.8,
+//!         "max_rss_kb": 1048576,
+//!         "max_threads": 4,
+//!         "max_heap_bytes": 805306368,
+//!         "read_bytes": 4096,
+//!         "write_bytes": 1024
+//!     }
+//! }
+//! ```
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often the number of threads is sampled.
+pub(crate) const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Name of the worker threads of the global rayon pool, suffixed with their
+/// index.
+pub(crate) const RAYON_THREAD_NAME: &str = "martian-rayon";
+
+/// Name of the worker threads of the tokio runtime.
+pub(crate) const TOKIO_THREAD_NAME: &str = "martian-tokio";
+
+/// Number of threads started by the adapter itself (e.g. to handle signals),
+/// which are not counted against the stage.
+static HELPER_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Spawn a thread used by the adapter itself, rather than by the stage.
+pub(crate) fn spawn_helper_thread<F>(name: &str, f: F) -> std::io::Result<JoinHandle<()>>
+where
+    F: FnOnce() + Send + 'static,
+{
+    HELPER_THREADS.fetch_add(1, Ordering::Relaxed);
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            f();
+            HELPER_THREADS.fetch_sub(1, Ordering::Relaxed);
+        })
+        .inspect_err(|_| {
+            HELPER_THREADS.fetch_sub(1, Ordering::Relaxed);
+        })
+}
+
+/// Resources consumed by one split, main or join.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// Wall clock time since the adapter started running the stage.
+    pub wall_time_secs: f64,
+    /// CPU time spent in user mode by the whole process.
+    pub user_cpu_secs: f64,
+    /// CPU time spent in kernel mode by the whole process.
+    pub system_cpu_secs: f64,
+    /// Peak resident set size of the process in kilobytes.
+    pub max_rss_kb: u64,
+    /// Largest number of threads observed running at the same time, not
+    /// counting the helper threads of the adapter.
+    pub max_threads: usize,
+    /// High-water mark of the heap, if `martian::alloc::TrackingAllocator`
+    /// is the global allocator.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_heap_bytes: Option<u64>,
+    /// Bytes read from storage, if reported by the kernel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_bytes: Option<u64>,
+    /// Bytes written to storage, if reported by the kernel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_bytes: Option<u64>,
+}
+
+/// Background thread which tracks the number of running threads until
+/// [`UsageMonitor::finish()`] is called.
+#[derive(Debug)]
+pub(crate) struct UsageMonitor {
+    start: Instant,
+    max_threads: Arc<AtomicUsize>,
+    stop: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl UsageMonitor {
+    /// Start monitoring. A warning is logged the first time the number of
+    /// stage threads outside of the rayon and tokio pools exceeds
+    /// `thread_limit` in two consecutive samples, unless it is 0.
+    pub(crate) fn start(interval: Duration, thread_limit: usize) -> UsageMonitor {
+        let max_threads = Arc::new(AtomicUsize::new(stage_threads().unwrap_or_default()));
+        let (sender, receiver) = channel();
+        let monitor_max_threads = max_threads.clone();
+        let mut warned = false;
+        let mut exceeded = false;
+        let stop = spawn_helper_thread("martian-usage", move || loop {
+            if let Some(threads) = stage_threads() {
+                monitor_max_threads.fetch_max(threads, Ordering::Relaxed);
+                let threads = threads.saturating_sub(pool_threads());
+                // A thread is only named once it runs, so the workers of a
+                // pool which is starting may not be recognized yet. Only warn
+                // if the limit is exceeded in two samples in a row.
+                let exceeded_before = exceeded;
+                exceeded = exceeds_thread_limit(threads, thread_limit);
+                if !warned && exceeded && exceeded_before {
+                    warn!(
+                        "the stage is running {threads} threads, but was only allotted \
+                         {thread_limit} threads. Size thread pools using MartianRover::get_threads() \
+                         to avoid oversubscribing the node."
+                    );
+                    warned = true;
+                }
+            }
+            match receiver.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {}
+                Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        })
+        .ok()
+        .map(|handle| (sender, handle));
+
+        UsageMonitor {
+            start: Instant::now(),
+            max_threads,
+            stop,
+        }
+    }
+
+    /// Largest number of stage threads observed so far.
+    pub(crate) fn max_threads(&self) -> usize {
+        self.max_threads.load(Ordering::Relaxed)
+    }
+
+    /// Stop monitoring and return the resources used so far.
+    pub(crate) fn finish(mut self) -> ResourceUsage {
+        if let Some((sender, handle)) = self.stop.take() {
+            let _ = sender.send(());
+            let _ = handle.join();
+        }
+        if let Some(threads) = stage_threads() {
+            self.max_threads.fetch_max(threads, Ordering::Relaxed);
+        }
+
+        let rusage = unsafe {
+            let mut rusage: libc::rusage = std::mem::zeroed();
+            libc::getrusage(libc::RUSAGE_SELF, &mut rusage);
+            rusage
+        };
+        let (read_bytes, write_bytes) = io_bytes().unzip();
+        ResourceUsage {
+            wall_time_secs: self.start.elapsed().as_secs_f64(),
+            user_cpu_secs: timeval_secs(rusage.ru_utime),
+            system_cpu_secs: timeval_secs(rusage.ru_stime),
+            max_rss_kb: rusage.ru_maxrss as u64,
+            max_threads: self.max_threads(),
+            max_heap_bytes: max_heap_bytes(),
+            read_bytes,
+            write_bytes,
+        }
+    }
+}
+
+#[cfg(feature = "tracking-allocator")]
+fn max_heap_bytes() -> Option<u64> {
+    crate::alloc::is_installed().then(|| crate::alloc::peak_bytes() as u64)
+}
+
+#[cfg(not(feature = "tracking-allocator"))]
+fn max_heap_bytes() -> Option<u64> {
+    None
+}
+
+fn timeval_secs(tv: libc::timeval) -> f64 {
+    tv.tv_sec as f64 + tv.tv_usec as f64 / 1e6
+}
+
+/// Whether `threads` exceeds the allotment. The main thread is not counted,
+/// as it is typically just waiting for a pool of worker threads.
+fn exceeds_thread_limit(threads: usize, thread_limit: usize) -> bool {
+    thread_limit > 0 && threads > thread_limit + 1
+}
+
+/// Number of threads in this process, excluding the adapter helper threads.
+pub(crate) fn stage_threads() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let threads: usize = proc_field(&status, "Threads:")?;
+    Some(threads.saturating_sub(HELPER_THREADS.load(Ordering::Relaxed)))
+}
+
+/// Number of worker threads of the rayon and tokio pools, identified by the
+/// names in `/proc/self/task/*/comm`. The pools are sized to the threads
+/// allotted to the stage, so their workers are not counted against it.
+fn pool_threads() -> usize {
+    let Ok(tasks) = std::fs::read_dir("/proc/self/task") else {
+        return 0;
+    };
+    tasks
+        .filter_map(|task| std::fs::read_to_string(task.ok()?.path().join("comm")).ok())
+        .filter(|comm| comm.starts_with(RAYON_THREAD_NAME) || comm.starts_with(TOKIO_THREAD_NAME))
+        .count()
+}
+
+/// Bytes read and written from storage, from `/proc/self/io`.
+fn io_bytes() -> Option<(u64, u64)> {
+    let io = std::fs::read_to_string("/proc/self/io").ok()?;
+    Some((
+        proc_field(&io, "read_bytes:")?,
+        proc_field(&io, "write_bytes:")?,
+    ))
+}
+
+/// Parse the value of a `key: value` line from a file in `/proc`.
+fn proc_field<T: std::str::FromStr>(content: &str, key: &str) -> Option<T> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix(key))
+        .and_then(|value| value.split_whitespace().next())
+        .and_then(|value| value.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(all(feature = "rayon", feature = "tokio"))]
+    use crate::{executor::self_adapter, Error, Metadata, RawMartianStage, Resource};
+
+    #[test]
+    fn test_proc_field() {
+        let status = "Name:\tcat\nVmHWM:\t     976 kB\nThreads:\t1\n";
+        assert_eq!(proc_field::<usize>(status, "Threads:"), Some(1));
+        assert_eq!(proc_field::<u64>(status, "VmHWM:"), Some(976));
+        assert_eq!(proc_field::<u64>(status, "VmRSS:"), None);
+    }
+
+    #[test]
+    fn test_thread_limit() {
+        assert!(!exceeds_thread_limit(5, 4));
+        assert!(exceeds_thread_limit(6, 4));
+        assert!(!exceeds_thread_limit(100, 0));
+    }
+
+    #[test]
+    fn test_usage_monitor() {
+        let monitor = UsageMonitor::start(Duration::from_millis(10), 0);
+        let workers: Vec<_> = (0..3)
+            .map(|_| std::thread::spawn(|| std::thread::sleep(Duration::from_millis(200))))
+            .collect();
+        let mut x = 0u64;
+        for i in 0..1_000_000 {
+            x = x.wrapping_add(std::hint::black_box(i));
+        }
+        for w in workers {
+            w.join().unwrap();
+        }
+        let usage = monitor.finish();
+        assert!(usage.max_threads >= 4, "{usage:?}");
+        assert!(usage.wall_time_secs >= 0.2);
+        assert!(usage.max_rss_kb > 0);
+        assert!(x > 0);
+    }
+
+    #[cfg(all(feature = "rayon", feature = "tokio"))]
+    const POOLS_STAGE: &str = "pools";
+
+    /// Run work on both the rayon pool and the tokio runtime.
+    #[cfg(all(feature = "rayon", feature = "tokio"))]
+    struct Pools;
+
+    #[cfg(all(feature = "rayon", feature = "tokio"))]
+    impl RawMartianStage for Pools {
+        fn split(&self, _: &mut Metadata) -> Result<(), Error> {
+            unimplemented!()
+        }
+
+        fn main(&self, md: &mut Metadata) -> Result<(), Error> {
+            use rayon::prelude::*;
+            let threads = md.get_threads_allocation();
+            crate::runtime::block_on(threads, async {
+                (0..threads).into_par_iter().for_each(|_| {
+                    // Outlast a sample of the usage monitor.
+                    std::thread::sleep(SAMPLE_INTERVAL + Duration::from_millis(200))
+                });
+            });
+            md.complete_with("outs", &Default::default())
+        }
+
+        fn join(&self, _: &mut Metadata) -> Result<(), Error> {
+            unimplemented!()
+        }
+    }
+
+    #[cfg(all(feature = "rayon", feature = "tokio"))]
+    #[test]
+    fn test_thread_limit_with_pools() -> Result<(), Error> {
+        self_adapter::run_if_child(POOLS_STAGE, Pools, |adapter| adapter);
+        let tmp = tempfile::tempdir()?;
+        let run = self_adapter::executor("usage::tests::test_thread_limit_with_pools", POOLS_STAGE)
+            .split_resource(Resource::with_threads(2))
+            .run_with_args(tmp.path().join("run"), Default::default())?;
+
+        assert!(run.is_success());
+        let main = &run.invocations[0];
+        let jobinfo: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(
+            main.metadata_path.join("_jobinfo"),
+        )?)?;
+        let usage = ResourceUsage::deserialize(&jobinfo["rust"]["usage"])?;
+        // The main thread and both pools.
+        assert!(usage.max_threads >= 5, "{usage:?}");
+        assert!(!main.log.contains("but was only allotted"), "{}", main.log);
+        Ok(())
+    }
+}
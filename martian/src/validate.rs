@@ -0,0 +1,148 @@
+//! Validation of the stage inputs before the stage runs.
+//!
+//! [`MartianStage::validate()`] and [`MartianMain::validate()`] are called
+//! with the stage inputs before `split()` (or `main()` for stages without a
+//! split) and by `test_run()`. The default implementation checks the
+//! `#[mro_validate(..)]` attributes of the fields of the `StageInputs`:
+//! ```ignore
+//! #[derive(Serialize, Deserialize, MartianStruct)]
+//! pub struct AlignStageInputs {
+//!     #[mro_validate(range = 1..=64)]
+//!     min_score: u32,
+//!     #[mro_validate(exists)]
+//!     reference: PathBuf,
+//!     #[mro_validate(exists)]
+//!     barcodes: Option<Vec<TxtFile>>,
+//! }
+//! ```
+//! `Option` fields are only checked if they are set, and each element of a
+//! `Vec` is checked. Failures are reported to martian as an assert listing
+//! every invalid field, since restarting the pipeline would not help:
+//! ```text
+//! ASSERT:invalid stage inputs:
+//!   - min_score: 0 is not in the range 1..=64
+//!   - barcodes[1]: /path/to/missing.txt does not exist
+//! ```
+//!
+//! [`MartianStage::validate()`]: crate::MartianStage::validate
+//! [`MartianMain::validate()`]: crate::MartianMain::validate
+use std::fmt::{self, Debug};
+use std::ops::RangeBounds;
+use std::path::Path;
+
+/// An invalid stage input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    /// Name of the field, e.g. `reads` or `reads[2]` for the elements of
+    /// an array.
+    pub field: String,
+    pub message: String,
+}
+
+/// Every invalid stage input.
+/// ```rust
+/// use martian::ValidationErrors;
+///
+/// let mut errors = ValidationErrors::new();
+/// assert!(errors.clone().into_result().is_ok());
+/// errors.add("threads", "must be positive");
+/// assert_eq!(
+///     errors.into_result().unwrap_err().to_string(),
+///     "invalid stage inputs:\n  - threads: must be positive"
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationErrors(Vec<ValidationError>);
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.0.push(ValidationError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    /// Add the errors from another validation, e.g. of a nested struct.
+    pub fn extend(&mut self, other: ValidationErrors) {
+        self.0.extend(other.0);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ValidationError> {
+        self.0.iter()
+    }
+
+    /// `Ok(())` if there are no errors.
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid stage inputs:")?;
+        for error in &self.0 {
+            write!(f, "\n  - {}: {}", error.field, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// Check for `#[mro_validate(range = ..)]`.
+pub fn check_range<T, R>(errors: &mut ValidationErrors, field: &str, value: &T, range: R)
+where
+    T: PartialOrd + Debug,
+    R: RangeBounds<T> + Debug,
+{
+    if !range.contains(value) {
+        errors.add(field, format!("{value:?} is not in the range {range:?}"));
+    }
+}
+
+/// Check for `#[mro_validate(exists)]`.
+pub fn check_exists(errors: &mut ValidationErrors, field: &str, path: &impl AsRef<Path>) {
+    let path = path.as_ref();
+    if !path.exists() {
+        errors.add(field, format!("{} does not exist", path.display()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checks() {
+        let mut errors = ValidationErrors::new();
+        check_range(&mut errors, "threads", &4, 1..=64);
+        check_range(&mut errors, "fraction", &1.5, 0.0..1.0);
+        check_range(&mut errors, "min_reads", &0u64, 1..);
+        check_exists(&mut errors, "jobinfo", &"tests/jobinfo.json");
+        check_exists(&mut errors, "reads[1]", &"/does/not/exist.fastq");
+        assert_eq!(errors.len(), 3);
+        assert_eq!(
+            errors.to_string(),
+            "invalid stage inputs:\n  \
+             - fraction: 1.5 is not in the range 0.0..1.0\n  \
+             - min_reads: 0 is not in the range 1..\n  \
+             - reads[1]: /does/not/exist.fastq does not exist"
+        );
+    }
+}